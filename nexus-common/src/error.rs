@@ -1,3 +1,4 @@
+use crate::types::{ExpectedVersion, StreamId, Version};
 use bincode;
 use thiserror::Error;
 #[derive(Debug, Error)]
@@ -13,6 +14,34 @@ pub enum NexusError {
 
     #[error("Consensus Error: {0}")]
     Consensus(String),
+
+    #[error("Corruption Error: {0}")]
+    Corruption(String),
+
+    #[error("State Machine Error: {0}")]
+    StateMachine(String),
+
+    #[error("Revision Compacted: history up to revision {0} is no longer available")]
+    Compacted(u64),
+
+    #[error("Wrong Expected Version: stream {stream_id} expected {expected:?}, actual {actual:?}")]
+    WrongExpectedVersion {
+        stream_id: StreamId,
+        expected: ExpectedVersion,
+        actual: Option<Version>, // None if the stream does not exist
+    },
+
+    #[error("Stream Not Found: {0}")]
+    StreamNotFound(StreamId),
+
+    #[error("Stream Deleted: {0}")]
+    StreamDeleted(StreamId),
+
+    #[error("Subscription Group Not Found: {0}")]
+    GroupNotFound(String),
+
+    #[error("Subscription Group Exists: {0}")]
+    GroupExists(String),
 }
 
 pub type Result<T> = std::result::Result<T, NexusError>;
@@ -1,4 +1,5 @@
 pub mod config;
 pub mod error;
 pub mod metrics;
+pub mod prometheus;
 pub mod types;
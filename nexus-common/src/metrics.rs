@@ -1,5 +1,269 @@
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+
+/// Key/value pairs attached to a metric, e.g. `&[("peer", "node-2")]`
+pub type Labels<'a> = &'a [(&'a str, &'a str)];
+
+/// Histogram bucket upper bounds (in seconds) used when none were registered
+pub const DEFAULT_BUCKETS: &[f64] = &[
+    0.0005, 0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
 /// Simple trait for metrics instrumentation
-pub trait MetricsCollector {
-    fn inc_counter(&self, name: &str);
-    fn observe_gauge(&self, name: &str, value: f64);
+pub trait MetricsCollector: Send + Sync {
+    /// Increments an unlabeled counter by one
+    fn inc_counter(&self, name: &str) {
+        self.inc_counter_by(name, &[], 1);
+    }
+
+    /// Sets an unlabeled gauge
+    fn observe_gauge(&self, name: &str, value: f64) {
+        self.set_gauge(name, &[], value);
+    }
+
+    /// Increments a labeled counter by `value`
+    fn inc_counter_by(&self, name: &str, labels: Labels, value: u64);
+
+    /// Sets a labeled gauge
+    fn set_gauge(&self, name: &str, labels: Labels, value: f64);
+
+    /// Records one observation in a labeled histogram
+    fn observe_histogram(&self, name: &str, labels: Labels, value: f64);
+
+    /// Sets the bucket upper bounds for a histogram; observations made before
+    /// registration use `DEFAULT_BUCKETS`
+    fn register_histogram(&self, _name: &str, _buckets: &[f64]) {}
+}
+
+/// Collector that discards everything; used when no metrics backend is configured
+#[derive(Debug, Default, Clone, Copy)]
+pub struct NoopMetrics;
+
+impl MetricsCollector for NoopMetrics {
+    fn inc_counter_by(&self, _name: &str, _labels: Labels, _value: u64) {}
+    fn set_gauge(&self, _name: &str, _labels: Labels, _value: f64) {}
+    fn observe_histogram(&self, _name: &str, _labels: Labels, _value: f64) {}
+}
+
+/// Identifies one series: a metric name plus its labels (sorted by label name)
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct MetricKey {
+    pub name: String,
+    pub labels: Vec<(String, String)>,
+}
+
+impl MetricKey {
+    pub fn new(name: &str, labels: Labels) -> Self {
+        let mut labels: Vec<(String, String)> = labels
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        labels.sort();
+        Self {
+            name: name.to_string(),
+            labels,
+        }
+    }
+}
+
+/// A histogram with fixed upper bounds and cumulative bucket counts
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Histogram {
+    pub buckets: Vec<f64>, // Upper bounds, ascending (+Inf is implicit)
+    pub counts: Vec<u64>,  // counts[i] = observations <= buckets[i]
+    pub sum: f64,          // Sum of all observed values
+    pub count: u64,        // Total number of observations
+}
+
+impl Histogram {
+    pub fn new(buckets: &[f64]) -> Self {
+        let mut buckets = buckets.to_vec();
+        buckets.sort_by(|a, b| a.total_cmp(b));
+        buckets.dedup();
+        Self {
+            counts: vec![0; buckets.len()],
+            buckets,
+            sum: 0.0,
+            count: 0,
+        }
+    }
+
+    pub fn observe(&mut self, value: f64) {
+        for (bound, count) in self.buckets.iter().zip(self.counts.iter_mut()) {
+            if value <= *bound {
+                *count += 1;
+            }
+        }
+        self.sum += value;
+        self.count += 1;
+    }
+}
+
+/// Point-in-time copy of every series held by an `InMemoryMetrics`
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct MetricsSnapshot {
+    pub counters: Vec<(MetricKey, u64)>,
+    pub gauges: Vec<(MetricKey, f64)>,
+    pub histograms: Vec<(MetricKey, Histogram)>,
+}
+
+impl MetricsSnapshot {
+    pub fn counter(&self, name: &str, labels: Labels) -> Option<u64> {
+        let key = MetricKey::new(name, labels);
+        self.counters
+            .iter()
+            .find(|(k, _)| *k == key)
+            .map(|(_, v)| *v)
+    }
+
+    pub fn gauge(&self, name: &str, labels: Labels) -> Option<f64> {
+        let key = MetricKey::new(name, labels);
+        self.gauges.iter().find(|(k, _)| *k == key).map(|(_, v)| *v)
+    }
+
+    pub fn histogram(&self, name: &str, labels: Labels) -> Option<&Histogram> {
+        let key = MetricKey::new(name, labels);
+        self.histograms
+            .iter()
+            .find(|(k, _)| *k == key)
+            .map(|(_, h)| h)
+    }
+}
+
+#[derive(Debug, Default)]
+struct Registry {
+    counters: BTreeMap<MetricKey, u64>,
+    gauges: BTreeMap<MetricKey, f64>,
+    histograms: BTreeMap<MetricKey, Histogram>,
+    buckets: HashMap<String, Vec<f64>>, // Registered bucket bounds per histogram name
+}
+
+/// Collector keeping every series in memory; can be snapshotted for tests or export
+#[derive(Debug, Default)]
+pub struct InMemoryMetrics {
+    registry: Mutex<Registry>,
+}
+
+impl InMemoryMetrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Copies out all current series, ordered by name and labels
+    pub fn snapshot(&self) -> MetricsSnapshot {
+        let registry = self.registry.lock().unwrap();
+        MetricsSnapshot {
+            counters: registry
+                .counters
+                .iter()
+                .map(|(k, v)| (k.clone(), *v))
+                .collect(),
+            gauges: registry
+                .gauges
+                .iter()
+                .map(|(k, v)| (k.clone(), *v))
+                .collect(),
+            histograms: registry
+                .histograms
+                .iter()
+                .map(|(k, h)| (k.clone(), h.clone()))
+                .collect(),
+        }
+    }
+}
+
+impl MetricsCollector for InMemoryMetrics {
+    fn inc_counter_by(&self, name: &str, labels: Labels, value: u64) {
+        let mut registry = self.registry.lock().unwrap();
+        *registry
+            .counters
+            .entry(MetricKey::new(name, labels))
+            .or_default() += value;
+    }
+
+    fn set_gauge(&self, name: &str, labels: Labels, value: f64) {
+        let mut registry = self.registry.lock().unwrap();
+        registry.gauges.insert(MetricKey::new(name, labels), value);
+    }
+
+    fn observe_histogram(&self, name: &str, labels: Labels, value: f64) {
+        let mut registry = self.registry.lock().unwrap();
+        let buckets = registry
+            .buckets
+            .get(name)
+            .cloned()
+            .unwrap_or_else(|| DEFAULT_BUCKETS.to_vec());
+
+        registry
+            .histograms
+            .entry(MetricKey::new(name, labels))
+            .or_insert_with(|| Histogram::new(&buckets))
+            .observe(value);
+    }
+
+    fn register_histogram(&self, name: &str, buckets: &[f64]) {
+        let mut registry = self.registry.lock().unwrap();
+        registry.buckets.insert(name.to_string(), buckets.to_vec());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_counters_and_labeled_gauges() {
+        let metrics = InMemoryMetrics::new();
+        metrics.inc_counter("requests");
+        metrics.inc_counter_by("requests", &[], 4);
+        metrics.set_gauge("lag", &[("peer", "node-2")], 3.0);
+        metrics.set_gauge("lag", &[("peer", "node-3")], 7.0);
+
+        let snap = metrics.snapshot();
+        assert_eq!(snap.counter("requests", &[]), Some(5));
+        assert_eq!(snap.gauge("lag", &[("peer", "node-2")]), Some(3.0));
+        assert_eq!(snap.gauge("lag", &[("peer", "node-3")]), Some(7.0));
+        assert_eq!(snap.gauge("lag", &[]), None);
+    }
+
+    #[test]
+    fn test_label_order_does_not_matter() {
+        let metrics = InMemoryMetrics::new();
+        metrics.inc_counter_by("ops", &[("a", "1"), ("b", "2")], 1);
+        metrics.inc_counter_by("ops", &[("b", "2"), ("a", "1")], 1);
+
+        assert_eq!(
+            metrics.snapshot().counter("ops", &[("a", "1"), ("b", "2")]),
+            Some(2)
+        );
+    }
+
+    #[test]
+    fn test_histogram_with_registered_buckets() {
+        let metrics = InMemoryMetrics::new();
+        metrics.register_histogram("latency", &[1.0, 5.0, 10.0]);
+        for value in [0.5, 2.0, 7.0, 20.0] {
+            metrics.observe_histogram("latency", &[], value);
+        }
+
+        let snap = metrics.snapshot();
+        let histogram = snap.histogram("latency", &[]).unwrap();
+        assert_eq!(histogram.buckets, vec![1.0, 5.0, 10.0]);
+        assert_eq!(histogram.counts, vec![1, 2, 3]);
+        assert_eq!(histogram.count, 4);
+        assert_eq!(histogram.sum, 29.5);
+    }
+
+    #[test]
+    fn test_snapshot_serialization() {
+        let metrics = InMemoryMetrics::new();
+        metrics.observe_gauge("term", 3.0);
+        metrics.observe_histogram("latency", &[("op", "append")], 0.002);
+
+        let snap = metrics.snapshot();
+        let json = serde_json::to_string(&snap).unwrap();
+        let decoded: MetricsSnapshot = serde_json::from_str(&json).unwrap();
+        assert_eq!(snap, decoded);
+    }
 }
@@ -0,0 +1,191 @@
+use crate::metrics::{InMemoryMetrics, Labels, MetricKey, MetricsCollector, MetricsSnapshot};
+use std::fmt::Write;
+
+/// Content type of the Prometheus text exposition format
+pub const CONTENT_TYPE: &str = "text/plain; version=0.0.4; charset=utf-8";
+
+/// Collector that stores counters, gauges and histograms and renders them in the
+/// Prometheus text exposition format
+#[derive(Debug, Default)]
+pub struct PrometheusMetrics {
+    inner: InMemoryMetrics,
+}
+
+impl PrometheusMetrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn snapshot(&self) -> MetricsSnapshot {
+        self.inner.snapshot()
+    }
+
+    /// Renders every series currently held by the collector
+    pub fn render(&self) -> String {
+        render(&self.snapshot())
+    }
+}
+
+impl MetricsCollector for PrometheusMetrics {
+    fn inc_counter_by(&self, name: &str, labels: Labels, value: u64) {
+        self.inner.inc_counter_by(name, labels, value);
+    }
+
+    fn set_gauge(&self, name: &str, labels: Labels, value: f64) {
+        self.inner.set_gauge(name, labels, value);
+    }
+
+    fn observe_histogram(&self, name: &str, labels: Labels, value: f64) {
+        self.inner.observe_histogram(name, labels, value);
+    }
+
+    fn register_histogram(&self, name: &str, buckets: &[f64]) {
+        self.inner.register_histogram(name, buckets);
+    }
+}
+
+/// Renders a metrics snapshot in the Prometheus text exposition format
+pub fn render(snapshot: &MetricsSnapshot) -> String {
+    let mut out = String::new();
+    let mut last_type: Option<String> = None;
+
+    let mut type_line = |out: &mut String, name: &str, kind: &str| {
+        if last_type.as_deref() != Some(name) {
+            let _ = writeln!(out, "# TYPE {} {}", name, kind);
+            last_type = Some(name.to_string());
+        }
+    };
+
+    for (key, value) in &snapshot.counters {
+        type_line(&mut out, &key.name, "counter");
+        let _ = writeln!(out, "{}{} {}", key.name, format_labels(key, None), value);
+    }
+
+    for (key, value) in &snapshot.gauges {
+        type_line(&mut out, &key.name, "gauge");
+        let _ = writeln!(
+            out,
+            "{}{} {}",
+            key.name,
+            format_labels(key, None),
+            format_value(*value)
+        );
+    }
+
+    for (key, histogram) in &snapshot.histograms {
+        type_line(&mut out, &key.name, "histogram");
+        for (bound, count) in histogram.buckets.iter().zip(&histogram.counts) {
+            let le = format_value(*bound);
+            let _ = writeln!(
+                out,
+                "{}_bucket{} {}",
+                key.name,
+                format_labels(key, Some(&le)),
+                count
+            );
+        }
+        let _ = writeln!(
+            out,
+            "{}_bucket{} {}",
+            key.name,
+            format_labels(key, Some("+Inf")),
+            histogram.count
+        );
+        let labels = format_labels(key, None);
+        let _ = writeln!(
+            out,
+            "{}_sum{} {}",
+            key.name,
+            labels,
+            format_value(histogram.sum)
+        );
+        let _ = writeln!(out, "{}_count{} {}", key.name, labels, histogram.count);
+    }
+
+    out
+}
+
+/// Formats `{k="v",...}`, appending the histogram `le` label when given
+fn format_labels(key: &MetricKey, le: Option<&str>) -> String {
+    let mut pairs: Vec<String> = key
+        .labels
+        .iter()
+        .map(|(k, v)| format!("{}=\"{}\"", k, escape(v)))
+        .collect();
+    if let Some(le) = le {
+        pairs.push(format!("le=\"{}\"", le));
+    }
+
+    if pairs.is_empty() {
+        String::new()
+    } else {
+        format!("{{{}}}", pairs.join(","))
+    }
+}
+
+fn format_value(value: f64) -> String {
+    if value.is_nan() {
+        "NaN".into()
+    } else if value.is_infinite() {
+        if value > 0.0 { "+Inf" } else { "-Inf" }.into()
+    } else {
+        value.to_string()
+    }
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_counters_and_gauges() {
+        let metrics = PrometheusMetrics::new();
+        metrics.inc_counter_by("raft_elections_total", &[], 2);
+        metrics.set_gauge("raft_replication_lag", &[("peer", "node-2")], 4.0);
+        metrics.set_gauge("raft_replication_lag", &[("peer", "node-3")], 0.5);
+
+        assert_eq!(
+            metrics.render(),
+            "# TYPE raft_elections_total counter\n\
+             raft_elections_total 2\n\
+             # TYPE raft_replication_lag gauge\n\
+             raft_replication_lag{peer=\"node-2\"} 4\n\
+             raft_replication_lag{peer=\"node-3\"} 0.5\n"
+        );
+    }
+
+    #[test]
+    fn test_render_histogram() {
+        let metrics = PrometheusMetrics::new();
+        metrics.register_histogram("append_seconds", &[0.1, 1.0]);
+        metrics.observe_histogram("append_seconds", &[("op", "put")], 0.05);
+        metrics.observe_histogram("append_seconds", &[("op", "put")], 3.0);
+
+        assert_eq!(
+            metrics.render(),
+            "# TYPE append_seconds histogram\n\
+             append_seconds_bucket{op=\"put\",le=\"0.1\"} 1\n\
+             append_seconds_bucket{op=\"put\",le=\"1\"} 1\n\
+             append_seconds_bucket{op=\"put\",le=\"+Inf\"} 2\n\
+             append_seconds_sum{op=\"put\"} 3.05\n\
+             append_seconds_count{op=\"put\"} 2\n"
+        );
+    }
+
+    #[test]
+    fn test_label_values_are_escaped() {
+        let metrics = PrometheusMetrics::new();
+        metrics.inc_counter_by("events", &[("stream", "a\"b\\c\nd")], 1);
+
+        assert!(metrics
+            .render()
+            .contains(r#"events{stream="a\"b\\c\nd"} 1"#));
+    }
+}
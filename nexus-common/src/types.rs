@@ -12,6 +12,8 @@ pub struct NodeAddress {
     pub host: String,
     pub port: u16,
     pub node_id: NodeId,
+    #[serde(default)]
+    pub priority: u32, // Election priority: higher values are preferred as leader
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -20,6 +22,70 @@ pub struct ClusterConfig {
     pub replication_factor: usize,
     pub election_timeout_ms: u64,
     pub heartbeat_interval_ms: u64,
+    #[serde(default)]
+    pub compression: CompressionCodec, // Codec for snapshot files and log segments
+}
+
+/// Compression applied to snapshot files and log segments
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CompressionCodec {
+    #[default]
+    None,
+    Lz4,
+    Zstd,
+}
+
+impl CompressionCodec {
+    /// Stable identifier written into file headers
+    pub fn id(self) -> u8 {
+        match self {
+            CompressionCodec::None => 0,
+            CompressionCodec::Lz4 => 1,
+            CompressionCodec::Zstd => 2,
+        }
+    }
+
+    pub fn from_id(id: u8) -> Option<Self> {
+        match id {
+            0 => Some(CompressionCodec::None),
+            1 => Some(CompressionCodec::Lz4),
+            2 => Some(CompressionCodec::Zstd),
+            _ => None,
+        }
+    }
+}
+
+/// Stream state an append expects; checked before any event is written
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ExpectedVersion {
+    Any,            // No check
+    NoStream,       // The stream must not exist yet
+    StreamExists,   // The stream must have at least one event
+    Exact(Version), // The stream's last event must have this version
+}
+
+impl ExpectedVersion {
+    /// Whether a stream whose last event has version `actual` (None = no stream) passes
+    pub fn matches(self, actual: Option<Version>) -> bool {
+        match self {
+            ExpectedVersion::Any => true,
+            ExpectedVersion::NoStream => actual.is_none(),
+            ExpectedVersion::StreamExists => actual.is_some(),
+            ExpectedVersion::Exact(v) => actual == Some(v),
+        }
+    }
+}
+
+impl ClusterConfig {
+    /// Returns the election priority of a node, or 0 if it is not part of the cluster
+    pub fn priority_of(&self, node_id: &str) -> u32 {
+        self.nodes
+            .iter()
+            .find(|n| n.node_id == node_id)
+            .map(|n| n.priority)
+            .unwrap_or(0)
+    }
 }
 
 #[cfg(test)]
@@ -32,10 +98,41 @@ mod tests {
             host: "127.0.0.1".into(),
             port: 8080,
             node_id: "node-1".into(),
+            priority: 2,
         };
 
         let json = serde_json::to_string(&node).unwrap();
         let deserialized: NodeAddress = serde_json::from_str(&json).unwrap();
         assert_eq!(node.node_id, deserialized.node_id);
+        assert_eq!(node.priority, deserialized.priority);
+    }
+
+    #[test]
+    fn test_compression_from_config() {
+        let json = r#"{"nodes":[],"replication_factor":3,"election_timeout_ms":150,
+            "heartbeat_interval_ms":50,"compression":"zstd"}"#;
+        let config: ClusterConfig = serde_json::from_str(json).unwrap();
+        assert_eq!(config.compression, CompressionCodec::Zstd);
+
+        let id = config.compression.id();
+        assert_eq!(CompressionCodec::from_id(id), Some(CompressionCodec::Zstd));
+    }
+
+    #[test]
+    fn test_priority_defaults_to_zero() {
+        let json = r#"{"host":"127.0.0.1","port":8080,"node_id":"node-1"}"#;
+        let node: NodeAddress = serde_json::from_str(json).unwrap();
+        assert_eq!(node.priority, 0);
+    }
+
+    #[test]
+    fn test_expected_version_matches() {
+        assert!(ExpectedVersion::Any.matches(None));
+        assert!(ExpectedVersion::NoStream.matches(None));
+        assert!(!ExpectedVersion::NoStream.matches(Some(0)));
+        assert!(ExpectedVersion::StreamExists.matches(Some(0)));
+        assert!(!ExpectedVersion::StreamExists.matches(None));
+        assert!(ExpectedVersion::Exact(3).matches(Some(3)));
+        assert!(!ExpectedVersion::Exact(3).matches(Some(4)));
     }
 }
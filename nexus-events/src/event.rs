@@ -0,0 +1,214 @@
+use chrono::{DateTime, Utc};
+use nexus_common::error::Result;
+use nexus_common::types::{AggregatedId, EventId, StreamId, Version};
+use serde::{Deserialize, Serialize};
+
+/// Global position of an event across all streams: the Raft log index of the entry that
+/// wrote it, then its offset within that entry. Replaying the log or restoring a
+/// snapshot yields the same positions.
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize,
+)]
+pub struct Position {
+    pub log_index: u64,
+    pub offset: u32,
+}
+
+impl Position {
+    pub const START: Position = Position::new(0, 0);
+    pub const END: Position = Position::new(u64::MAX, u32::MAX);
+
+    pub const fn new(log_index: u64, offset: u32) -> Self {
+        Position { log_index, offset }
+    }
+
+    /// Smallest position after this one
+    pub const fn next(self) -> Self {
+        Position::new(self.log_index, self.offset + 1)
+    }
+}
+
+/// How an event's payload is encoded
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ContentType {
+    #[default]
+    Json,
+    Binary,
+}
+
+/// An event to be appended. Metadata is opaque to the store, except for the
+/// correlation and causation ids that tie together events of one workflow.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct EventData {
+    pub event_id: EventId,
+    pub event_type: String,
+    pub content_type: ContentType,
+    pub data: Vec<u8>,
+    pub metadata: Vec<u8>,
+    pub correlation_id: Option<EventId>, // Shared by every event of one workflow
+    pub causation_id: Option<EventId>,   // Event that directly caused this one
+}
+
+impl EventData {
+    /// Event with a JSON payload and a fresh id
+    pub fn json(event_type: impl Into<String>, payload: &impl Serialize) -> Result<Self> {
+        Ok(Self::new(
+            event_type,
+            ContentType::Json,
+            serde_json::to_vec(payload)?,
+        ))
+    }
+
+    /// Event with an opaque binary payload and a fresh id
+    pub fn binary(event_type: impl Into<String>, data: Vec<u8>) -> Self {
+        Self::new(event_type, ContentType::Binary, data)
+    }
+
+    fn new(event_type: impl Into<String>, content_type: ContentType, data: Vec<u8>) -> Self {
+        EventData {
+            event_id: EventId::new_v4(),
+            event_type: event_type.into(),
+            content_type,
+            data,
+            metadata: Vec::new(),
+            correlation_id: None,
+            causation_id: None,
+        }
+    }
+
+    /// Uses a caller-chosen id, e.g. to make retried appends idempotent
+    pub fn with_id(mut self, event_id: EventId) -> Self {
+        self.event_id = event_id;
+        self
+    }
+
+    pub fn with_metadata(mut self, metadata: Vec<u8>) -> Self {
+        self.metadata = metadata;
+        self
+    }
+
+    pub fn with_correlation_id(mut self, correlation_id: EventId) -> Self {
+        self.correlation_id = Some(correlation_id);
+        self
+    }
+
+    pub fn with_causation_id(mut self, causation_id: EventId) -> Self {
+        self.causation_id = Some(causation_id);
+        self
+    }
+
+    /// Marks this event as caused by `cause`, continuing its correlation
+    pub fn caused_by(mut self, cause: &RecordedEvent) -> Self {
+        self.causation_id = Some(cause.event_id);
+        self.correlation_id = Some(cause.correlation_id.unwrap_or(cause.event_id));
+        self
+    }
+}
+
+/// An event as stored: the appended `EventData` plus where and when it was written
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RecordedEvent {
+    pub stream_id: StreamId,
+    pub event_id: EventId,
+    pub event_type: String,
+    pub content_type: ContentType,
+    pub data: Vec<u8>,
+    pub metadata: Vec<u8>,
+    pub correlation_id: Option<EventId>,
+    pub causation_id: Option<EventId>,
+    pub version: Version,   // Position within the stream, from 0
+    pub position: Position, // Position across all streams
+    pub created: DateTime<Utc>,
+}
+
+impl RecordedEvent {
+    pub fn new(
+        stream_id: &str,
+        event: EventData,
+        version: Version,
+        position: Position,
+        created: DateTime<Utc>,
+    ) -> Self {
+        RecordedEvent {
+            stream_id: stream_id.to_string(),
+            event_id: event.event_id,
+            event_type: event.event_type,
+            content_type: event.content_type,
+            data: event.data,
+            metadata: event.metadata,
+            correlation_id: event.correlation_id,
+            causation_id: event.causation_id,
+            version,
+            position,
+            created,
+        }
+    }
+
+    /// Aggregate the event belongs to. Each stream holds one aggregate, so this is the
+    /// stream id.
+    pub fn aggregate_id(&self) -> &AggregatedId {
+        &self.stream_id
+    }
+
+    /// Decodes a JSON payload
+    pub fn json<T: for<'de> Deserialize<'de>>(&self) -> Result<T> {
+        Ok(serde_json::from_slice(&self.data)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn recorded() -> RecordedEvent {
+        let event = EventData::json("OrderPlaced", &json!({"order": 42}))
+            .unwrap()
+            .with_metadata(b"{\"user\":\"ann\"}".to_vec());
+        RecordedEvent::new("order-42", event, 0, Position::new(7, 0), Utc::now())
+    }
+
+    #[test]
+    fn test_json_payload() {
+        let event = recorded();
+        assert_eq!(event.content_type, ContentType::Json);
+        let payload: serde_json::Value = event.json().unwrap();
+        assert_eq!(payload["order"], 42);
+    }
+
+    #[test]
+    fn test_causation_continues_correlation() {
+        let first = recorded();
+        let second = EventData::binary("OrderPaid", vec![1, 2]).caused_by(&first);
+        assert_eq!(second.causation_id, Some(first.event_id));
+        assert_eq!(second.correlation_id, Some(first.event_id));
+
+        let second = RecordedEvent::new("order-42", second, 1, Position::new(8, 0), Utc::now());
+        let third = EventData::binary("OrderShipped", vec![]).caused_by(&second);
+        assert_eq!(third.causation_id, Some(second.event_id));
+        assert_eq!(third.correlation_id, Some(first.event_id));
+    }
+
+    #[test]
+    fn test_bincode_round_trip() {
+        let event = recorded();
+        let bytes = bincode::serialize(&event).unwrap();
+        assert_eq!(
+            bincode::deserialize::<RecordedEvent>(&bytes).unwrap(),
+            event
+        );
+
+        let data = EventData::binary("Ping", vec![0xff]).with_correlation_id(EventId::new_v4());
+        let bytes = bincode::serialize(&data).unwrap();
+        assert_eq!(bincode::deserialize::<EventData>(&bytes).unwrap(), data);
+    }
+
+    #[test]
+    fn test_serde_json_round_trip() {
+        let event = recorded();
+        let text = serde_json::to_string(&event).unwrap();
+        assert!(text.contains("\"content_type\":\"json\""));
+        assert_eq!(serde_json::from_str::<RecordedEvent>(&text).unwrap(), event);
+    }
+}
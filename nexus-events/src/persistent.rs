@@ -0,0 +1,657 @@
+use crate::event::{Position, RecordedEvent};
+use crate::store::{EventFilter, EventStore};
+use crate::subscription::{set_reason, DropReason, Subscription};
+use nexus_common::error::{NexusError, Result};
+use nexus_common::types::AggregatedId;
+use serde::{Deserialize, Serialize};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::hash::{Hash, Hasher};
+use std::sync::mpsc::{SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+
+/// How a group spreads events over its consumers
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DispatchStrategy {
+    #[default]
+    RoundRobin,
+    /// By hash of the event's aggregate (`RecordedEvent::aggregate_id`, i.e. its stream),
+    /// one event per aggregate at a time. The hash is taken modulo the number of
+    /// connected consumers, so aggregates are re-pinned whenever a consumer joins or
+    /// leaves.
+    Pinned,
+}
+
+/// What to do with an event a consumer could not handle
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum NackAction {
+    Retry, // Redeliver; parked once `max_retries` is exceeded
+    Park,  // Set aside until `replay_parked`
+    Skip,  // Treat as handled
+}
+
+/// Configuration of a persistent subscription group
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct GroupSettings {
+    pub filter: EventFilter, // Events of $all the group receives
+    pub strategy: DispatchStrategy,
+    pub max_retries: u32,     // Retries before an event is parked
+    pub start_from: Position, // First position considered
+}
+
+impl GroupSettings {
+    pub fn new(filter: EventFilter) -> Self {
+        GroupSettings {
+            filter,
+            strategy: DispatchStrategy::RoundRobin,
+            max_retries: 10,
+            start_from: Position::START,
+        }
+    }
+
+    pub fn with_strategy(mut self, strategy: DispatchStrategy) -> Self {
+        self.strategy = strategy;
+        self
+    }
+
+    pub fn with_max_retries(mut self, max_retries: u32) -> Self {
+        self.max_retries = max_retries;
+        self
+    }
+
+    pub fn with_start_from(mut self, start_from: Position) -> Self {
+        self.start_from = start_from;
+        self
+    }
+}
+
+/// Replicated progress of a group. Every matching event before `checkpoint` is acked or
+/// parked, and the one at it (if any) is not; the sets only hold positions past it.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PersistentGroup {
+    pub settings: GroupSettings,
+    pub checkpoint: Position,
+    pub acked: im::OrdSet<Position>, // Acked out of order, past the checkpoint
+    pub retries: im::OrdMap<Position, u32>, // Nacks of events still outstanding
+    pub parked: im::OrdSet<Position>, // Poison events, kept until replayed
+}
+
+/// An event handed to one consumer of a group. Ack or nack it by `event.position`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PersistentEvent {
+    pub event: RecordedEvent,
+    pub retry_count: u32,
+}
+
+/// A connected consumer, held by the store
+#[derive(Debug)]
+struct Consumer {
+    id: u64,
+    capacity: usize, // Events it may have in flight
+    tx: SyncSender<PersistentEvent>,
+    dropped: Arc<Mutex<Option<DropReason>>>,
+}
+
+/// Consumers of a group connected to this replica, and what they have been sent
+#[derive(Debug, Default)]
+pub(crate) struct Dispatcher {
+    consumers: Vec<Consumer>,
+    in_flight: BTreeMap<Position, u64>, // Sent, not yet acked or nacked, by consumer id
+    in_flight_count: HashMap<u64, usize>, // Consumer id -> its entries in `in_flight`
+    next_consumer: usize,               // Round-robin cursor
+    next_id: u64,
+}
+
+impl Dispatcher {
+    /// Offers the event to the consumers at `candidates` in turn. Returns the id of the
+    /// one that took it; full and closed consumers are recorded. A consumer is full once
+    /// it has `capacity` events in flight, or its channel is.
+    fn offer(
+        &mut self,
+        candidates: impl Iterator<Item = usize>,
+        mut message: PersistentEvent,
+        full: &mut HashSet<usize>,
+        closed: &mut HashSet<usize>,
+    ) -> Option<u64> {
+        for i in candidates {
+            if full.contains(&i) || closed.contains(&i) {
+                continue;
+            }
+            let consumer = &self.consumers[i];
+            let sent = self.in_flight_count.get(&consumer.id).copied().unwrap_or(0);
+            if sent >= consumer.capacity {
+                full.insert(i);
+                continue;
+            }
+            match self.consumers[i].tx.try_send(message) {
+                Ok(()) => {
+                    self.next_consumer = i + 1;
+                    return Some(self.consumers[i].id);
+                }
+                Err(TrySendError::Full(m)) => {
+                    full.insert(i);
+                    message = m;
+                }
+                Err(TrySendError::Disconnected(m)) => {
+                    closed.insert(i);
+                    message = m;
+                }
+            }
+        }
+        None
+    }
+
+    /// Records that consumer `id` was sent the event at `position`
+    fn track(&mut self, position: Position, id: u64) {
+        if let Some(previous) = self.in_flight.insert(position, id) {
+            uncount(&mut self.in_flight_count, previous);
+        }
+        *self.in_flight_count.entry(id).or_default() += 1;
+    }
+
+    /// Forgets the delivery of the event at `position`, if it is in flight
+    fn release(&mut self, position: &Position) {
+        if let Some(id) = self.in_flight.remove(position) {
+            uncount(&mut self.in_flight_count, id);
+        }
+    }
+
+    /// Keeps only the deliveries `keep` returns true for
+    fn retain_in_flight(&mut self, mut keep: impl FnMut(&Position, u64) -> bool) {
+        let counts = &mut self.in_flight_count;
+        self.in_flight.retain(|position, id| {
+            let kept = keep(position, *id);
+            if !kept {
+                uncount(counts, *id);
+            }
+            kept
+        });
+    }
+
+    /// Removes consumers whose subscription was dropped, releasing their events for
+    /// redelivery
+    fn prune(&mut self) {
+        // The subscription holds the only other reference to `dropped`
+        self.consumers
+            .retain(|consumer| Arc::strong_count(&consumer.dropped) > 1);
+        let ids: HashSet<u64> = self.consumers.iter().map(|c| c.id).collect();
+        self.retain_in_flight(|_, id| ids.contains(&id));
+    }
+
+    fn close(&mut self, reason: DropReason) {
+        for consumer in &self.consumers {
+            set_reason(&consumer.dropped, reason);
+        }
+        self.consumers.clear();
+    }
+}
+
+/// Takes one event off a consumer's in-flight count
+fn uncount(counts: &mut HashMap<u64, usize>, id: u64) {
+    if let Some(count) = counts.get_mut(&id) {
+        *count -= 1;
+        if *count == 0 {
+            counts.remove(&id);
+        }
+    }
+}
+
+impl EventStore {
+    pub fn create_group(&mut self, group: &str, settings: GroupSettings) -> Result<()> {
+        if self.groups.contains_key(group) {
+            return Err(NexusError::GroupExists(group.to_string()));
+        }
+        let mut state = PersistentGroup {
+            checkpoint: settings.start_from,
+            settings,
+            acked: im::OrdSet::new(),
+            retries: im::OrdMap::new(),
+            parked: im::OrdSet::new(),
+        };
+        self.advance_checkpoint(&mut state);
+        self.groups.insert(group.to_string(), state);
+        self.dispatch_groups();
+        Ok(())
+    }
+
+    /// Deletes the group and drops its connected consumers
+    pub fn delete_group(&mut self, group: &str) -> Result<()> {
+        self.groups
+            .remove(group)
+            .ok_or_else(|| NexusError::GroupNotFound(group.to_string()))?;
+        self.dispatch_groups();
+        Ok(())
+    }
+
+    pub fn group(&self, group: &str) -> Option<&PersistentGroup> {
+        self.groups.get(group)
+    }
+
+    /// Marks events as handled, moving the checkpoint past every settled prefix.
+    /// Positions that are not events of the group are ignored.
+    pub fn ack(&mut self, group: &str, positions: &[Position]) -> Result<()> {
+        self.update_group(group, |store, state| {
+            for position in positions
+                .iter()
+                .filter(|p| store.in_group(&state.settings.filter, **p))
+            {
+                state.retries.remove(position);
+                state.parked.remove(position);
+                if *position >= state.checkpoint {
+                    state.acked.insert(*position);
+                }
+            }
+        })
+    }
+
+    /// Reports events a consumer could not handle. Returns the ones that got parked.
+    /// Positions that are not events of the group are ignored.
+    pub fn nack(
+        &mut self,
+        group: &str,
+        positions: &[Position],
+        action: NackAction,
+    ) -> Result<Vec<Position>> {
+        // Nacked events are no longer in flight; retries go out again
+        if let Some(dispatcher) = self.dispatchers.get_mut(group) {
+            for position in positions {
+                dispatcher.release(position);
+            }
+        }
+
+        self.update_group(group, |store, state| {
+            let mut parked = Vec::new();
+            for &position in positions {
+                let settled = !store.in_group(&state.settings.filter, position)
+                    || position < state.checkpoint
+                    || state.acked.contains(&position)
+                    || state.parked.contains(&position);
+                if settled {
+                    continue;
+                }
+
+                let retries = state.retries.get(&position).map_or(1, |n| n + 1);
+                match action {
+                    NackAction::Retry if retries <= state.settings.max_retries => {
+                        state.retries.insert(position, retries);
+                    }
+                    NackAction::Retry | NackAction::Park => {
+                        state.retries.remove(&position);
+                        state.parked.insert(position);
+                        parked.push(position);
+                    }
+                    NackAction::Skip => {
+                        state.retries.remove(&position);
+                        state.acked.insert(position);
+                    }
+                }
+            }
+            parked
+        })
+    }
+
+    /// Redelivers every parked event, in position order. Returns how many there were.
+    pub fn replay_parked(&mut self, group: &str) -> Result<usize> {
+        self.update_group(group, |store, state| {
+            let count = state.parked.len();
+            let Some(first) = state.parked.get_min().copied() else {
+                return 0;
+            };
+            // Move the checkpoint back to the first parked event; what was settled in
+            // between stays settled
+            let settled: Vec<Position> = store
+                .all_from(first)
+                .take_while(|e| e.position < state.checkpoint)
+                .filter(|e| state.settings.filter.matches(e))
+                .map(|e| e.position)
+                .filter(|p| !state.parked.contains(p))
+                .collect();
+            state.acked.extend(settled);
+            state.checkpoint = first;
+            state.parked = im::OrdSet::new();
+            count
+        })
+    }
+
+    /// Connects a consumer to the group. It competes with the group's other consumers
+    /// on this replica and is sent at most `capacity` events it has not acked or nacked.
+    pub fn connect(
+        &mut self,
+        group: &str,
+        capacity: usize,
+    ) -> Result<Subscription<PersistentEvent>> {
+        if !self.groups.contains_key(group) {
+            return Err(NexusError::GroupNotFound(group.to_string()));
+        }
+
+        let (subscription, tx, dropped) = Subscription::channel(capacity);
+        let dispatcher = self.dispatchers.entry(group.to_string()).or_default();
+        dispatcher.consumers.push(Consumer {
+            id: dispatcher.next_id,
+            capacity: capacity.max(1),
+            tx,
+            dropped,
+        });
+        dispatcher.next_id += 1;
+        self.dispatch_groups();
+        Ok(subscription)
+    }
+
+    /// Sends every group's consumers the events they can take
+    pub(crate) fn dispatch_groups(&mut self) {
+        let mut dispatchers = std::mem::take(&mut self.dispatchers);
+        dispatchers.retain(|name, dispatcher| match self.groups.get(name) {
+            Some(state) => {
+                dispatcher.prune();
+                while self.dispatch(state, dispatcher) {
+                    dispatcher.prune();
+                }
+                true
+            }
+            None => {
+                dispatcher.close(DropReason::GroupDeleted);
+                false
+            }
+        });
+        self.dispatchers = dispatchers;
+    }
+
+    /// Sends unsettled events from the checkpoint on until the consumers are full.
+    /// Returns true if a consumer turned out to be gone, so the dispatch is repeated
+    /// without it.
+    fn dispatch(&self, state: &PersistentGroup, dispatcher: &mut Dispatcher) -> bool {
+        // Forget deliveries settled since, e.g. through a snapshot restore
+        dispatcher.retain_in_flight(|p, _| {
+            *p >= state.checkpoint && !state.acked.contains(p) && !state.parked.contains(p)
+        });
+
+        let pinned = state.settings.strategy == DispatchStrategy::Pinned;
+        let count = dispatcher.consumers.len();
+        let mut full = HashSet::new();
+        let mut closed = HashSet::new();
+        let mut blocked: HashSet<&AggregatedId> = HashSet::new(); // Aggregates with an earlier event outstanding
+
+        let pending = self
+            .all_from(state.checkpoint)
+            .filter(|e| state.settings.filter.matches(e))
+            .filter(|e| !state.acked.contains(&e.position) && !state.parked.contains(&e.position));
+        for event in pending {
+            if full.len() + closed.len() >= count {
+                break;
+            }
+            let aggregate = event.aggregate_id();
+            if dispatcher.in_flight.contains_key(&event.position)
+                || (pinned && blocked.contains(aggregate))
+            {
+                blocked.insert(aggregate);
+                continue;
+            }
+
+            let message = PersistentEvent {
+                event: event.clone(),
+                retry_count: state.retries.get(&event.position).copied().unwrap_or(0),
+            };
+            let taken = if pinned {
+                let mut hasher = DefaultHasher::new();
+                aggregate.hash(&mut hasher);
+                let pin = (hasher.finish() % count as u64) as usize;
+                dispatcher.offer(std::iter::once(pin), message, &mut full, &mut closed)
+            } else {
+                let start = dispatcher.next_consumer;
+                let candidates = (0..count).map(|i| (start + i) % count);
+                dispatcher.offer(candidates, message, &mut full, &mut closed)
+            };
+            if let Some(id) = taken {
+                dispatcher.track(event.position, id);
+            }
+            blocked.insert(aggregate);
+        }
+
+        !closed.is_empty()
+    }
+
+    /// Whether `position` holds an event a group with `filter` receives
+    fn in_group(&self, filter: &EventFilter, position: Position) -> bool {
+        self.event_at(position)
+            .is_some_and(|event| filter.matches(event))
+    }
+
+    /// Applies `f` to the group's replicated state, then moves its checkpoint and
+    /// dispatches
+    fn update_group<T>(
+        &mut self,
+        group: &str,
+        f: impl FnOnce(&Self, &mut PersistentGroup) -> T,
+    ) -> Result<T> {
+        let mut state = self
+            .groups
+            .get(group)
+            .cloned()
+            .ok_or_else(|| NexusError::GroupNotFound(group.to_string()))?;
+        let out = f(self, &mut state);
+        self.advance_checkpoint(&mut state);
+        self.groups.insert(group.to_string(), state);
+        self.dispatch_groups();
+        Ok(out)
+    }
+
+    /// Moves the checkpoint past matching events that are acked or parked
+    fn advance_checkpoint(&self, state: &mut PersistentGroup) {
+        let filter = state.settings.filter.clone();
+        let mut checkpoint = state.checkpoint;
+        for event in self.all_from(checkpoint).filter(|e| filter.matches(e)) {
+            if state.acked.remove(&event.position).is_none()
+                && !state.parked.contains(&event.position)
+            {
+                checkpoint = event.position;
+                break;
+            }
+            checkpoint = event.position.next();
+        }
+        state.checkpoint = checkpoint;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{append, drain};
+
+    /// Buffered events as (type, retry count)
+    fn deliveries(consumer: &Subscription<PersistentEvent>) -> Vec<(String, u32)> {
+        drain(consumer)
+            .into_iter()
+            .map(|message| (message.event.event_type, message.retry_count))
+            .collect()
+    }
+
+    fn types(received: &[(String, u32)]) -> Vec<&str> {
+        received.iter().map(|(t, _)| t.as_str()).collect()
+    }
+
+    fn position(store: &EventStore, event_type: &str) -> Position {
+        store
+            .all_from(Position::START)
+            .find(|e| e.event_type == event_type)
+            .unwrap()
+            .position
+    }
+
+    #[test]
+    fn test_round_robin_and_checkpoint() {
+        let mut store = EventStore::new();
+        append(&mut store, "order-1", &["a", "b"]);
+        append(&mut store, "user-1", &["skipped"]);
+        append(&mut store, "order-2", &["c", "d"]);
+        let settings = GroupSettings::new(EventFilter::StreamPrefix("order-".into()));
+        store.create_group("billing", settings).unwrap();
+
+        let first = store.connect("billing", 10).unwrap();
+        let second = store.connect("billing", 1).unwrap();
+        // The first consumer took everything before the second connected
+        assert_eq!(types(&deliveries(&first)), ["a", "b", "c", "d"]);
+        assert!(deliveries(&second).is_empty());
+
+        // Out-of-order acks only move the checkpoint past a settled prefix
+        let (a, b, c) = (
+            position(&store, "a"),
+            position(&store, "b"),
+            position(&store, "c"),
+        );
+        store.ack("billing", &[b, c]).unwrap();
+        assert_eq!(store.group("billing").unwrap().checkpoint, a);
+        store.ack("billing", &[a]).unwrap();
+        assert_eq!(
+            store.group("billing").unwrap().checkpoint,
+            position(&store, "d")
+        );
+
+        append(&mut store, "order-3", &["e", "f", "g"]);
+        assert_eq!(types(&deliveries(&second)), ["e"]);
+        assert_eq!(types(&deliveries(&first)), ["f", "g"]);
+        assert!(matches!(
+            store.create_group("billing", GroupSettings::new(EventFilter::All)),
+            Err(NexusError::GroupExists(_))
+        ));
+    }
+
+    #[test]
+    fn test_nack_retries_then_parks() {
+        let mut store = EventStore::new();
+        append(&mut store, "s", &["poison", "fine"]);
+        let settings = GroupSettings::new(EventFilter::All).with_max_retries(1);
+        store.create_group("g", settings).unwrap();
+        let consumer = store.connect("g", 10).unwrap();
+        assert_eq!(
+            deliveries(&consumer),
+            [("poison".into(), 0), ("fine".into(), 0)]
+        );
+
+        let (poison, fine) = (position(&store, "poison"), position(&store, "fine"));
+        store.ack("g", &[fine]).unwrap();
+        let parked = store.nack("g", &[poison], NackAction::Retry).unwrap();
+        assert!(parked.is_empty());
+        assert_eq!(deliveries(&consumer), [("poison".into(), 1)]);
+
+        let parked = store.nack("g", &[poison], NackAction::Retry).unwrap();
+        assert_eq!(parked, [poison]);
+        assert!(deliveries(&consumer).is_empty());
+        let group = store.group("g").unwrap();
+        assert_eq!(group.checkpoint, fine.next());
+        assert!(group.parked.contains(&poison));
+
+        // Replaying redelivers the parked event without what was acked after it
+        assert_eq!(store.replay_parked("g").unwrap(), 1);
+        assert_eq!(deliveries(&consumer), [("poison".into(), 0)]);
+        store.ack("g", &[poison]).unwrap();
+        assert_eq!(store.group("g").unwrap().checkpoint, fine.next());
+    }
+
+    #[test]
+    fn test_pinned_keeps_aggregates_ordered() {
+        let mut store = EventStore::new();
+        let settings = GroupSettings::new(EventFilter::All).with_strategy(DispatchStrategy::Pinned);
+        store.create_group("g", settings).unwrap();
+        let consumers = [
+            store.connect("g", 10).unwrap(),
+            store.connect("g", 10).unwrap(),
+        ];
+
+        for i in 0..8 {
+            append(
+                &mut store,
+                &format!("agg-{}", i % 4),
+                &[&format!("{}-{}", i % 4, i / 4)],
+            );
+        }
+        // One event per aggregate is outstanding at a time
+        let received: Vec<Vec<(String, u32)>> = consumers.iter().map(deliveries).collect();
+        let mut first: Vec<&str> = received.iter().flat_map(|r| types(r)).collect();
+        first.sort();
+        assert_eq!(first, ["0-0", "1-0", "2-0", "3-0"]);
+
+        // A retry is redelivered to the same consumer before the aggregate's next event
+        let owner = &consumers[received
+            .iter()
+            .position(|r| types(r).contains(&"0-0"))
+            .unwrap()];
+        let (zero, one) = (position(&store, "0-0"), position(&store, "0-1"));
+        store.nack("g", &[zero], NackAction::Retry).unwrap();
+        assert_eq!(deliveries(owner), [("0-0".into(), 1)]);
+
+        store.ack("g", &[zero]).unwrap();
+        assert_eq!(deliveries(owner), [("0-1".into(), 0)]);
+        store.ack("g", &[one]).unwrap();
+        assert!(deliveries(owner).is_empty());
+    }
+
+    #[test]
+    fn test_disconnect_releases_events() {
+        let mut store = EventStore::new();
+        append(&mut store, "s", &["a", "b"]);
+        store
+            .create_group("g", GroupSettings::new(EventFilter::All))
+            .unwrap();
+        let gone = store.connect("g", 10).unwrap();
+        assert_eq!(types(&deliveries(&gone)), ["a", "b"]);
+        drop(gone);
+
+        let other = store.connect("g", 10).unwrap();
+        append(&mut store, "s", &["c"]);
+        assert_eq!(types(&deliveries(&other)), ["a", "b", "c"]);
+
+        store.delete_group("g").unwrap();
+        assert_eq!(other.try_recv(), Err(DropReason::GroupDeleted));
+    }
+
+    #[test]
+    fn test_ack_and_nack_ignore_positions_outside_group() {
+        let mut store = EventStore::new();
+        append(&mut store, "order-1", &["a"]);
+        append(&mut store, "user-1", &["other"]);
+        let settings = GroupSettings::new(EventFilter::StreamPrefix("order-".into()));
+        store.create_group("g", settings).unwrap();
+        let consumer = store.connect("g", 10).unwrap();
+        assert_eq!(types(&deliveries(&consumer)), ["a"]);
+
+        // The next append lands at log index 3
+        let future = Position::new(3, 0);
+        let other = position(&store, "other");
+        store.ack("g", &[future, other]).unwrap();
+        let parked = store
+            .nack("g", &[Position::new(9, 0)], NackAction::Park)
+            .unwrap();
+        assert!(parked.is_empty());
+        let group = store.group("g").unwrap();
+        assert!(group.acked.is_empty() && group.parked.is_empty() && group.retries.is_empty());
+
+        // The event later written at the acked position is still delivered
+        append(&mut store, "order-2", &["b"]);
+        assert_eq!(position(&store, "b"), future);
+        store.ack("g", &[position(&store, "a")]).unwrap();
+        assert_eq!(types(&deliveries(&consumer)), ["b"]);
+        assert_eq!(store.group("g").unwrap().checkpoint, future);
+    }
+
+    #[test]
+    fn test_capacity_limits_unacked_events() {
+        let mut store = EventStore::new();
+        append(&mut store, "s", &["a", "b", "c"]);
+        store
+            .create_group("g", GroupSettings::new(EventFilter::All))
+            .unwrap();
+        let consumer = store.connect("g", 2).unwrap();
+
+        // Receiving frees the channel, but the events stay in flight until acked
+        assert_eq!(types(&deliveries(&consumer)), ["a", "b"]);
+        append(&mut store, "s", &["d"]);
+        assert!(deliveries(&consumer).is_empty());
+
+        store.ack("g", &[position(&store, "a")]).unwrap();
+        assert_eq!(types(&deliveries(&consumer)), ["c"]);
+        store
+            .nack("g", &[position(&store, "b")], NackAction::Skip)
+            .unwrap();
+        assert_eq!(types(&deliveries(&consumer)), ["d"]);
+    }
+}
@@ -0,0 +1,391 @@
+use crate::event::{EventData, Position};
+use crate::persistent::{GroupSettings, NackAction, PersistentGroup};
+use crate::store::{AppendResult, EventStore, Stream, StreamMetadata};
+use chrono::{DateTime, Utc};
+use nexus_common::error::NexusError;
+use nexus_common::types::{ExpectedVersion, StreamId};
+use nexus_storage::raft::state_machine::{FrozenState, StateMachine};
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::io::{Read, Write};
+
+/// Writes to the event store, replicated through the Raft log.
+/// Timestamps are chosen by the proposing leader so every replica records the same ones.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum EventCommand {
+    Append {
+        stream_id: StreamId,
+        expected_version: ExpectedVersion,
+        events: Vec<EventData>,
+        created: DateTime<Utc>,
+    },
+    DeleteStream {
+        stream_id: StreamId,
+        expected_version: ExpectedVersion,
+    },
+    SetStreamMetadata {
+        stream_id: StreamId,
+        metadata: StreamMetadata,
+    },
+    CreateGroup {
+        group: String,
+        settings: GroupSettings,
+    },
+    DeleteGroup {
+        group: String,
+    },
+    Ack {
+        group: String,
+        positions: Vec<Position>,
+    },
+    Nack {
+        group: String,
+        positions: Vec<Position>,
+        action: NackAction,
+    },
+    ReplayParked {
+        group: String,
+    },
+}
+
+/// Successful outcome of an `EventCommand`
+#[derive(Debug, Clone, PartialEq)]
+pub enum EventResponse {
+    Appended(AppendResult),
+    StreamDeleted,
+    MetadataSet,
+    GroupCreated,
+    GroupDeleted,
+    Acked,
+    Nacked { parked: Vec<Position> },
+    ParkedReplayed(usize), // How many parked events are redelivered
+}
+
+impl EventCommand {
+    /// Append stamped with the proposer's current time
+    pub fn append(
+        stream_id: impl Into<StreamId>,
+        expected_version: ExpectedVersion,
+        events: Vec<EventData>,
+    ) -> Self {
+        EventCommand::Append {
+            stream_id: stream_id.into(),
+            expected_version,
+            events,
+            created: Utc::now(),
+        }
+    }
+}
+
+/// Point-in-time copy of an `EventStore`. The $all index is rebuilt from the streams on
+/// restore, so it is not written.
+struct FrozenEvents {
+    streams: im::OrdMap<StreamId, Stream>,
+    groups: im::OrdMap<String, PersistentGroup>,
+    last_index: u64,
+}
+
+impl FrozenState for FrozenEvents {
+    fn write_to(&self, out: &mut dyn Write) -> Result<(), Box<dyn Error>> {
+        bincode::serialize_into(out, &(self.last_index, &self.streams, &self.groups))?;
+        Ok(())
+    }
+}
+
+impl EventStore {
+    /// Events appended by the entry at `index` get positions `(index, 0..)`
+    fn execute(&mut self, index: u64, command: EventCommand) -> Result<EventResponse, NexusError> {
+        self.last_index = index;
+        match command {
+            EventCommand::Append {
+                stream_id,
+                expected_version,
+                events,
+                created,
+            } => self
+                .append_at(&stream_id, expected_version, events, created, index)
+                .map(EventResponse::Appended),
+            EventCommand::DeleteStream {
+                stream_id,
+                expected_version,
+            } => self
+                .delete_stream(&stream_id, expected_version)
+                .map(|()| EventResponse::StreamDeleted),
+            EventCommand::SetStreamMetadata {
+                stream_id,
+                metadata,
+            } => self
+                .set_stream_metadata(&stream_id, metadata)
+                .map(|()| EventResponse::MetadataSet),
+            EventCommand::CreateGroup { group, settings } => self
+                .create_group(&group, settings)
+                .map(|()| EventResponse::GroupCreated),
+            EventCommand::DeleteGroup { group } => self
+                .delete_group(&group)
+                .map(|()| EventResponse::GroupDeleted),
+            EventCommand::Ack { group, positions } => {
+                self.ack(&group, &positions).map(|()| EventResponse::Acked)
+            }
+            EventCommand::Nack {
+                group,
+                positions,
+                action,
+            } => self
+                .nack(&group, &positions, action)
+                .map(|parked| EventResponse::Nacked { parked }),
+            EventCommand::ReplayParked { group } => self
+                .replay_parked(&group)
+                .map(EventResponse::ParkedReplayed),
+        }
+    }
+}
+
+/// Expected-version checks run at apply time, in log order, so every replica accepts
+/// and rejects the same appends
+impl StateMachine for EventStore {
+    type Command = EventCommand;
+    type Response = Result<EventResponse, NexusError>;
+
+    /// Applies a command as if it were the next log entry
+    fn apply(&mut self, command: Self::Command) -> Self::Response {
+        self.execute(self.last_index + 1, command)
+    }
+
+    /// Rejected commands still apply: the rejection is their response
+    fn apply_entry(
+        &mut self,
+        index: u64,
+        command: Self::Command,
+    ) -> Result<Self::Response, NexusError> {
+        Ok(self.execute(index, command))
+    }
+
+    fn freeze(&self) -> Result<Box<dyn FrozenState>, NexusError> {
+        Ok(Box::new(FrozenEvents {
+            streams: self.streams.clone(), // O(1): shares structure with `streams`
+            groups: self.groups.clone(),
+            last_index: self.last_index,
+        }))
+    }
+
+    fn restore(&mut self, input: &mut dyn Read) -> Result<(), Box<dyn Error>> {
+        (self.last_index, self.streams, self.groups) = bincode::deserialize_from(input)?;
+        self.rebuild_all();
+        self.notify_subscribers();
+        Ok(())
+    }
+
+    /// Returns the stream's current version
+    fn get(&self, stream_id: String) -> Option<String> {
+        self.stream_version(&stream_id).map(|v| v.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::event::Position;
+    use crate::persistent::GroupSettings;
+    use crate::store::{Direction, EventFilter};
+    use crate::test_support::append_command as append;
+
+    #[test]
+    fn test_apply_checks_versions_in_log_order() {
+        let mut store = EventStore::new();
+        // Two clients raced with the same expected version; the log decides the winner
+        let first = append("order-1", ExpectedVersion::NoStream, &["created"]);
+        let second = append("order-1", ExpectedVersion::NoStream, &["created-too"]);
+
+        assert!(matches!(store.apply(first), Ok(EventResponse::Appended(_))));
+        assert!(matches!(
+            store.apply(second),
+            Err(NexusError::WrongExpectedVersion {
+                actual: Some(0),
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn test_commands_survive_bincode() {
+        let command = append("order-1", ExpectedVersion::Exact(3), &["paid"]);
+        let bytes = bincode::serialize(&command).unwrap();
+        assert_eq!(
+            bincode::deserialize::<EventCommand>(&bytes).unwrap(),
+            command
+        );
+    }
+
+    #[test]
+    fn test_snapshot_restore_captures_every_stream() {
+        let mut store = EventStore::new();
+        store
+            .apply(append("a", ExpectedVersion::Any, &["x", "y"]))
+            .unwrap();
+        store
+            .apply(append("b", ExpectedVersion::Any, &["z"]))
+            .unwrap();
+        store
+            .apply(EventCommand::DeleteStream {
+                stream_id: "b".into(),
+                expected_version: ExpectedVersion::Any,
+            })
+            .unwrap();
+        let metadata = StreamMetadata {
+            custom: b"owner=billing".to_vec(),
+            ..StreamMetadata::default()
+        };
+        store
+            .apply(EventCommand::SetStreamMetadata {
+                stream_id: "a".into(),
+                metadata: metadata.clone(),
+            })
+            .unwrap();
+
+        let mut snap = Vec::new();
+        store.snapshot(&mut snap).unwrap();
+        let mut restored = EventStore::new();
+        restored.restore(&mut &snap[..]).unwrap();
+
+        let original: Vec<_> = store.events("a").cloned().collect();
+        let copied: Vec<_> = restored.events("a").cloned().collect();
+        assert_eq!(copied, original);
+        assert_eq!(restored.stream_metadata("a"), Some(&metadata));
+        assert!(matches!(
+            restored.apply(append("b", ExpectedVersion::Any, &["w"])),
+            Err(NexusError::StreamDeleted(_))
+        ));
+        assert_eq!(restored.get("a".into()), Some("1".into()));
+
+        // Positions continue where the snapshot left off
+        restored
+            .apply(append("c", ExpectedVersion::Any, &["v"]))
+            .unwrap();
+        assert_eq!(
+            restored.events("c").next().unwrap().position,
+            Position::new(6, 0)
+        );
+    }
+
+    #[test]
+    fn test_positions_come_from_log_index() {
+        let entries = vec![
+            (3, append("a", ExpectedVersion::Any, &["x", "y"])),
+            (4, append("a", ExpectedVersion::Exact(0), &["rejected"])),
+            (7, append("b", ExpectedVersion::Any, &["z"])),
+        ];
+        let mut store = EventStore::new();
+        for (index, command) in entries.clone() {
+            let _ = store.apply_entry(index, command);
+        }
+
+        let page = store.read_all(Position::START, Direction::Forward, 10, &EventFilter::All);
+        let positions: Vec<Position> = page.events.iter().map(|e| e.position).collect();
+        assert_eq!(
+            positions,
+            [
+                Position::new(3, 0),
+                Position::new(3, 1),
+                Position::new(7, 0)
+            ]
+        );
+
+        // A replica replaying the same log, and one restored from a snapshot, agree
+        let mut replayed = EventStore::new();
+        for (index, command) in entries {
+            let _ = replayed.apply_entry(index, command);
+        }
+        let mut snap = Vec::new();
+        store.snapshot(&mut snap).unwrap();
+        let mut restored = EventStore::new();
+        restored.restore(&mut &snap[..]).unwrap();
+
+        for other in [&replayed, &restored] {
+            let read = other.read_all(Position::START, Direction::Forward, 10, &EventFilter::All);
+            assert_eq!(read, page);
+        }
+    }
+
+    #[test]
+    fn test_group_progress_survives_snapshot() {
+        let mut store = EventStore::new();
+        store
+            .apply(append("s", ExpectedVersion::Any, &["a", "b", "c"]))
+            .unwrap();
+        let settings = GroupSettings::new(EventFilter::All);
+        store
+            .apply(EventCommand::CreateGroup {
+                group: "g".into(),
+                settings,
+            })
+            .unwrap();
+        let positions: Vec<Position> = store.events("s").map(|e| e.position).collect();
+        store
+            .apply(EventCommand::Ack {
+                group: "g".into(),
+                positions: vec![positions[0]],
+            })
+            .unwrap();
+        let response = store.apply(EventCommand::Nack {
+            group: "g".into(),
+            positions: vec![positions[1]],
+            action: NackAction::Park,
+        });
+        assert_eq!(
+            response.unwrap(),
+            EventResponse::Nacked {
+                parked: vec![positions[1]]
+            }
+        );
+
+        let mut snap = Vec::new();
+        store.snapshot(&mut snap).unwrap();
+        let mut restored = EventStore::new();
+        restored.restore(&mut &snap[..]).unwrap();
+        assert_eq!(restored.group("g"), store.group("g"));
+        assert_eq!(restored.group("g").unwrap().checkpoint, positions[2]);
+
+        // A consumer on the restored replica resumes from the checkpoint
+        let consumer = restored.connect("g", 10).unwrap();
+        let message = consumer.try_recv().unwrap().unwrap();
+        assert_eq!(message.event.event_type, "c");
+        assert_eq!(consumer.try_recv(), Ok(None));
+    }
+
+    #[test]
+    fn test_commands_replicate_through_raft_node() {
+        use nexus_common::types::{ClusterConfig, NodeAddress};
+        use nexus_storage::raft::node::RaftNode;
+
+        let config = ClusterConfig {
+            nodes: vec![NodeAddress {
+                host: "127.0.0.1".into(),
+                port: 8080,
+                node_id: "node1".into(),
+                priority: 1,
+            }],
+            replication_factor: 1,
+            election_timeout_ms: 150,
+            heartbeat_interval_ms: 50,
+            compression: Default::default(),
+        };
+        let mut node: RaftNode<EventCommand, Result<EventResponse, NexusError>> =
+            RaftNode::with_config("node1".into(), &config, Box::new(EventStore::new()));
+        assert!(node.peers.is_empty());
+        let command = append("order-1", ExpectedVersion::NoStream, &["created"]);
+        assert_eq!(node.propose_command(&command), None);
+
+        node.become_leader();
+        let index = node.propose_command(&command).unwrap();
+        node.commit_index = index;
+
+        let mut store = EventStore::new();
+        node.apply_committed_entries(&mut store).unwrap();
+        assert_eq!(node.log.last_applied, index);
+
+        let recorded: Vec<_> = store.events("order-1").cloned().collect();
+        assert_eq!(recorded.len(), 1);
+        assert_eq!(recorded[0].event_type, "created");
+        assert_eq!(recorded[0].position.log_index, index);
+    }
+}
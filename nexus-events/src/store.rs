@@ -0,0 +1,687 @@
+use crate::event::{EventData, Position, RecordedEvent};
+use crate::persistent::{Dispatcher, PersistentGroup};
+use crate::subscription::Subscriber;
+use chrono::{DateTime, Utc};
+use nexus_common::error::{NexusError, Result};
+use nexus_common::types::{ExpectedVersion, StreamId, Version};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// Outcome of a successful append
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AppendResult {
+    /// Pass this as the expected version of the next append to the stream
+    pub next_expected_version: ExpectedVersion,
+}
+
+/// Which way a read walks a stream
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Forward,
+    Backward,
+}
+
+/// One page of a stream read
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReadStreamResult {
+    pub events: Vec<RecordedEvent>,
+    pub next_version: Version,  // Pass as `from` to read the following page
+    pub is_end_of_stream: bool, // No events remain in the read direction
+}
+
+/// Per-stream settings stored alongside the events
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct StreamMetadata {
+    pub max_count: Option<u64>, // Only the newest `max_count` events are readable
+    pub truncate_before: Option<Version>, // Events before this version are not readable
+    pub custom: Vec<u8>,        // Application-defined, opaque to the store
+}
+
+/// One stream's events and state
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub(crate) struct Stream {
+    pub events: im::Vector<RecordedEvent>,
+    pub metadata: StreamMetadata,
+    pub deleted: bool, // Deleted streams keep their name reserved; appends fail
+}
+
+impl Stream {
+    /// Lowest version that stream metadata still allows reading
+    pub(crate) fn first_readable(&self) -> Version {
+        let len = self.events.len() as Version;
+        let by_count = self
+            .metadata
+            .max_count
+            .map_or(0, |max| len.saturating_sub(max));
+        self.metadata.truncate_before.unwrap_or(0).max(by_count)
+    }
+
+    /// Events with versions in `[start, end)`
+    pub(crate) fn slice(
+        &self,
+        start: Version,
+        end: Version,
+    ) -> impl DoubleEndedIterator<Item = &RecordedEvent> {
+        let focus = (start < end).then(|| self.events.focus().narrow(start as usize..end as usize));
+        focus.into_iter().flatten()
+    }
+}
+
+/// Which events `read_all` returns
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum EventFilter {
+    All,
+    Stream(StreamId),
+    EventType(String),
+    StreamPrefix(String),
+}
+
+impl EventFilter {
+    pub fn matches(&self, event: &RecordedEvent) -> bool {
+        match self {
+            EventFilter::All => true,
+            EventFilter::Stream(stream_id) => event.stream_id == *stream_id,
+            EventFilter::EventType(event_type) => event.event_type == *event_type,
+            EventFilter::StreamPrefix(prefix) => event.stream_id.starts_with(prefix.as_str()),
+        }
+    }
+}
+
+/// One page of a `read_all`
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReadAllResult {
+    pub events: Vec<RecordedEvent>,
+    pub next_position: Option<Position>, // Next matching event, None at the end of $all
+}
+
+/// Append-only event streams. Events in a stream get consecutive versions starting at 0,
+/// and a global position that orders them across all streams (the $all log). Backed by
+/// persistent collections, so copies for snapshots are cheap.
+#[derive(Debug, Default)]
+pub struct EventStore {
+    pub(crate) streams: im::OrdMap<StreamId, Stream>,
+    pub(crate) all: im::OrdMap<Position, (StreamId, Version)>, // Rebuilt from `streams`
+    pub(crate) last_index: u64, // Log index of the last applied command
+    pub(crate) groups: im::OrdMap<String, PersistentGroup>, // Persistent subscription groups
+    pub(crate) subscribers: Vec<Subscriber>, // Local to this replica, not replicated
+    pub(crate) dispatchers: BTreeMap<String, Dispatcher>, // Local consumers of `groups`
+}
+
+impl EventStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Version of the stream's last event, or None if the stream does not exist
+    pub fn stream_version(&self, stream_id: &str) -> Option<Version> {
+        let stream = self.streams.get(stream_id)?;
+        (stream.events.len() as Version).checked_sub(1)
+    }
+
+    /// Appends `events` to the stream if it is in the `expected_version` state.
+    /// Fails with `WrongExpectedVersion` otherwise, leaving the stream untouched.
+    pub fn append_to_stream(
+        &mut self,
+        stream_id: &str,
+        expected_version: ExpectedVersion,
+        events: Vec<EventData>,
+    ) -> Result<AppendResult> {
+        // Outside Raft each call counts as the next log entry
+        self.last_index += 1;
+        self.append_at(
+            stream_id,
+            expected_version,
+            events,
+            Utc::now(),
+            self.last_index,
+        )
+    }
+
+    /// Like `append_to_stream`, for the command at `log_index` with the timestamp chosen
+    /// by its proposer
+    pub(crate) fn append_at(
+        &mut self,
+        stream_id: &str,
+        expected_version: ExpectedVersion,
+        events: Vec<EventData>,
+        created: DateTime<Utc>,
+        log_index: u64,
+    ) -> Result<AppendResult> {
+        self.check(stream_id, expected_version)?;
+
+        if !events.is_empty() {
+            let stream = self.streams.entry(stream_id.to_string()).or_default();
+            for (offset, event) in events.into_iter().enumerate() {
+                let version = stream.events.len() as Version;
+                let position = Position::new(log_index, offset as u32);
+                stream.events.push_back(RecordedEvent::new(
+                    stream_id, event, version, position, created,
+                ));
+                self.all.insert(position, (stream_id.to_string(), version));
+            }
+            self.notify_subscribers();
+        }
+
+        let next_expected_version = match self.stream_version(stream_id) {
+            Some(version) => ExpectedVersion::Exact(version),
+            None => ExpectedVersion::NoStream,
+        };
+        Ok(AppendResult {
+            next_expected_version,
+        })
+    }
+
+    /// Deletes the stream. Its events stop being readable through the stream and its
+    /// name cannot be appended to again.
+    pub fn delete_stream(
+        &mut self,
+        stream_id: &str,
+        expected_version: ExpectedVersion,
+    ) -> Result<()> {
+        self.check(stream_id, expected_version)?;
+
+        let stream = self.streams.entry(stream_id.to_string()).or_default();
+        stream.deleted = true;
+        self.notify_subscribers();
+        Ok(())
+    }
+
+    pub fn set_stream_metadata(&mut self, stream_id: &str, metadata: StreamMetadata) -> Result<()> {
+        match self.streams.get_mut(stream_id) {
+            Some(stream) if stream.deleted => Err(NexusError::StreamDeleted(stream_id.to_string())),
+            Some(stream) => {
+                stream.metadata = metadata;
+                Ok(())
+            }
+            None => {
+                let stream = Stream {
+                    metadata,
+                    ..Stream::default()
+                };
+                self.streams.insert(stream_id.to_string(), stream);
+                Ok(())
+            }
+        }
+    }
+
+    pub fn stream_metadata(&self, stream_id: &str) -> Option<&StreamMetadata> {
+        self.streams.get(stream_id).map(|s| &s.metadata)
+    }
+
+    /// Events of a stream, in version order; none once it is deleted
+    pub fn events(&self, stream_id: &str) -> impl Iterator<Item = &RecordedEvent> {
+        self.streams
+            .get(stream_id)
+            .filter(|s| !s.deleted)
+            .into_iter()
+            .flat_map(|s| s.events.iter())
+    }
+
+    /// Reads up to `max_count` events starting at version `from`, walking forward or
+    /// backward. Reading backward from `Version::MAX` starts at the newest event. Events
+    /// hidden by `max_count`/`truncate_before` metadata are skipped.
+    pub fn read_stream(
+        &self,
+        stream_id: &str,
+        from: Version,
+        direction: Direction,
+        max_count: usize,
+    ) -> Result<ReadStreamResult> {
+        let stream = match self.streams.get(stream_id) {
+            Some(stream) if stream.deleted => {
+                return Err(NexusError::StreamDeleted(stream_id.to_string()))
+            }
+            Some(stream) if !stream.events.is_empty() => stream,
+            _ => return Err(NexusError::StreamNotFound(stream_id.to_string())),
+        };
+
+        let len = stream.events.len() as Version;
+        let first = stream.first_readable();
+        let max_count = max_count as Version;
+
+        let result = match direction {
+            Direction::Forward => {
+                let start = from.max(first).min(len);
+                let end = start.saturating_add(max_count).min(len);
+                ReadStreamResult {
+                    events: stream.slice(start, end).cloned().collect(),
+                    next_version: end,
+                    is_end_of_stream: end == len,
+                }
+            }
+            Direction::Backward => {
+                if from < first {
+                    return Ok(ReadStreamResult {
+                        events: Vec::new(),
+                        next_version: from,
+                        is_end_of_stream: true,
+                    });
+                }
+                let end = from.min(len - 1) + 1; // Exclusive
+                let start = end.saturating_sub(max_count).max(first);
+                ReadStreamResult {
+                    events: stream.slice(start, end).rev().cloned().collect(),
+                    next_version: start.saturating_sub(1),
+                    is_end_of_stream: start == first,
+                }
+            }
+        };
+        Ok(result)
+    }
+
+    /// Reads up to `max_count` events matching `filter` across all streams, in position
+    /// order, starting at `from` (inclusive). Reading backward from `Position::END` starts
+    /// at the newest event. Events of deleted streams, and events hidden by a stream's
+    /// `max_count`/`truncate_before` metadata, are skipped.
+    pub fn read_all(
+        &self,
+        from: Position,
+        direction: Direction,
+        max_count: usize,
+        filter: &EventFilter,
+    ) -> ReadAllResult {
+        let positions: Box<dyn Iterator<Item = (&Position, &(StreamId, Version))>> = match direction
+        {
+            Direction::Forward => Box::new(self.all.range(from..)),
+            Direction::Backward => Box::new(self.all.range(..=from).rev()),
+        };
+        let mut matching = positions
+            .filter_map(|(_, (stream_id, version))| self.readable(stream_id, *version))
+            .filter(|event| filter.matches(event));
+
+        let events: Vec<RecordedEvent> = matching.by_ref().take(max_count).cloned().collect();
+        let next_position = matching.next().map(|event| event.position);
+        ReadAllResult {
+            events,
+            next_position,
+        }
+    }
+
+    /// Events of live streams from position `from` (inclusive), in position order
+    pub(crate) fn all_from(&self, from: Position) -> impl Iterator<Item = &RecordedEvent> {
+        self.all
+            .range(from..)
+            .filter_map(|(_, (stream_id, version))| self.readable(stream_id, *version))
+    }
+
+    /// The readable event at `position`, if any
+    pub(crate) fn event_at(&self, position: Position) -> Option<&RecordedEvent> {
+        let (stream_id, version) = self.all.get(&position)?;
+        self.readable(stream_id, *version)
+    }
+
+    /// The event at `version` unless its stream is deleted or the stream's `max_count` /
+    /// `truncate_before` hides it
+    fn readable(&self, stream_id: &str, version: Version) -> Option<&RecordedEvent> {
+        let stream = self.streams.get(stream_id).filter(|s| !s.deleted)?;
+        if version < stream.first_readable() {
+            return None;
+        }
+        stream.events.get(version as usize)
+    }
+
+    /// Rebuilds the $all index from the streams, e.g. after a restore
+    pub(crate) fn rebuild_all(&mut self) {
+        self.all = self
+            .streams
+            .iter()
+            .flat_map(|(stream_id, stream)| {
+                stream
+                    .events
+                    .iter()
+                    .map(move |e| (e.position, (stream_id.clone(), e.version)))
+            })
+            .collect();
+    }
+
+    /// Fails if the stream is deleted or not in the expected state
+    fn check(&self, stream_id: &str, expected_version: ExpectedVersion) -> Result<()> {
+        if self.streams.get(stream_id).is_some_and(|s| s.deleted) {
+            return Err(NexusError::StreamDeleted(stream_id.to_string()));
+        }
+
+        let actual = self.stream_version(stream_id);
+        if !expected_version.matches(actual) {
+            return Err(NexusError::WrongExpectedVersion {
+                stream_id: stream_id.to_string(),
+                expected: expected_version,
+                actual,
+            });
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::events;
+
+    fn types(store: &EventStore, stream_id: &str) -> Vec<String> {
+        store
+            .events(stream_id)
+            .map(|e| e.event_type.clone())
+            .collect()
+    }
+
+    #[test]
+    fn test_append_assigns_consecutive_versions() {
+        let mut store = EventStore::new();
+
+        let result = store
+            .append_to_stream(
+                "order-1",
+                ExpectedVersion::NoStream,
+                events(&["created", "paid"]),
+            )
+            .unwrap();
+        assert_eq!(result.next_expected_version, ExpectedVersion::Exact(1));
+
+        let result = store
+            .append_to_stream(
+                "order-1",
+                result.next_expected_version,
+                events(&["shipped"]),
+            )
+            .unwrap();
+        assert_eq!(result.next_expected_version, ExpectedVersion::Exact(2));
+        assert_eq!(types(&store, "order-1"), ["created", "paid", "shipped"]);
+
+        let versions: Vec<Version> = store.events("order-1").map(|e| e.version).collect();
+        assert_eq!(versions, [0, 1, 2]);
+    }
+
+    #[test]
+    fn test_positions_span_streams() {
+        let mut store = EventStore::new();
+        store
+            .append_to_stream("a", ExpectedVersion::Any, events(&["x"]))
+            .unwrap();
+        store
+            .append_to_stream("b", ExpectedVersion::Any, events(&["y", "z"]))
+            .unwrap();
+
+        assert_eq!(
+            store.events("a").next().unwrap().position,
+            Position::new(1, 0)
+        );
+        let positions: Vec<Position> = store.events("b").map(|e| e.position).collect();
+        assert_eq!(positions, [Position::new(2, 0), Position::new(2, 1)]);
+    }
+
+    #[test]
+    fn test_wrong_expected_version() {
+        let mut store = EventStore::new();
+        store
+            .append_to_stream("order-1", ExpectedVersion::Any, events(&["created"]))
+            .unwrap();
+
+        let err = store
+            .append_to_stream("order-1", ExpectedVersion::Exact(5), events(&["paid"]))
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            NexusError::WrongExpectedVersion {
+                expected: ExpectedVersion::Exact(5),
+                actual: Some(0),
+                ..
+            }
+        ));
+
+        let err = store
+            .append_to_stream("order-1", ExpectedVersion::NoStream, events(&["again"]))
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            NexusError::WrongExpectedVersion {
+                actual: Some(0),
+                ..
+            }
+        ));
+        assert_eq!(store.events("order-1").count(), 1);
+    }
+
+    #[test]
+    fn test_stream_exists_on_missing_stream() {
+        let mut store = EventStore::new();
+
+        let err = store
+            .append_to_stream("order-9", ExpectedVersion::StreamExists, events(&["paid"]))
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            NexusError::WrongExpectedVersion { actual: None, .. }
+        ));
+        assert_eq!(store.stream_version("order-9"), None);
+    }
+
+    #[test]
+    fn test_empty_append_does_not_create_stream() {
+        let mut store = EventStore::new();
+
+        let result = store
+            .append_to_stream("order-1", ExpectedVersion::NoStream, vec![])
+            .unwrap();
+        assert_eq!(result.next_expected_version, ExpectedVersion::NoStream);
+        assert_eq!(store.stream_version("order-1"), None);
+    }
+
+    #[test]
+    fn test_deleted_stream_rejects_appends() {
+        let mut store = EventStore::new();
+        store
+            .append_to_stream("order-1", ExpectedVersion::Any, events(&["created"]))
+            .unwrap();
+
+        assert!(matches!(
+            store.delete_stream("order-1", ExpectedVersion::Exact(3)),
+            Err(NexusError::WrongExpectedVersion { .. })
+        ));
+        store
+            .delete_stream("order-1", ExpectedVersion::Exact(0))
+            .unwrap();
+
+        assert_eq!(store.events("order-1").count(), 0);
+        assert!(matches!(
+            store.append_to_stream("order-1", ExpectedVersion::Any, events(&["again"])),
+            Err(NexusError::StreamDeleted(_))
+        ));
+    }
+
+    #[test]
+    fn test_metadata_on_new_stream() {
+        let mut store = EventStore::new();
+        let metadata = StreamMetadata {
+            max_count: Some(10),
+            ..StreamMetadata::default()
+        };
+        store
+            .set_stream_metadata("order-1", metadata.clone())
+            .unwrap();
+
+        assert_eq!(store.stream_metadata("order-1"), Some(&metadata));
+        assert_eq!(store.stream_version("order-1"), None);
+        store
+            .append_to_stream("order-1", ExpectedVersion::NoStream, events(&["created"]))
+            .unwrap();
+    }
+
+    fn read(
+        store: &EventStore,
+        from: Version,
+        direction: Direction,
+        max_count: usize,
+    ) -> (Vec<Version>, Version, bool) {
+        let page = store.read_stream("s", from, direction, max_count).unwrap();
+        let versions = page.events.iter().map(|e| e.version).collect();
+        (versions, page.next_version, page.is_end_of_stream)
+    }
+
+    fn store_with(count: usize) -> EventStore {
+        let mut store = EventStore::new();
+        let types: Vec<&str> = vec!["e"; count];
+        store
+            .append_to_stream("s", ExpectedVersion::Any, events(&types))
+            .unwrap();
+        store
+    }
+
+    #[test]
+    fn test_read_stream_forward_pages() {
+        let store = store_with(5);
+
+        assert_eq!(
+            read(&store, 0, Direction::Forward, 2),
+            (vec![0, 1], 2, false)
+        );
+        assert_eq!(
+            read(&store, 2, Direction::Forward, 2),
+            (vec![2, 3], 4, false)
+        );
+        assert_eq!(read(&store, 4, Direction::Forward, 2), (vec![4], 5, true));
+        assert_eq!(read(&store, 9, Direction::Forward, 2), (vec![], 5, true));
+    }
+
+    #[test]
+    fn test_read_stream_backward_pages() {
+        let store = store_with(5);
+
+        assert_eq!(
+            read(&store, Version::MAX, Direction::Backward, 2),
+            (vec![4, 3], 2, false)
+        );
+        assert_eq!(
+            read(&store, 2, Direction::Backward, 2),
+            (vec![2, 1], 0, false)
+        );
+        assert_eq!(read(&store, 0, Direction::Backward, 2), (vec![0], 0, true));
+    }
+
+    #[test]
+    fn test_read_stream_respects_metadata() {
+        let mut store = store_with(6);
+        let metadata = StreamMetadata {
+            max_count: Some(4),       // Versions 2.. readable
+            truncate_before: Some(3), // Versions 3.. readable
+            ..StreamMetadata::default()
+        };
+        store.set_stream_metadata("s", metadata).unwrap();
+
+        assert_eq!(
+            read(&store, 0, Direction::Forward, 10),
+            (vec![3, 4, 5], 6, true)
+        );
+        assert_eq!(
+            read(&store, 4, Direction::Backward, 10),
+            (vec![4, 3], 2, true)
+        );
+    }
+
+    #[test]
+    fn test_read_missing_and_deleted_streams() {
+        let mut store = store_with(1);
+
+        assert!(matches!(
+            store.read_stream("nope", 0, Direction::Forward, 10),
+            Err(NexusError::StreamNotFound(_))
+        ));
+        store.delete_stream("s", ExpectedVersion::Any).unwrap();
+        assert!(matches!(
+            store.read_stream("s", 0, Direction::Forward, 10),
+            Err(NexusError::StreamDeleted(_))
+        ));
+    }
+
+    fn all_store() -> EventStore {
+        let mut store = EventStore::new();
+        store
+            .append_to_stream("order-1", ExpectedVersion::Any, events(&["placed", "paid"]))
+            .unwrap();
+        store
+            .append_to_stream("user-1", ExpectedVersion::Any, events(&["registered"]))
+            .unwrap();
+        store
+            .append_to_stream("order-2", ExpectedVersion::Any, events(&["placed"]))
+            .unwrap();
+        store
+    }
+
+    fn all_types(page: &ReadAllResult) -> Vec<&str> {
+        page.events.iter().map(|e| e.event_type.as_str()).collect()
+    }
+
+    #[test]
+    fn test_read_all_forward_and_backward() {
+        let store = all_store();
+
+        let page = store.read_all(Position::START, Direction::Forward, 3, &EventFilter::All);
+        assert_eq!(all_types(&page), ["placed", "paid", "registered"]);
+        assert_eq!(page.next_position, Some(Position::new(3, 0)));
+
+        let page = store.read_all(Position::END, Direction::Backward, 2, &EventFilter::All);
+        assert_eq!(all_types(&page), ["placed", "registered"]);
+        assert_eq!(page.next_position, Some(Position::new(1, 1)));
+        let page = store.read_all(
+            Position::new(1, 1),
+            Direction::Backward,
+            5,
+            &EventFilter::All,
+        );
+        assert_eq!(all_types(&page), ["paid", "placed"]);
+        assert_eq!(page.next_position, None);
+    }
+
+    #[test]
+    fn test_read_all_filters() {
+        let mut store = all_store();
+
+        let by_type = EventFilter::EventType("placed".into());
+        let page = store.read_all(Position::START, Direction::Forward, 10, &by_type);
+        let streams: Vec<&str> = page.events.iter().map(|e| e.stream_id.as_str()).collect();
+        assert_eq!(streams, ["order-1", "order-2"]);
+
+        let by_stream = EventFilter::StreamPrefix("order-".into());
+        let page = store.read_all(Position::START, Direction::Forward, 1, &by_stream);
+        assert_eq!(page.next_position, Some(Position::new(1, 1)));
+
+        store
+            .delete_stream("order-1", ExpectedVersion::Any)
+            .unwrap();
+        let page = store.read_all(Position::START, Direction::Forward, 10, &by_stream);
+        assert_eq!(page.events.len(), 1);
+    }
+
+    #[test]
+    fn test_read_all_respects_stream_metadata() {
+        let mut store = all_store();
+        store
+            .set_stream_metadata(
+                "order-1",
+                StreamMetadata {
+                    max_count: Some(1),
+                    ..StreamMetadata::default()
+                },
+            )
+            .unwrap();
+
+        let page = store.read_all(Position::START, Direction::Forward, 10, &EventFilter::All);
+        assert_eq!(all_types(&page), ["paid", "registered", "placed"]);
+        let page = store.read_all(Position::END, Direction::Backward, 10, &EventFilter::All);
+        assert_eq!(all_types(&page), ["placed", "registered", "paid"]);
+
+        store
+            .set_stream_metadata(
+                "user-1",
+                StreamMetadata {
+                    truncate_before: Some(1),
+                    ..StreamMetadata::default()
+                },
+            )
+            .unwrap();
+        let types: Vec<&str> = store
+            .all_from(Position::START)
+            .map(|e| e.event_type.as_str())
+            .collect();
+        assert_eq!(types, ["paid", "placed"]);
+    }
+}
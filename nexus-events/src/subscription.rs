@@ -0,0 +1,420 @@
+use crate::event::{Position, RecordedEvent};
+use crate::store::{EventFilter, EventStore};
+use nexus_common::error::{NexusError, Result};
+use nexus_common::types::{StreamId, Version};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, SyncSender, TryRecvError, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Something delivered to a subscriber
+#[derive(Debug, Clone, PartialEq)]
+pub enum SubscriptionMessage {
+    Event(RecordedEvent),
+    CaughtUp, // History is replayed; what follows is live
+}
+
+/// Why the store ended a subscription
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DropReason {
+    TooSlow,       // Fell more than `max_lag` events behind after catching up
+    StreamDeleted, // The subscribed stream was deleted
+    GroupDeleted,  // The persistent subscription group was deleted
+    Closed,        // The store went away
+}
+
+/// Buffering limits of a subscription
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SubscriptionSettings {
+    pub capacity: usize, // Undelivered messages buffered before the store holds back
+    pub max_lag: usize,  // Events a live subscriber may fall behind before it is dropped
+}
+
+impl Default for SubscriptionSettings {
+    fn default() -> Self {
+        SubscriptionSettings {
+            capacity: 256,
+            max_lag: 10_000,
+        }
+    }
+}
+
+/// Receiving end of a subscription. Buffered messages are still delivered after the
+/// store drops it; after them every receive returns the `DropReason`. Dropping it
+/// unsubscribes.
+#[derive(Debug)]
+pub struct Subscription<M = SubscriptionMessage> {
+    rx: Receiver<M>,
+    dropped: Arc<Mutex<Option<DropReason>>>,
+}
+
+impl<M> Subscription<M> {
+    /// Creates a subscription buffering at most `capacity` messages, with the sender and
+    /// the slot the store fills in when it drops the subscription
+    pub(crate) fn channel(
+        capacity: usize,
+    ) -> (Self, SyncSender<M>, Arc<Mutex<Option<DropReason>>>) {
+        let (tx, rx) = mpsc::sync_channel(capacity.max(1));
+        let dropped = Arc::new(Mutex::new(None));
+        let subscription = Subscription {
+            rx,
+            dropped: Arc::clone(&dropped),
+        };
+        (subscription, tx, dropped)
+    }
+
+    /// Blocks until the next message
+    pub fn recv(&self) -> std::result::Result<M, DropReason> {
+        self.rx.recv().map_err(|_| self.reason())
+    }
+
+    /// Returns the next message if one is buffered
+    pub fn try_recv(&self) -> std::result::Result<Option<M>, DropReason> {
+        match self.rx.try_recv() {
+            Ok(message) => Ok(Some(message)),
+            Err(TryRecvError::Empty) => Ok(None),
+            Err(TryRecvError::Disconnected) => Err(self.reason()),
+        }
+    }
+
+    pub fn recv_timeout(&self, timeout: Duration) -> std::result::Result<Option<M>, DropReason> {
+        match self.rx.recv_timeout(timeout) {
+            Ok(message) => Ok(Some(message)),
+            Err(RecvTimeoutError::Timeout) => Ok(None),
+            Err(RecvTimeoutError::Disconnected) => Err(self.reason()),
+        }
+    }
+
+    fn reason(&self) -> DropReason {
+        let reason = self.dropped.lock().ok().and_then(|r| *r);
+        reason.unwrap_or(DropReason::Closed)
+    }
+}
+
+/// Where a subscriber reads from, and the first event it has not been sent
+#[derive(Debug)]
+pub(crate) enum Cursor {
+    Stream {
+        stream_id: StreamId,
+        next_version: Version,
+    },
+    All {
+        filter: EventFilter,
+        next_position: Position,
+    },
+}
+
+/// Outcome of handing a message to a subscriber
+enum Delivery {
+    Sent,
+    Full,   // The client is not keeping up; retry later
+    Closed, // The client dropped its subscription
+}
+
+/// Sending end of a subscription, held by the store
+#[derive(Debug)]
+pub(crate) struct Subscriber {
+    cursor: Cursor,
+    live: bool, // `CaughtUp` has been sent
+    max_lag: usize,
+    tx: SyncSender<SubscriptionMessage>,
+    dropped: Arc<Mutex<Option<DropReason>>>,
+}
+
+impl Subscriber {
+    fn new(cursor: Cursor, settings: SubscriptionSettings) -> (Self, Subscription) {
+        let (subscription, tx, dropped) = Subscription::channel(settings.capacity);
+        let subscriber = Subscriber {
+            cursor,
+            live: false,
+            max_lag: settings.max_lag,
+            tx,
+            dropped,
+        };
+        (subscriber, subscription)
+    }
+
+    fn send(&self, message: SubscriptionMessage) -> Delivery {
+        match self.tx.try_send(message) {
+            Ok(()) => Delivery::Sent,
+            Err(TrySendError::Full(_)) => Delivery::Full,
+            Err(TrySendError::Disconnected(_)) => Delivery::Closed,
+        }
+    }
+
+    /// Records why the subscription ends. Returns false so the caller drops the sender.
+    fn close(&self, reason: DropReason) -> bool {
+        set_reason(&self.dropped, reason);
+        false
+    }
+
+    fn wants(&self, event: &RecordedEvent) -> bool {
+        match &self.cursor {
+            Cursor::Stream { .. } => true,
+            Cursor::All { filter, .. } => filter.matches(event),
+        }
+    }
+
+    fn advance(&mut self, event: &RecordedEvent) {
+        match &mut self.cursor {
+            Cursor::Stream { next_version, .. } => *next_version = event.version + 1,
+            Cursor::All { next_position, .. } => *next_position = event.position.next(),
+        }
+    }
+}
+
+/// Records why the store ends a subscription, before it drops the sender
+pub(crate) fn set_reason(dropped: &Mutex<Option<DropReason>>, reason: DropReason) {
+    if let Ok(mut dropped) = dropped.lock() {
+        *dropped = Some(reason);
+    }
+}
+
+impl EventStore {
+    /// Subscribes to a stream from version `from` (inclusive), or to new events only if
+    /// None. History is replayed first, then `CaughtUp` is sent and live events follow,
+    /// with no gap or duplicate in between. Subscriptions are local to this replica.
+    pub fn subscribe_to_stream(
+        &mut self,
+        stream_id: &str,
+        from: Option<Version>,
+        settings: SubscriptionSettings,
+    ) -> Result<Subscription> {
+        let stream = self.streams.get(stream_id);
+        if stream.is_some_and(|s| s.deleted) {
+            return Err(NexusError::StreamDeleted(stream_id.to_string()));
+        }
+        let next_version = from.unwrap_or_else(|| stream.map_or(0, |s| s.events.len() as Version));
+
+        let cursor = Cursor::Stream {
+            stream_id: stream_id.to_string(),
+            next_version,
+        };
+        Ok(self.subscribe(cursor, settings))
+    }
+
+    /// Subscribes to events matching `filter` across all streams from position `from`
+    /// (inclusive), or to new events only if None. Delivered like `subscribe_to_stream`.
+    pub fn subscribe_to_all(
+        &mut self,
+        from: Option<Position>,
+        filter: EventFilter,
+        settings: SubscriptionSettings,
+    ) -> Subscription {
+        let next_position = from.unwrap_or(Position::new(self.last_index + 1, 0));
+        self.subscribe(
+            Cursor::All {
+                filter,
+                next_position,
+            },
+            settings,
+        )
+    }
+
+    fn subscribe(&mut self, cursor: Cursor, settings: SubscriptionSettings) -> Subscription {
+        let (mut subscriber, subscription) = Subscriber::new(cursor, settings);
+        if self.catch_up(&mut subscriber) {
+            self.subscribers.push(subscriber);
+        }
+        subscription
+    }
+
+    /// Sends every subscriber, and every persistent group's consumers, the events they
+    /// have not been sent yet. Runs after each write; hosts also call it periodically so
+    /// subscribers whose buffer was full catch up while nothing is written.
+    pub fn notify_subscribers(&mut self) {
+        let mut subscribers = std::mem::take(&mut self.subscribers);
+        subscribers.retain_mut(|subscriber| self.catch_up(subscriber));
+        self.subscribers = subscribers;
+        self.dispatch_groups();
+    }
+
+    /// Sends the subscriber events from its cursor until it is up to date or its buffer
+    /// is full. Returns false once the subscription is over.
+    fn catch_up(&self, subscriber: &mut Subscriber) -> bool {
+        let pending: Box<dyn Iterator<Item = &RecordedEvent>> = match &subscriber.cursor {
+            Cursor::Stream {
+                stream_id,
+                next_version,
+            } => match self.streams.get(stream_id) {
+                Some(stream) if stream.deleted => {
+                    return subscriber.close(DropReason::StreamDeleted)
+                }
+                Some(stream) => {
+                    let start = (*next_version).max(stream.first_readable());
+                    Box::new(stream.slice(start, stream.events.len() as Version))
+                }
+                None => Box::new(std::iter::empty()),
+            },
+            Cursor::All { next_position, .. } => Box::new(self.all_from(*next_position)),
+        };
+
+        for event in pending {
+            if !subscriber.wants(event) {
+                subscriber.advance(event);
+                continue;
+            }
+            match subscriber.send(SubscriptionMessage::Event(event.clone())) {
+                Delivery::Sent => subscriber.advance(event),
+                Delivery::Full if subscriber.live && self.lag(subscriber) > subscriber.max_lag => {
+                    return subscriber.close(DropReason::TooSlow)
+                }
+                Delivery::Full => return true,
+                Delivery::Closed => return false,
+            }
+        }
+
+        if !subscriber.live {
+            match subscriber.send(SubscriptionMessage::CaughtUp) {
+                Delivery::Sent => subscriber.live = true,
+                Delivery::Full => return true,
+                Delivery::Closed => return false,
+            }
+        }
+        true
+    }
+
+    /// Events not yet sent to the subscriber, counting at most `max_lag + 1`
+    fn lag(&self, subscriber: &Subscriber) -> usize {
+        match &subscriber.cursor {
+            Cursor::Stream {
+                stream_id,
+                next_version,
+            } => {
+                let len = self.streams.get(stream_id).map_or(0, |s| s.events.len());
+                len.saturating_sub(*next_version as usize)
+            }
+            Cursor::All { next_position, .. } => self
+                .all
+                .range(*next_position..)
+                .take(subscriber.max_lag + 1)
+                .count(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{append, drain};
+    use nexus_common::types::ExpectedVersion;
+
+    /// Event types received until the buffer is empty, with "|" marking `CaughtUp`
+    fn event_types(subscription: &Subscription) -> Vec<String> {
+        drain(subscription)
+            .into_iter()
+            .map(|message| match message {
+                SubscriptionMessage::Event(event) => event.event_type,
+                SubscriptionMessage::CaughtUp => "|".to_string(),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_stream_subscription_hands_over_to_live() {
+        let mut store = EventStore::new();
+        append(&mut store, "order-1", &["placed", "paid"]);
+        append(&mut store, "order-2", &["placed"]);
+
+        let settings = SubscriptionSettings::default();
+        let from_start = store
+            .subscribe_to_stream("order-1", Some(0), settings)
+            .unwrap();
+        let live_only = store
+            .subscribe_to_stream("order-1", None, settings)
+            .unwrap();
+        append(&mut store, "order-1", &["shipped"]);
+
+        assert_eq!(event_types(&from_start), ["placed", "paid", "|", "shipped"]);
+        assert_eq!(event_types(&live_only), ["|", "shipped"]);
+    }
+
+    #[test]
+    fn test_all_subscription_filters_and_resumes() {
+        let mut store = EventStore::new();
+        append(&mut store, "order-1", &["placed"]);
+        append(&mut store, "user-1", &["registered"]);
+
+        let filter = EventFilter::StreamPrefix("order-".into());
+        let subscription = store.subscribe_to_all(
+            Some(Position::START),
+            filter,
+            SubscriptionSettings::default(),
+        );
+        append(&mut store, "user-2", &["registered"]);
+        append(&mut store, "order-2", &["placed"]);
+        assert_eq!(event_types(&subscription), ["placed", "|", "placed"]);
+
+        // Resuming after the last seen position skips what was already delivered
+        let resumed = store.subscribe_to_all(
+            Some(Position::new(4, 0)),
+            EventFilter::All,
+            SubscriptionSettings::default(),
+        );
+        assert_eq!(event_types(&resumed), ["placed", "|"]);
+    }
+
+    #[test]
+    fn test_full_subscriber_catches_up_without_gaps() {
+        let mut store = EventStore::new();
+        append(&mut store, "s", &["0", "1", "2", "3", "4"]);
+
+        let settings = SubscriptionSettings {
+            capacity: 2,
+            max_lag: 100,
+        };
+        let subscription = store.subscribe_to_stream("s", Some(0), settings).unwrap();
+
+        let mut received = Vec::new();
+        for _ in 0..4 {
+            received.extend(event_types(&subscription));
+            store.notify_subscribers();
+        }
+        append(&mut store, "s", &["5"]);
+        received.extend(event_types(&subscription));
+        assert_eq!(received, ["0", "1", "2", "3", "4", "|", "5"]);
+    }
+
+    #[test]
+    fn test_slow_live_subscriber_is_dropped() {
+        let mut store = EventStore::new();
+        append(&mut store, "s", &["0", "1", "2", "3", "4"]);
+        let settings = SubscriptionSettings {
+            capacity: 1,
+            max_lag: 2,
+        };
+        // Replaying history is not limited by `max_lag`
+        let replaying = store.subscribe_to_stream("s", Some(0), settings).unwrap();
+        let live = store.subscribe_to_all(None, EventFilter::All, settings);
+
+        let mut received = Vec::new();
+        for i in 5..9 {
+            append(&mut store, "s", &[&i.to_string()]);
+            received.extend(event_types(&replaying));
+        }
+        assert_eq!(live.recv(), Ok(SubscriptionMessage::CaughtUp));
+        assert_eq!(live.recv(), Err(DropReason::TooSlow));
+
+        for _ in 0..6 {
+            store.notify_subscribers();
+            received.extend(event_types(&replaying));
+        }
+        assert_eq!(received, ["0", "1", "2", "3", "4", "5", "6", "7", "8", "|"]);
+    }
+
+    #[test]
+    fn test_deleting_stream_drops_its_subscribers() {
+        let mut store = EventStore::new();
+        append(&mut store, "s", &["x"]);
+        let subscription = store
+            .subscribe_to_stream("s", Some(0), SubscriptionSettings::default())
+            .unwrap();
+
+        store.delete_stream("s", ExpectedVersion::Any).unwrap();
+        assert_eq!(event_types(&subscription), ["x", "|"]);
+        assert_eq!(subscription.try_recv(), Err(DropReason::StreamDeleted));
+        assert!(matches!(
+            store.subscribe_to_stream("s", None, SubscriptionSettings::default()),
+            Err(NexusError::StreamDeleted(_))
+        ));
+    }
+}
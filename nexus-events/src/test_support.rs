@@ -0,0 +1,40 @@
+//! Fixtures shared by the crate's unit tests
+
+use crate::event::EventData;
+use crate::state_machine::EventCommand;
+use crate::store::EventStore;
+use crate::subscription::Subscription;
+use nexus_common::types::ExpectedVersion;
+
+/// Events with the given types and empty payloads
+pub(crate) fn events(types: &[&str]) -> Vec<EventData> {
+    types
+        .iter()
+        .map(|t| EventData::binary(*t, Vec::new()))
+        .collect()
+}
+
+/// Appends events with the given types to a stream, whatever its version
+pub(crate) fn append(store: &mut EventStore, stream_id: &str, types: &[&str]) {
+    store
+        .append_to_stream(stream_id, ExpectedVersion::Any, events(types))
+        .unwrap();
+}
+
+/// An `Append` command for events with the given types
+pub(crate) fn append_command(
+    stream_id: &str,
+    expected: ExpectedVersion,
+    types: &[&str],
+) -> EventCommand {
+    EventCommand::append(stream_id, expected, events(types))
+}
+
+/// Messages buffered in a subscription, until it is empty
+pub(crate) fn drain<M>(subscription: &Subscription<M>) -> Vec<M> {
+    let mut received = Vec::new();
+    while let Ok(Some(message)) = subscription.try_recv() {
+        received.push(message);
+    }
+    received
+}
@@ -0,0 +1,43 @@
+use axum::http::header::CONTENT_TYPE;
+use axum::routing::get;
+use axum::Router;
+use nexus_common::prometheus::{self, PrometheusMetrics};
+use std::sync::Arc;
+
+/// Router serving `GET /metrics` in the Prometheus text format.
+/// Merge it into the server's main router to expose node metrics.
+pub fn router(metrics: Arc<PrometheusMetrics>) -> Router {
+    Router::new().route(
+        "/metrics",
+        get(move || {
+            let body = metrics.render();
+            async move { ([(CONTENT_TYPE, prometheus::CONTENT_TYPE)], body) }
+        }),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::body::{to_bytes, Body};
+    use axum::http::{Request, StatusCode};
+    use nexus_common::metrics::MetricsCollector;
+    use tower::ServiceExt;
+
+    #[tokio::test]
+    async fn test_metrics_endpoint() {
+        let metrics = Arc::new(PrometheusMetrics::new());
+        metrics.observe_gauge("raft_term", 7.0);
+
+        let response = router(metrics)
+            .oneshot(Request::get("/metrics").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[CONTENT_TYPE], prometheus::CONTENT_TYPE);
+
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(&body[..], b"# TYPE raft_term gauge\nraft_term 7\n");
+    }
+}
@@ -0,0 +1,89 @@
+use nexus_common::types::CompressionCodec;
+use std::io::{self, Read, Write};
+
+/// Compresses everything written through it with the configured codec
+pub enum Compressor<W: Write> {
+    None(W),
+    Lz4(lz4_flex::frame::FrameEncoder<W>),
+    Zstd(zstd::Encoder<'static, W>),
+}
+
+impl<W: Write> Compressor<W> {
+    pub fn new(codec: CompressionCodec, inner: W) -> io::Result<Self> {
+        Ok(match codec {
+            CompressionCodec::None => Compressor::None(inner),
+            CompressionCodec::Lz4 => Compressor::Lz4(lz4_flex::frame::FrameEncoder::new(inner)),
+            CompressionCodec::Zstd => Compressor::Zstd(zstd::Encoder::new(inner, 0)?),
+        })
+    }
+
+    /// Writes any buffered data and the codec trailer, returning the inner writer
+    pub fn finish(self) -> io::Result<W> {
+        match self {
+            Compressor::None(inner) => Ok(inner),
+            Compressor::Lz4(encoder) => encoder.finish().map_err(io::Error::other),
+            Compressor::Zstd(encoder) => encoder.finish(),
+        }
+    }
+}
+
+impl<W: Write> Write for Compressor<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Compressor::None(inner) => inner.write(buf),
+            Compressor::Lz4(encoder) => encoder.write(buf),
+            Compressor::Zstd(encoder) => encoder.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Compressor::None(inner) => inner.flush(),
+            Compressor::Lz4(encoder) => encoder.flush(),
+            Compressor::Zstd(encoder) => encoder.flush(),
+        }
+    }
+}
+
+/// Wraps a reader of compressed data with the matching decoder
+pub fn decompressor<R: Read + Send + 'static>(
+    codec: CompressionCodec,
+    inner: R,
+) -> io::Result<Box<dyn Read + Send>> {
+    Ok(match codec {
+        CompressionCodec::None => Box::new(inner),
+        CompressionCodec::Lz4 => Box::new(lz4_flex::frame::FrameDecoder::new(inner)),
+        CompressionCodec::Zstd => Box::new(zstd::Decoder::new(inner)?),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip_all_codecs() {
+        let data: Vec<u8> = b"nexus ".iter().copied().cycle().take(10_000).collect();
+
+        for codec in [
+            CompressionCodec::None,
+            CompressionCodec::Lz4,
+            CompressionCodec::Zstd,
+        ] {
+            let mut compressor = Compressor::new(codec, Vec::new()).unwrap();
+            compressor.write_all(&data).unwrap();
+            let compressed = compressor.finish().unwrap();
+
+            if codec != CompressionCodec::None {
+                assert!(compressed.len() < data.len() / 10, "{:?}", codec);
+            }
+
+            let mut decoded = Vec::new();
+            decompressor(codec, io::Cursor::new(compressed))
+                .unwrap()
+                .read_to_end(&mut decoded)
+                .unwrap();
+            assert_eq!(decoded, data, "{:?}", codec);
+        }
+    }
+}
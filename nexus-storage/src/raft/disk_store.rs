@@ -0,0 +1,659 @@
+use crate::raft::kv_backend::{self, Bounds, KvBackend};
+use crate::raft::state_machine::{
+    FrozenState, KeyVersion, KvCommand, KvEntry, KvResponse, Lease, StateMachine,
+};
+use nexus_common::error::NexusError;
+use redb::{Database, ReadTransaction, ReadableTable, Table, TableDefinition};
+use std::error::Error;
+use std::io::{Read, Write};
+use std::ops::Bound;
+use std::path::Path;
+use tracing::error;
+
+const ENTRIES: TableDefinition<&str, &[u8]> = TableDefinition::new("entries"); // key -> KvEntry
+const LEASES: TableDefinition<u64, &[u8]> = TableDefinition::new("leases"); // id -> Lease
+/// (key, revision) -> `Option<KvEntry>`, `None` where the key was deleted
+const HISTORY: TableDefinition<(&str, u64), &[u8]> = TableDefinition::new("history");
+const META: TableDefinition<&str, u64> = TableDefinition::new("meta");
+
+const APPLIED_INDEX: &str = "applied_index";
+const REVISION: &str = "revision";
+const COMPACTED: &str = "compacted";
+const LEASE_CLOCK: &str = "lease_clock_ms";
+
+/// Key-value state machine stored in a redb B-tree file instead of RAM.
+/// Each command commits in one write transaction together with its log index, so after
+/// a restart the store resumes from its own durable state. Commands run through the
+/// same code as `KeyValueStore`, with the per-key history kept in its own table, and
+/// snapshots use `KeyValueStore`'s format.
+pub struct DiskKvStore {
+    db: Database,
+    revision: u64,       // Revision of the last write command
+    compacted: u64,      // Reads before this revision are rejected
+    applied_index: u64,  // Last log index committed with the data
+    lease_clock_ms: u64, // Latest leader time carried by an applied lease command
+}
+
+/// The tables of one write transaction
+struct Batch<'txn> {
+    entries: Table<'txn, &'static str, &'static [u8]>,
+    leases: Table<'txn, u64, &'static [u8]>,
+    history: Table<'txn, (&'static str, u64), &'static [u8]>,
+    revision: u64,
+    compacted: u64,
+    lease_clock_ms: u64,
+}
+
+type BatchResult<T> = Result<T, Box<dyn Error>>;
+
+impl DiskKvStore {
+    /// Opens or creates the store at `path`
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, NexusError> {
+        let db = Database::create(path).map_err(storage_err)?;
+
+        let txn = db.begin_write().map_err(storage_err)?;
+        let (revision, compacted, applied_index, lease_clock_ms) = {
+            txn.open_table(ENTRIES).map_err(storage_err)?;
+            txn.open_table(LEASES).map_err(storage_err)?;
+            txn.open_table(HISTORY).map_err(storage_err)?;
+            let meta = txn.open_table(META).map_err(storage_err)?;
+            let read = |name| -> Result<u64, NexusError> {
+                Ok(meta
+                    .get(name)
+                    .map_err(storage_err)?
+                    .map_or(0, |v| v.value()))
+            };
+            (
+                read(REVISION)?,
+                read(COMPACTED)?,
+                read(APPLIED_INDEX)?,
+                read(LEASE_CLOCK)?,
+            )
+        };
+        txn.commit().map_err(storage_err)?;
+
+        Ok(DiskKvStore {
+            db,
+            revision,
+            compacted,
+            applied_index,
+            lease_clock_ms,
+        })
+    }
+
+    /// Revision of the last applied write
+    pub fn revision(&self) -> u64 {
+        self.revision
+    }
+
+    /// Revision the history was last compacted at
+    pub fn compacted_revision(&self) -> u64 {
+        self.compacted
+    }
+
+    /// Runs `f` in a write transaction and commits it with `index` as the applied index
+    fn commit<T>(
+        &mut self,
+        index: u64,
+        f: impl FnOnce(&mut Batch) -> BatchResult<T>,
+    ) -> BatchResult<T> {
+        let txn = self.db.begin_write()?;
+        let (result, revision, compacted, lease_clock_ms) = {
+            let mut batch = Batch {
+                entries: txn.open_table(ENTRIES)?,
+                leases: txn.open_table(LEASES)?,
+                history: txn.open_table(HISTORY)?,
+                revision: self.revision,
+                compacted: self.compacted,
+                lease_clock_ms: self.lease_clock_ms,
+            };
+            let result = f(&mut batch)?;
+
+            let mut meta = txn.open_table(META)?;
+            meta.insert(REVISION, batch.revision)?;
+            meta.insert(COMPACTED, batch.compacted)?;
+            meta.insert(APPLIED_INDEX, index)?;
+            meta.insert(LEASE_CLOCK, batch.lease_clock_ms)?;
+            (
+                result,
+                batch.revision,
+                batch.compacted,
+                batch.lease_clock_ms,
+            )
+        };
+        txn.commit()?;
+
+        self.revision = revision;
+        self.compacted = compacted;
+        self.lease_clock_ms = lease_clock_ms;
+        self.applied_index = index;
+        Ok(result)
+    }
+}
+
+impl KvBackend for Batch<'_> {
+    type Error = Box<dyn Error>;
+
+    fn revision(&self) -> u64 {
+        self.revision
+    }
+
+    fn set_revision(&mut self, revision: u64) {
+        self.revision = revision;
+    }
+
+    fn compacted(&self) -> u64 {
+        self.compacted
+    }
+
+    fn set_compacted(&mut self, revision: u64) {
+        self.compacted = revision;
+    }
+
+    fn lease_clock_ms(&self) -> u64 {
+        self.lease_clock_ms
+    }
+
+    fn set_lease_clock_ms(&mut self, now_ms: u64) {
+        self.lease_clock_ms = now_ms;
+    }
+
+    fn entry(&self, key: &str) -> BatchResult<Option<KvEntry>> {
+        match self.entries.get(key)? {
+            Some(bytes) => Ok(Some(bincode::deserialize(bytes.value())?)),
+            None => Ok(None),
+        }
+    }
+
+    fn set_entry(&mut self, key: &str, entry: Option<KvEntry>) -> BatchResult<()> {
+        match entry {
+            Some(entry) => {
+                self.entries
+                    .insert(key, bincode::serialize(&entry)?.as_slice())?;
+            }
+            None => {
+                self.entries.remove(key)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn visit_entries(
+        &self,
+        bounds: Bounds,
+        visit: &mut dyn FnMut(&str, &KvEntry) -> bool,
+    ) -> BatchResult<()> {
+        for item in self.entries.range::<&str>(bounds)? {
+            let (key, bytes) = item?;
+            let entry: KvEntry = bincode::deserialize(bytes.value())?;
+            if !visit(key.value(), &entry) {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    /// Versions are keyed by revision, so a later write within a revision replaces the
+    /// earlier one
+    fn push_version(&mut self, key: &str, version: KeyVersion) -> BatchResult<()> {
+        self.history.insert(
+            (key, version.revision),
+            bincode::serialize(&version.entry)?.as_slice(),
+        )?;
+        Ok(())
+    }
+
+    fn visit_history(
+        &self,
+        bounds: Bounds,
+        visit: &mut dyn FnMut(&str, &[KeyVersion]) -> bool,
+    ) -> BatchResult<()> {
+        visit_versions(&self.history, bounds, visit)
+    }
+
+    fn drop_versions_before(&mut self, key: &str, revision: u64) -> BatchResult<()> {
+        let mut dropped = Vec::new();
+        for item in self.history.range((key, 0)..(key, revision))? {
+            dropped.push(item?.0.value().1);
+        }
+        for old in dropped {
+            self.history.remove((key, old))?;
+        }
+        Ok(())
+    }
+
+    fn lease(&self, id: u64) -> BatchResult<Option<Lease>> {
+        match self.leases.get(id)? {
+            Some(bytes) => Ok(Some(bincode::deserialize(bytes.value())?)),
+            None => Ok(None),
+        }
+    }
+
+    fn save_lease(&mut self, lease: Lease) -> BatchResult<()> {
+        self.leases
+            .insert(lease.id, bincode::serialize(&lease)?.as_slice())?;
+        Ok(())
+    }
+
+    fn remove_lease(&mut self, id: u64) -> BatchResult<()> {
+        self.leases.remove(id)?;
+        Ok(())
+    }
+
+    fn leases(&self) -> BatchResult<Vec<Lease>> {
+        let mut leases = Vec::new();
+        for item in self.leases.iter()? {
+            leases.push(bincode::deserialize(item?.1.value())?);
+        }
+        Ok(leases)
+    }
+}
+
+/// Calls `visit` with the versions of each key within the bounds, oldest first, until
+/// it returns false
+fn visit_versions(
+    history: &impl ReadableTable<(&'static str, u64), &'static [u8]>,
+    (start, end): Bounds,
+    visit: &mut dyn FnMut(&str, &[KeyVersion]) -> bool,
+) -> BatchResult<()> {
+    let start = match start {
+        Bound::Included(key) => Bound::Included((key, 0)),
+        Bound::Excluded(key) => Bound::Excluded((key, u64::MAX)),
+        Bound::Unbounded => Bound::Unbounded,
+    };
+    let end = match end {
+        Bound::Included(key) => Bound::Included((key, u64::MAX)),
+        Bound::Excluded(key) => Bound::Excluded((key, 0)),
+        Bound::Unbounded => Bound::Unbounded,
+    };
+
+    let mut current = String::new();
+    let mut versions = Vec::new();
+    for item in history.range::<(&str, u64)>((start, end))? {
+        let (key, bytes) = item?;
+        let (key, revision) = key.value();
+        if key != current {
+            if !versions.is_empty() && !visit(&current, &versions) {
+                return Ok(());
+            }
+            versions.clear();
+            current = key.to_string();
+        }
+        versions.push(KeyVersion {
+            revision,
+            entry: bincode::deserialize(bytes.value())?,
+        });
+    }
+    if !versions.is_empty() {
+        visit(&current, &versions);
+    }
+    Ok(())
+}
+
+/// Point-in-time view of a `DiskKvStore`, backed by a redb read transaction
+struct FrozenDisk {
+    txn: ReadTransaction,
+    revision: u64,
+    compacted: u64,
+    lease_clock_ms: u64,
+}
+
+impl FrozenState for FrozenDisk {
+    /// Streams the tables in `KeyValueStore`'s snapshot layout, one key at a time
+    fn write_to(&self, out: &mut dyn Write) -> Result<(), Box<dyn Error>> {
+        let mut leases = im::OrdMap::new();
+        for item in self.txn.open_table(LEASES)?.iter()? {
+            let (id, lease) = item?;
+            leases.insert(id.value(), bincode::deserialize::<Lease>(lease.value())?);
+        }
+
+        // The history map is written as its length followed by each key's versions
+        let history = self.txn.open_table(HISTORY)?;
+        let mut keys = 0u64;
+        let mut last = String::new();
+        for item in history.iter()? {
+            let (key, _) = item?;
+            let (key, _) = key.value();
+            if keys == 0 || key != last {
+                keys += 1;
+                last = key.to_string();
+            }
+        }
+
+        bincode::serialize_into(&mut *out, &self.revision)?;
+        bincode::serialize_into(&mut *out, &self.compacted)?;
+        bincode::serialize_into(&mut *out, &self.lease_clock_ms)?;
+        bincode::serialize_into(&mut *out, &leases)?;
+        bincode::serialize_into(&mut *out, &keys)?;
+        let mut written = Ok(());
+        visit_versions(
+            &history,
+            (Bound::Unbounded, Bound::Unbounded),
+            &mut |key, versions| {
+                written = bincode::serialize_into(&mut *out, &(key, versions));
+                written.is_ok()
+            },
+        )?;
+        Ok(written?)
+    }
+}
+
+impl StateMachine for DiskKvStore {
+    type Command = KvCommand;
+    type Response = KvResponse;
+
+    /// Applies a command without moving the applied index.
+    ///
+    /// # Panics
+    ///
+    /// If the command cannot be committed to disk. Raft applies entries through
+    /// `apply_entry`, which returns the error instead.
+    fn apply(&mut self, command: Self::Command) -> Self::Response {
+        self.apply_entry(self.applied_index, command)
+            .unwrap_or_else(|err| panic!("failed to apply command to disk: {err}"))
+    }
+
+    /// Fails without changing anything if the command cannot be committed; the node
+    /// then stops applying, as carrying on would leave this replica behind the others
+    fn apply_entry(
+        &mut self,
+        index: u64,
+        command: Self::Command,
+    ) -> Result<KvResponse, NexusError> {
+        self.commit(index, |batch| kv_backend::execute(batch, command))
+            .map_err(|err| {
+                NexusError::StateMachine(format!("failed to apply entry {index}: {err}"))
+            })
+    }
+
+    fn applied_index(&self) -> Option<u64> {
+        Some(self.applied_index)
+    }
+
+    fn freeze(&self) -> Result<Box<dyn FrozenState>, NexusError> {
+        let txn = self.db.begin_read().map_err(storage_err)?;
+        Ok(Box::new(FrozenDisk {
+            txn,
+            revision: self.revision,
+            compacted: self.compacted,
+            lease_clock_ms: self.lease_clock_ms,
+        }))
+    }
+
+    /// Restores without recording the snapshot's index; prefer `restore_at`
+    fn restore(&mut self, input: &mut dyn Read) -> Result<(), Box<dyn Error>> {
+        self.restore_at(0, input)
+    }
+
+    fn restore_at(&mut self, index: u64, input: &mut dyn Read) -> Result<(), Box<dyn Error>> {
+        let revision: u64 = bincode::deserialize_from(&mut *input)?;
+        let compacted: u64 = bincode::deserialize_from(&mut *input)?;
+        let lease_clock_ms: u64 = bincode::deserialize_from(&mut *input)?;
+        let leases: im::OrdMap<u64, Lease> = bincode::deserialize_from(&mut *input)?;
+        let keys: u64 = bincode::deserialize_from(&mut *input)?;
+
+        let txn = self.db.begin_write()?;
+        txn.delete_table(ENTRIES)?;
+        txn.delete_table(LEASES)?;
+        txn.delete_table(HISTORY)?;
+        {
+            let mut entries = txn.open_table(ENTRIES)?;
+            let mut history = txn.open_table(HISTORY)?;
+            for _ in 0..keys {
+                let (key, versions): (String, Vec<KeyVersion>) =
+                    bincode::deserialize_from(&mut *input)?;
+                for version in &versions {
+                    history.insert(
+                        (key.as_str(), version.revision),
+                        bincode::serialize(&version.entry)?.as_slice(),
+                    )?;
+                }
+                if let Some(entry) = versions.last().and_then(|v| v.entry.as_ref()) {
+                    entries.insert(key.as_str(), bincode::serialize(entry)?.as_slice())?;
+                }
+            }
+
+            let mut table = txn.open_table(LEASES)?;
+            for (id, lease) in &leases {
+                table.insert(*id, bincode::serialize(lease)?.as_slice())?;
+            }
+
+            let mut meta = txn.open_table(META)?;
+            meta.insert(REVISION, revision)?;
+            meta.insert(COMPACTED, compacted)?;
+            meta.insert(APPLIED_INDEX, index)?;
+            meta.insert(LEASE_CLOCK, lease_clock_ms)?;
+        }
+        txn.commit()?;
+
+        self.revision = revision;
+        self.compacted = compacted;
+        self.lease_clock_ms = lease_clock_ms;
+        self.applied_index = index;
+        Ok(())
+    }
+
+    fn get(&self, key: String) -> Option<String> {
+        let read = || -> BatchResult<Option<String>> {
+            let txn = self.db.begin_read()?;
+            let entries = txn.open_table(ENTRIES)?;
+            match entries.get(key.as_str())? {
+                Some(bytes) => Ok(Some(bincode::deserialize::<KvEntry>(bytes.value())?.value)),
+                None => Ok(None),
+            }
+        };
+        read().unwrap_or_else(|err| {
+            error!(%err, "failed to read from disk store");
+            None
+        })
+    }
+}
+
+fn storage_err(err: impl Into<redb::Error>) -> NexusError {
+    NexusError::StateMachine(format!("storage error: {}", err.into()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::raft::state_machine::{KeyValueStore, Op};
+
+    fn open(dir: &tempfile::TempDir) -> DiskKvStore {
+        DiskKvStore::open(dir.path().join("kv.redb")).unwrap()
+    }
+
+    #[test]
+    fn test_commands_match_in_memory_store() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut disk = open(&dir);
+        let mut mem = KeyValueStore::default();
+
+        let commands = vec![
+            KvCommand::Set("a".into(), "1".into()),
+            KvCommand::Set("b".into(), "2".into()),
+            KvCommand::CompareAndSwap {
+                key: "a".into(),
+                expected: crate::raft::state_machine::Expected::Version(1),
+                value: "3".into(),
+            },
+            KvCommand::LeaseGrant {
+                id: 1,
+                ttl_ms: 100,
+                now_ms: 0,
+            },
+            KvCommand::PutWithLease {
+                key: "c".into(),
+                value: "4".into(),
+                lease: 1,
+            },
+            KvCommand::Prefix {
+                prefix: "".into(),
+                cursor: None,
+                limit: 2,
+            },
+            KvCommand::ExpireLeases { now_ms: 100 },
+            KvCommand::Txn {
+                compare: vec![],
+                success: vec![Op::Delete("b".into()), Op::Get("a".into())],
+                failure: vec![],
+            },
+            KvCommand::Range {
+                start: "a".into(),
+                end: "z".into(),
+                limit: 0,
+            },
+            KvCommand::GetEntry("a".into()),
+            // Lease 2 expires at 250 but is not revoked yet; the clock has moved past it
+            KvCommand::LeaseGrant {
+                id: 2,
+                ttl_ms: 50,
+                now_ms: 200,
+            },
+            KvCommand::LeaseKeepAlive { id: 1, now_ms: 300 },
+            KvCommand::PutWithLease {
+                key: "d".into(),
+                value: "5".into(),
+                lease: 2,
+            },
+            KvCommand::LeaseRevoke(1),
+            KvCommand::DeletePrefix("a".into()),
+            KvCommand::GetAt("a".into(), 3),
+            KvCommand::GetAt("c".into(), 5),
+            KvCommand::RangeAt {
+                start: "a".into(),
+                end: "z".into(),
+                limit: 1,
+                revision: 5,
+            },
+            KvCommand::Compact(6),
+            KvCommand::GetAt("a".into(), 5),
+            KvCommand::GetAt("b".into(), 6),
+            KvCommand::Compact(100),
+        ];
+        for (i, command) in commands.into_iter().enumerate() {
+            let index = i as u64 + 1;
+            assert_eq!(
+                disk.apply_entry(index, command.clone()).unwrap(),
+                mem.apply(command),
+                "command {index}"
+            );
+        }
+        assert_eq!(disk.revision(), mem.revision());
+    }
+
+    #[test]
+    fn test_resumes_from_durable_state() {
+        let dir = tempfile::tempdir().unwrap();
+        {
+            let mut disk = open(&dir);
+            disk.apply_entry(1, KvCommand::Set("a".into(), "1".into()))
+                .unwrap();
+            disk.apply_entry(2, KvCommand::Set("b".into(), "2".into()))
+                .unwrap();
+        }
+
+        let disk = open(&dir);
+        assert_eq!(disk.applied_index(), Some(2));
+        assert_eq!(disk.revision(), 2);
+        assert_eq!(disk.get("b".into()), Some("2".into()));
+    }
+
+    #[test]
+    fn test_history_survives_restart() {
+        let dir = tempfile::tempdir().unwrap();
+        {
+            let mut disk = open(&dir);
+            disk.apply_entry(1, KvCommand::Set("a".into(), "1".into()))
+                .unwrap();
+            disk.apply_entry(2, KvCommand::Set("a".into(), "2".into()))
+                .unwrap();
+            disk.apply_entry(3, KvCommand::Delete("a".into())).unwrap();
+        }
+
+        let mut disk = open(&dir);
+        assert!(matches!(
+            disk.apply(KvCommand::GetAt("a".into(), 1)),
+            KvResponse::Entry(Some(e)) if e.value == "1"
+        ));
+        assert_eq!(
+            disk.apply(KvCommand::GetAt("a".into(), 3)),
+            KvResponse::Entry(None)
+        );
+
+        assert_eq!(disk.apply(KvCommand::Compact(2)), KvResponse::Ack);
+        let mut disk = {
+            drop(disk);
+            open(&dir)
+        };
+        assert_eq!(disk.compacted_revision(), 2);
+        assert_eq!(
+            disk.apply(KvCommand::GetAt("a".into(), 1)),
+            KvResponse::Compacted(2)
+        );
+        assert!(matches!(
+            disk.apply(KvCommand::GetAt("a".into(), 2)),
+            KvResponse::Entry(Some(e)) if e.value == "2"
+        ));
+    }
+
+    #[test]
+    fn test_snapshot_round_trips_with_in_memory_store() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut disk = open(&dir);
+        disk.apply_entry(1, KvCommand::Set("a".into(), "1".into()))
+            .unwrap();
+        disk.apply_entry(
+            2,
+            KvCommand::LeaseGrant {
+                id: 7,
+                ttl_ms: 100,
+                now_ms: 0,
+            },
+        )
+        .unwrap();
+        disk.apply_entry(
+            3,
+            KvCommand::PutWithLease {
+                key: "b".into(),
+                value: "2".into(),
+                lease: 7,
+            },
+        )
+        .unwrap();
+
+        let frozen = disk.freeze().unwrap();
+        disk.apply_entry(4, KvCommand::Set("a".into(), "changed".into()))
+            .unwrap();
+        let mut snap = Vec::new();
+        frozen.write_to(&mut snap).unwrap();
+
+        // Disk snapshot into the in-memory store
+        let mut mem = KeyValueStore::default();
+        mem.restore(&mut &snap[..]).unwrap();
+        assert_eq!(mem.get("a".into()), Some("1".into()));
+        assert_eq!(mem.revision(), 2);
+        assert_eq!(mem.lease(7).unwrap().keys.len(), 1);
+        assert_eq!(mem.history("a").count(), 1);
+
+        // History written on one side is readable on the other
+        mem.apply(KvCommand::Set("a".into(), "3".into()));
+
+        // And back onto disk
+        let mut snap = Vec::new();
+        mem.snapshot(&mut snap).unwrap();
+        let other = tempfile::tempdir().unwrap();
+        let mut restored = open(&other);
+        restored.restore_at(3, &mut &snap[..]).unwrap();
+        assert_eq!(restored.applied_index(), Some(3));
+        assert_eq!(restored.get("b".into()), Some("2".into()));
+        assert!(matches!(
+            restored.apply(KvCommand::GetAt("a".into(), 2)),
+            KvResponse::Entry(Some(e)) if e.value == "1"
+        ));
+        assert_eq!(restored.get("a".into()), Some("3".into()));
+        assert_eq!(
+            restored.apply(KvCommand::LeaseRevoke(7)),
+            KvResponse::Deleted(1)
+        );
+    }
+}
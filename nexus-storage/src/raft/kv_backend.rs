@@ -0,0 +1,521 @@
+use crate::raft::state_machine::{
+    duplicate_write, Condition, KeyVersion, KvCommand, KvEntry, KvResponse, Lease, Op, OpResult,
+};
+use std::ops::Bound;
+
+/// Key bounds of a scan
+pub(crate) type Bounds<'a> = (Bound<&'a str>, Bound<&'a str>);
+
+type Page = (Vec<(String, String)>, Option<String>); // Entries and the next page's first key
+
+/// Storage underneath a key-value state machine. `KeyValueStore` keeps it in memory
+/// and `DiskKvStore` in redb tables; what each `KvCommand` does is written once, in
+/// `execute`, on top of these operations.
+pub(crate) trait KvBackend {
+    type Error;
+
+    /// Revision of the last write command
+    fn revision(&self) -> u64;
+    fn set_revision(&mut self, revision: u64);
+    /// Revision the history was last compacted at
+    fn compacted(&self) -> u64;
+    fn set_compacted(&mut self, revision: u64);
+    /// Latest leader time carried by an applied lease command
+    fn lease_clock_ms(&self) -> u64;
+    fn set_lease_clock_ms(&mut self, now_ms: u64);
+
+    fn entry(&self, key: &str) -> Result<Option<KvEntry>, Self::Error>;
+    /// Replaces the current entry of a key; `None` removes it
+    fn set_entry(&mut self, key: &str, entry: Option<KvEntry>) -> Result<(), Self::Error>;
+    /// Calls `visit` with the current entries within the bounds in key order, until it
+    /// returns false
+    fn visit_entries(
+        &self,
+        bounds: Bounds,
+        visit: &mut dyn FnMut(&str, &KvEntry) -> bool,
+    ) -> Result<(), Self::Error>;
+
+    /// Appends a version to a key's history; writes within one revision collapse
+    fn push_version(&mut self, key: &str, version: KeyVersion) -> Result<(), Self::Error>;
+    /// Calls `visit` with the retained versions (oldest first) of each key within the
+    /// bounds in key order, until it returns false
+    fn visit_history(
+        &self,
+        bounds: Bounds,
+        visit: &mut dyn FnMut(&str, &[KeyVersion]) -> bool,
+    ) -> Result<(), Self::Error>;
+    /// Drops the versions of a key older than `revision`
+    fn drop_versions_before(&mut self, key: &str, revision: u64) -> Result<(), Self::Error>;
+
+    fn lease(&self, id: u64) -> Result<Option<Lease>, Self::Error>;
+    fn save_lease(&mut self, lease: Lease) -> Result<(), Self::Error>;
+    fn remove_lease(&mut self, id: u64) -> Result<(), Self::Error>;
+    fn leases(&self) -> Result<Vec<Lease>, Self::Error>;
+}
+
+/// Applies one command to the backend
+pub(crate) fn execute<B: KvBackend>(b: &mut B, command: KvCommand) -> Result<KvResponse, B::Error> {
+    let response = match command {
+        KvCommand::Set(k, v) => KvResponse::Written {
+            mod_revision: put(b, &k, v, None)?,
+        },
+        KvCommand::Get(k) => KvResponse::Value(b.entry(&k)?.map(|e| e.value)),
+        KvCommand::Delete(k) => {
+            let revision = b.revision() + 1;
+            if remove(b, &k, revision)?.is_some() {
+                b.set_revision(revision);
+            }
+            KvResponse::Ack
+        }
+        KvCommand::Range { start, end, limit } => {
+            let (entries, next) = scan(
+                b,
+                (Bound::Included(&start), Bound::Excluded(&end)),
+                "",
+                limit,
+            )?;
+            KvResponse::Entries { entries, next }
+        }
+        KvCommand::Prefix {
+            prefix,
+            cursor,
+            limit,
+        } => {
+            let start = cursor.as_deref().unwrap_or(&prefix).max(prefix.as_str());
+            let (entries, next) = scan(
+                b,
+                (Bound::Included(start), Bound::Unbounded),
+                &prefix,
+                limit,
+            )?;
+            KvResponse::Entries { entries, next }
+        }
+        KvCommand::DeleteRange { start, end } => KvResponse::Deleted(delete_scan(
+            b,
+            (Bound::Included(&start), Bound::Excluded(&end)),
+            "",
+        )?),
+        KvCommand::DeletePrefix(prefix) => KvResponse::Deleted(delete_scan(
+            b,
+            (Bound::Included(&prefix), Bound::Unbounded),
+            &prefix,
+        )?),
+        KvCommand::GetEntry(k) => KvResponse::Entry(b.entry(&k)?),
+        KvCommand::CompareAndSwap {
+            key,
+            expected,
+            value,
+        } => {
+            let current = b.entry(&key)?;
+            if !expected.matches(current.as_ref()) {
+                return Ok(KvResponse::ConditionFailed { current });
+            }
+            KvResponse::Written {
+                mod_revision: put(b, &key, value, None)?,
+            }
+        }
+        KvCommand::SetIfAbsent(key, value) => {
+            if let Some(current) = b.entry(&key)? {
+                return Ok(KvResponse::ConditionFailed {
+                    current: Some(current),
+                });
+            }
+            KvResponse::Written {
+                mod_revision: put(b, &key, value, None)?,
+            }
+        }
+        KvCommand::Txn {
+            compare,
+            success,
+            failure,
+        } => txn(b, compare, success, failure)?,
+        KvCommand::LeaseGrant { id, ttl_ms, now_ms } => {
+            advance_lease_clock(b, now_ms);
+            if b.lease(id)?.is_some() {
+                return Ok(KvResponse::LeaseExists(id));
+            }
+            let expires_at_ms = now_ms.saturating_add(ttl_ms);
+            b.save_lease(Lease {
+                id,
+                ttl_ms,
+                expires_at_ms,
+                keys: im::OrdSet::new(),
+            })?;
+            KvResponse::Lease { id, expires_at_ms }
+        }
+        KvCommand::LeaseKeepAlive { id, now_ms } => {
+            advance_lease_clock(b, now_ms);
+            match b.lease(id)?.filter(|lease| lease.expires_at_ms > now_ms) {
+                Some(mut lease) => {
+                    lease.expires_at_ms = now_ms.saturating_add(lease.ttl_ms);
+                    let expires_at_ms = lease.expires_at_ms;
+                    b.save_lease(lease)?;
+                    KvResponse::Lease { id, expires_at_ms }
+                }
+                None => KvResponse::LeaseNotFound(id),
+            }
+        }
+        KvCommand::LeaseRevoke(id) => match revoke(b, id)? {
+            Some(deleted) => KvResponse::Deleted(deleted),
+            None => KvResponse::LeaseNotFound(id),
+        },
+        KvCommand::PutWithLease { key, value, lease } => {
+            if !is_live(b, lease)? {
+                return Ok(KvResponse::LeaseNotFound(lease));
+            }
+            KvResponse::Written {
+                mod_revision: put(b, &key, value, Some(lease))?,
+            }
+        }
+        KvCommand::ExpireLeases { now_ms } => {
+            advance_lease_clock(b, now_ms);
+            let expired: Vec<u64> = b
+                .leases()?
+                .into_iter()
+                .filter(|lease| lease.expires_at_ms <= now_ms)
+                .map(|lease| lease.id)
+                .collect();
+            for id in &expired {
+                revoke(b, *id)?;
+            }
+            KvResponse::Expired(expired)
+        }
+        KvCommand::GetAt(key, revision) => match readable(b, revision) {
+            Ok(()) => KvResponse::Entry(entry_at(b, &key, revision)?),
+            Err(err) => err,
+        },
+        KvCommand::RangeAt {
+            start,
+            end,
+            limit,
+            revision,
+        } => match readable(b, revision) {
+            Ok(()) => {
+                let (entries, next) = scan_at(b, &start, &end, limit, revision)?;
+                KvResponse::Entries { entries, next }
+            }
+            Err(err) => err,
+        },
+        KvCommand::Compact(revision) => compact(b, revision)?,
+    };
+    Ok(response)
+}
+
+fn advance_lease_clock<B: KvBackend>(b: &mut B, now_ms: u64) {
+    b.set_lease_clock_ms(b.lease_clock_ms().max(now_ms));
+}
+
+/// Checks that `revision` can still be read
+fn readable<B: KvBackend>(b: &B, revision: u64) -> Result<(), KvResponse> {
+    if revision < b.compacted() {
+        Err(KvResponse::Compacted(b.compacted()))
+    } else if revision > b.revision() {
+        Err(KvResponse::FutureRevision(b.revision()))
+    } else {
+        Ok(())
+    }
+}
+
+/// The entry of a key as of `revision`, given its versions
+fn version_at(versions: &[KeyVersion], revision: u64) -> Option<&KvEntry> {
+    versions
+        .iter()
+        .rev()
+        .find(|v| v.revision <= revision)
+        .and_then(|v| v.entry.as_ref())
+}
+
+/// The entry a key had as of `revision`
+fn entry_at<B: KvBackend>(b: &B, key: &str, revision: u64) -> Result<Option<KvEntry>, B::Error> {
+    let mut found = None;
+    b.visit_history(
+        (Bound::Included(key), Bound::Included(key)),
+        &mut |_, versions| {
+            found = version_at(versions, revision).cloned();
+            false
+        },
+    )?;
+    Ok(found)
+}
+
+/// Like `scan` over `[start, end)`, but as of `revision`
+fn scan_at<B: KvBackend>(
+    b: &B,
+    start: &str,
+    end: &str,
+    limit: usize,
+    revision: u64,
+) -> Result<Page, B::Error> {
+    if start >= end {
+        return Ok((Vec::new(), None));
+    }
+
+    let limit = if limit == 0 { usize::MAX } else { limit };
+    let mut entries = Vec::new();
+    let mut next = None;
+    b.visit_history(
+        (Bound::Included(start), Bound::Excluded(end)),
+        &mut |key, versions| {
+            let Some(entry) = version_at(versions, revision) else {
+                return true;
+            };
+            if entries.len() == limit {
+                next = Some(key.to_string());
+                return false;
+            }
+            entries.push((key.to_string(), entry.value.clone()));
+            true
+        },
+    )?;
+    Ok((entries, next))
+}
+
+/// Drops every version superseded at or before `revision`, keeping the one that was
+/// current at `revision` unless it is a deletion
+fn compact<B: KvBackend>(b: &mut B, revision: u64) -> Result<KvResponse, B::Error> {
+    if let Err(err) = readable(b, revision) {
+        return Ok(err);
+    }
+
+    let mut cuts = Vec::new(); // (key, first revision kept)
+    b.visit_history(
+        (Bound::Unbounded, Bound::Unbounded),
+        &mut |key, versions| {
+            if let Some(current) = versions.iter().rposition(|v| v.revision <= revision) {
+                let version = &versions[current];
+                match &version.entry {
+                    Some(_) if current > 0 => cuts.push((key.to_string(), version.revision)),
+                    Some(_) => {}
+                    None => cuts.push((key.to_string(), version.revision + 1)),
+                }
+            }
+            true
+        },
+    )?;
+    for (key, cut) in cuts {
+        b.drop_versions_before(&key, cut)?;
+    }
+
+    b.set_compacted(revision);
+    Ok(KvResponse::Ack)
+}
+
+/// Writes a key at a new revision and returns that revision
+fn put<B: KvBackend>(
+    b: &mut B,
+    key: &str,
+    value: String,
+    lease: Option<u64>,
+) -> Result<u64, B::Error> {
+    let revision = b.revision() + 1;
+    b.set_revision(revision);
+    write(b, key, value, revision, lease)?;
+    Ok(revision)
+}
+
+/// Writes a key as part of the given revision, moving it to `lease`
+fn write<B: KvBackend>(
+    b: &mut B,
+    key: &str,
+    value: String,
+    revision: u64,
+    lease: Option<u64>,
+) -> Result<(), B::Error> {
+    let current = b.entry(key)?;
+    let previous = current.as_ref().and_then(|e| e.lease);
+    if previous != lease {
+        move_key(b, key, previous, lease)?;
+    }
+
+    let entry = match current {
+        Some(mut entry) => {
+            entry.value = value;
+            entry.mod_revision = revision;
+            entry.version += 1;
+            entry.lease = lease;
+            entry
+        }
+        None => KvEntry {
+            value,
+            create_revision: revision,
+            mod_revision: revision,
+            version: 1,
+            lease,
+        },
+    };
+    b.set_entry(key, Some(entry.clone()))?;
+    b.push_version(
+        key,
+        KeyVersion {
+            revision,
+            entry: Some(entry),
+        },
+    )
+}
+
+/// Detaches a key from its old lease and attaches it to the new one
+fn move_key<B: KvBackend>(
+    b: &mut B,
+    key: &str,
+    from: Option<u64>,
+    to: Option<u64>,
+) -> Result<(), B::Error> {
+    if let Some(mut lease) = from.map(|id| b.lease(id)).transpose()?.flatten() {
+        lease.keys.remove(key);
+        b.save_lease(lease)?;
+    }
+    if let Some(mut lease) = to.map(|id| b.lease(id)).transpose()?.flatten() {
+        lease.keys.insert(key.to_string());
+        b.save_lease(lease)?;
+    }
+    Ok(())
+}
+
+/// Removes a key as part of the given revision, detaching it from its lease.
+/// Callers bump the revision.
+fn remove<B: KvBackend>(b: &mut B, key: &str, revision: u64) -> Result<Option<KvEntry>, B::Error> {
+    let Some(entry) = b.entry(key)? else {
+        return Ok(None);
+    };
+    if entry.lease.is_some() {
+        move_key(b, key, entry.lease, None)?;
+    }
+    b.set_entry(key, None)?;
+    b.push_version(
+        key,
+        KeyVersion {
+            revision,
+            entry: None,
+        },
+    )?;
+    Ok(Some(entry))
+}
+
+/// Drops a lease and deletes its keys in one revision; returns the number deleted
+fn revoke<B: KvBackend>(b: &mut B, id: u64) -> Result<Option<u64>, B::Error> {
+    let Some(lease) = b.lease(id)? else {
+        return Ok(None);
+    };
+    b.remove_lease(id)?;
+    if lease.keys.is_empty() {
+        return Ok(Some(0));
+    }
+
+    let revision = b.revision() + 1;
+    b.set_revision(revision);
+    for key in &lease.keys {
+        remove(b, key, revision)?;
+    }
+    Ok(Some(lease.keys.len() as u64))
+}
+
+/// Whether a lease can have keys attached: it exists and has not expired as of the
+/// lease clock
+fn is_live<B: KvBackend>(b: &B, id: u64) -> Result<bool, B::Error> {
+    Ok(b.lease(id)?
+        .is_some_and(|lease| lease.expires_at_ms > b.lease_clock_ms()))
+}
+
+/// Runs a `Txn`: every write lands in a single new revision
+fn txn<B: KvBackend>(
+    b: &mut B,
+    compare: Vec<Condition>,
+    success: Vec<Op>,
+    failure: Vec<Op>,
+) -> Result<KvResponse, B::Error> {
+    if let Some(key) = duplicate_write(&success).or(duplicate_write(&failure)) {
+        return Ok(KvResponse::DuplicateKey(key.to_string()));
+    }
+
+    let mut succeeded = true;
+    for condition in &compare {
+        if !condition.holds(b.entry(&condition.key)?.as_ref()) {
+            succeeded = false;
+            break;
+        }
+    }
+    let ops = if succeeded { success } else { failure };
+
+    for op in &ops {
+        if let Op::PutWithLease { lease, .. } = op {
+            if !is_live(b, *lease)? {
+                return Ok(KvResponse::LeaseNotFound(*lease));
+            }
+        }
+    }
+
+    let revision = b.revision() + 1;
+    let mut wrote = false;
+    let mut results = Vec::with_capacity(ops.len());
+    for op in ops {
+        let result = match op {
+            Op::Put(key, value) => {
+                write(b, &key, value, revision, None)?;
+                wrote = true;
+                OpResult::Put {
+                    mod_revision: revision,
+                }
+            }
+            Op::PutWithLease { key, value, lease } => {
+                write(b, &key, value, revision, Some(lease))?;
+                wrote = true;
+                OpResult::Put {
+                    mod_revision: revision,
+                }
+            }
+            Op::Get(key) => OpResult::Get(b.entry(&key)?),
+            Op::Delete(key) => {
+                let deleted = remove(b, &key, revision)?.is_some();
+                wrote |= deleted;
+                OpResult::Delete { deleted }
+            }
+        };
+        results.push(result);
+    }
+
+    if wrote {
+        b.set_revision(revision);
+    }
+    Ok(KvResponse::Txn { succeeded, results })
+}
+
+/// Returns up to `limit` entries (0 = unlimited) within the bounds that start with
+/// `prefix`, plus the key the next page starts at
+fn scan<B: KvBackend>(b: &B, bounds: Bounds, prefix: &str, limit: usize) -> Result<Page, B::Error> {
+    if let (Bound::Included(start), Bound::Excluded(end)) = bounds {
+        if start >= end {
+            return Ok((Vec::new(), None));
+        }
+    }
+
+    let limit = if limit == 0 { usize::MAX } else { limit };
+    let mut entries = Vec::new();
+    let mut next = None;
+    b.visit_entries(bounds, &mut |key, entry| {
+        if !key.starts_with(prefix) {
+            return false;
+        }
+        if entries.len() == limit {
+            next = Some(key.to_string());
+            return false;
+        }
+        entries.push((key.to_string(), entry.value.clone()));
+        true
+    })?;
+    Ok((entries, next))
+}
+
+/// Removes every key within the bounds that starts with `prefix`
+fn delete_scan<B: KvBackend>(b: &mut B, bounds: Bounds, prefix: &str) -> Result<u64, B::Error> {
+    let (entries, _) = scan(b, bounds, prefix, 0)?;
+    if entries.is_empty() {
+        return Ok(0);
+    }
+
+    let revision = b.revision() + 1; // One revision for the whole delete
+    b.set_revision(revision);
+    for (k, _) in &entries {
+        remove(b, k, revision)?;
+    }
+    Ok(entries.len() as u64)
+}
@@ -3,10 +3,18 @@ use serde::{Deserialize, Serialize};
 /// A single log entry in the Raft log
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct LogEntry {
-    pub term: u64,                // Term number when entry was received by leader
-    pub index: u64,               // Index of the log entry in the log
-    pub entry_type: LogEntryType, // Type of entry (Command/Config/Noop)
-    pub data: Vec<u8>,            // Payload (usually a command)
+    pub term: u64,                 // Term number when entry was received by leader
+    pub index: u64,                // Index of the log entry in the log
+    pub entry_type: LogEntryType,  // Type of entry (Command/Config/Noop)
+    pub data: Vec<u8>,             // Payload (usually a command)
+    pub client: Option<ClientTag>, // Client request the command came from, if any
+}
+
+/// Identifies the client request behind a command entry so retries apply only once
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ClientTag {
+    pub client_id: String,
+    pub sequence: u64,
 }
 
 /// Type of log entry — determines how state machine interprets the entry
@@ -18,11 +26,13 @@ pub enum LogEntryType {
 }
 
 /// A complete log for one Raft node
-#[derive(Debug)]
+#[derive(Debug, Default)]
 pub struct RaftLog {
     pub entries: Vec<LogEntry>, // Ordered log entries
     pub commit_index: u64,      // Index of last committed entry
     pub last_applied: u64,      // Index of last entry applied to state machine
+    pub snapshot_index: u64,    // Last index covered by the latest snapshot
+    pub snapshot_term: u64,     // Term of snapshot_index
 }
 
 impl RaftLog {
@@ -32,6 +42,8 @@ impl RaftLog {
             entries: Vec::new(),
             commit_index: 0,
             last_applied: 0,
+            snapshot_index: 0,
+            snapshot_term: 0,
         }
     }
 
@@ -45,14 +57,43 @@ impl RaftLog {
         self.entries.iter().find(|e| e.index == index)
     }
 
-    /// Returns the last log index, or 0 if the log is empty
+    /// Returns the last log index, or the snapshot index if the log is empty
     pub fn last_index(&self) -> u64 {
-        self.entries.last().map(|e| e.index).unwrap_or(0)
+        self.entries
+            .last()
+            .map(|e| e.index)
+            .unwrap_or(self.snapshot_index)
     }
 
-    /// Returns the term of the last entry, or 0 if empty
+    /// Returns the term of the last entry, or the snapshot term if empty
     pub fn last_term(&self) -> u64 {
-        self.entries.last().map(|e| e.term).unwrap_or(0)
+        self.entries
+            .last()
+            .map(|e| e.term)
+            .unwrap_or(self.snapshot_term)
+    }
+
+    /// Returns the term of the entry at `index`, including the snapshot boundary
+    pub fn term_at(&self, index: u64) -> Option<u64> {
+        if index == self.snapshot_index {
+            return Some(self.snapshot_term);
+        }
+        self.get(index).map(|e| e.term)
+    }
+
+    /// Replaces the log prefix up through `index` with a snapshot. Entries after it are
+    /// kept only if the log agrees with the snapshot at `index`.
+    pub fn install_snapshot(&mut self, index: u64, term: u64) {
+        if self.term_at(index) == Some(term) {
+            self.entries.retain(|e| e.index > index);
+        } else {
+            self.entries.clear();
+        }
+
+        self.snapshot_index = index;
+        self.snapshot_term = term;
+        self.commit_index = self.commit_index.max(index);
+        self.last_applied = self.last_applied.max(index);
     }
 }
 
@@ -102,6 +143,7 @@ impl RaftNode {
             index: self.log.last_index() + 1,
             entry_type: LogEntryType::Noop,
             data: vec![],
+            client: None,
         };
         self.log.append(entry);
     }
@@ -126,6 +168,7 @@ mod tests {
             index: 1,
             entry_type: LogEntryType::Command,
             data: vec![1, 2, 3],
+            client: None,
         });
 
         assert_eq!(log.last_index(), 1);
@@ -133,6 +176,32 @@ mod tests {
         assert_eq!(log.get(1).unwrap().data, vec![1, 2, 3]);
     }
 
+    #[test]
+    fn test_install_snapshot_keeps_matching_suffix() {
+        let mut log = RaftLog::new();
+        for index in 1..=5 {
+            log.append(LogEntry {
+                term: 1,
+                index,
+                entry_type: LogEntryType::Command,
+                data: vec![],
+                client: None,
+            });
+        }
+
+        log.install_snapshot(3, 1);
+        assert_eq!(log.entries.len(), 2);
+        assert_eq!(log.last_index(), 5);
+        assert_eq!(log.term_at(3), Some(1));
+
+        // Conflicting snapshot discards everything
+        log.install_snapshot(4, 2);
+        assert!(log.entries.is_empty());
+        assert_eq!(log.last_index(), 4);
+        assert_eq!(log.last_term(), 2);
+        assert_eq!(log.last_applied, 4);
+    }
+
     #[test]
     fn test_raft_node_roles() {
         let mut node = RaftNode::new("node-1".to_string());
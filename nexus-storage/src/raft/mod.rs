@@ -1,6 +1,11 @@
 // Basic Raft log data structure & Raft node behavior
+pub mod codec;
+pub mod disk_store;
+mod kv_backend;
 pub mod log;
 pub mod node;
 pub mod rpc;
+pub mod segment;
 pub mod snapshot;
 pub mod state_machine;
+pub mod watch;
@@ -1,9 +1,26 @@
-use super::rpc::{AppendEntriesRequest, AppendEntriesResponse};
-use crate::raft::log::{LogEntry, LogEntryType, RaftLog};
-use crate::raft::state_machine::{KvCommand, KvResponse, StateMachine};
-use nexus_common::types::{NodeId, Term};
-use std::collections::{HashMap, HashSet};
+use super::rpc::{
+    AppendEntriesRequest, AppendEntriesResponse, InstallSnapshotRequest, InstallSnapshotResponse,
+    TimeoutNowRequest,
+};
+use crate::raft::log::{ClientTag, LogEntry, LogEntryType, RaftLog};
+use crate::raft::segment::LogSegments;
+use crate::raft::snapshot::{
+    ClientSessions, Membership, SnapshotChunks, SnapshotMeta, SnapshotReader, SnapshotSink,
+    SnapshotStorage,
+};
+use crate::raft::state_machine::{FrozenState, KvCommand, KvResponse, StateMachine};
+use nexus_common::error::NexusError;
+use nexus_common::metrics::{MetricsCollector, NoopMetrics};
+use nexus_common::types::{ClusterConfig, CompressionCodec, NodeId, Term};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
 use std::time::{Duration, Instant};
+use tracing::{debug, error, info, info_span, trace, warn, Span};
 
 /// Role of the node in the cluster
 #[derive(Debug, Clone, PartialEq)]
@@ -13,8 +30,79 @@ pub enum NodeRole {
     Leader,
 }
 
-/// A Raft node: controls its own state and participates in consensus
-pub struct RaftNode {
+impl NodeRole {
+    /// Numeric encoding used when reporting the role as a gauge
+    pub fn as_gauge(&self) -> f64 {
+        match self {
+            NodeRole::Follower => 0.0,
+            NodeRole::Candidate => 1.0,
+            NodeRole::Leader => 2.0,
+        }
+    }
+}
+
+/// A snapshot being received from the leader through InstallSnapshot chunks
+pub struct IncomingSnapshot {
+    pub meta: SnapshotMeta,
+    pub sink: Box<dyn SnapshotSink>,
+    pub offset: u64, // Bytes received so far
+}
+
+/// Counts bytes passing through to the wrapped writer
+struct CountingWriter<W: Write> {
+    inner: W,
+    written: u64,
+}
+
+impl<W: Write> Write for CountingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.written += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// A snapshot being written on a background thread
+pub struct PendingSnapshot {
+    pub meta: SnapshotMeta, // Exact log position the snapshot captures
+    pub started: Instant,
+    pub handle: JoinHandle<Result<u64, NexusError>>, // Yields the snapshot size in bytes
+}
+
+/// Serializes a frozen state into a snapshot sink, returning the bytes written
+fn write_snapshot(
+    frozen: Box<dyn FrozenState>,
+    sink: Box<dyn SnapshotSink>,
+) -> Result<u64, NexusError> {
+    let mut out = CountingWriter {
+        inner: sink,
+        written: 0,
+    };
+    frozen
+        .write_to(&mut out)
+        .map_err(|err| NexusError::StateMachine(err.to_string()))?;
+    let size = out.written;
+    out.inner.finish()?;
+    Ok(size)
+}
+
+/// Records `sequence` as applied for `client_id` unless it already was
+fn record_sequence(sessions: &mut ClientSessions, client_id: &str, sequence: u64) -> bool {
+    let last = sessions.entry(client_id.to_string()).or_insert(0);
+    if sequence <= *last {
+        return false;
+    }
+    *last = sequence;
+    true
+}
+
+/// A Raft node: controls its own state and participates in consensus. Log entries carry
+/// commands of type `C`, which the state machine answers with `R`.
+pub struct RaftNode<C = KvCommand, R = KvResponse> {
     pub id: NodeId,
     pub current_term: Term,
     pub voted_for: Option<NodeId>,
@@ -22,6 +110,9 @@ pub struct RaftNode {
     pub commit_index: u64,
     pub log: RaftLog,
     pub peers: Vec<NodeId>,
+    pub learners: Vec<NodeId>,    // Non-voting members receiving the log
+    pub sessions: ClientSessions, // Last applied request sequence per client
+    pub configs: BTreeMap<u64, Membership>, // Log index -> membership taking effect there
 
     pub election_timeout: Duration,
     pub last_heartbeat: Instant,
@@ -30,13 +121,35 @@ pub struct RaftNode {
     pub next_index: HashMap<NodeId, u64>, // For each peer: next entry to send
     pub match_index: HashMap<NodeId, u64>, // For each peer: last index known replicated
 
-    pub state_machine:
-        Box<dyn StateMachine<Command = KvCommand, Response = KvResponse> + Send + Sync>,
+    pub priorities: HashMap<NodeId, u32>, // Election priority of every known node (incl. self)
+    pub transfer_target: Option<NodeId>,  // Peer leadership is being handed to, if any
+    pub transfer_started: Option<Instant>, // When the pending transfer began
+
+    pub state_machine: Box<dyn StateMachine<Command = C, Response = R> + Send + Sync>,
+
+    pub metrics: Arc<dyn MetricsCollector>, // Where node and log metrics are reported
+
+    pub next_request_id: u64, // Id given to the next proposal's tracing span
+    pub proposals: HashMap<u64, Span>, // Log index -> span following that proposal to apply
+    pub proposed_at: BTreeMap<u64, Instant>, // Log index -> when it was appended, until committed
+
+    pub incoming_snapshot: Option<IncomingSnapshot>, // Snapshot being installed from the leader
+    pub snapshot_in_progress: Option<PendingSnapshot>, // Local snapshot being written
+
+    pub compression: CompressionCodec, // Codec for log segments written by this node
+    pub segments: Option<LogSegments>, // Where the log is sealed to disk, if anywhere
+    pub sealed_index: u64,             // Last log index written to a segment
 }
 
-impl RaftNode {
+impl<C, R> RaftNode<C, R>
+where
+    C: Serialize + DeserializeOwned + Send + Sync,
+    R: Send + Sync,
+{
     /// Called periodically by the leader to send heartbeats (empty AppendEntries)
     pub fn send_heartbeats(&self) {
+        let _span = self.span().entered();
+
         for peer in &self.peers {
             let next_idx = *self.next_index.get(peer).unwrap_or(&1);
 
@@ -59,7 +172,7 @@ impl RaftNode {
             };
 
             // Normally this would be a network send
-            println!("Sending heartbeat to {}: {:?}", peer, request);
+            trace!(%peer, ?request, "sending heartbeat");
         }
     }
 
@@ -69,6 +182,8 @@ impl RaftNode {
         from: NodeId,
         response: AppendEntriesResponse,
     ) {
+        let _span = self.span().entered();
+
         if response.term > self.current_term {
             self.become_follower(response.term);
             return;
@@ -79,6 +194,11 @@ impl RaftNode {
             self.match_index.insert(from.clone(), sent_idx - 1);
             self.next_index.insert(from.clone(), sent_idx);
             self.update_commit_index();
+
+            if let Some((target, request)) = self.maybe_transfer_leadership() {
+                // Normally this would be a network send
+                info!(%target, ?request, "transferring leadership to preferred peer");
+            }
         } else {
             // Follower rejected: decrement next_index and retry later
             let next = self.next_index.get(&from).copied().unwrap_or(1);
@@ -92,17 +212,32 @@ impl RaftNode {
         match_indexes.push(self.log.last_index()); // include leader's own index
         match_indexes.sort_by(|a, b| b.cmp(a)); // descending
 
-        let majority = (self.peers.len() + 1) / 2;
+        let majority = self.peers.len().div_ceil(2);
         let new_commit = match_indexes[majority];
 
         if new_commit > self.commit_index {
             if let Some(entry) = self.log.get(new_commit) {
                 if entry.term == self.current_term {
+                    let old_commit = self.commit_index;
                     self.commit_index = new_commit;
-                    println!(
-                        "[{}] Commit index advanced to {}",
-                        self.id, self.commit_index
-                    );
+                    debug!(commit_index = new_commit, "commit index advanced");
+
+                    for index in (old_commit + 1)..=new_commit {
+                        if let Some(span) = self.proposals.get(&index) {
+                            span.in_scope(|| debug!(index, "proposal committed"));
+                        }
+                    }
+
+                    // Append latency runs from the proposal to its commit by a quorum
+                    let pending = self.proposed_at.split_off(&(new_commit + 1));
+                    for appended in std::mem::replace(&mut self.proposed_at, pending).values() {
+                        self.metrics.observe_histogram(
+                            "raft_append_latency_seconds",
+                            &[],
+                            appended.elapsed().as_secs_f64(),
+                        );
+                    }
+                    self.report_metrics();
                 }
             }
         }
@@ -110,41 +245,101 @@ impl RaftNode {
 
     /// Called by the leader to append a new client command (application-level payload)
     pub fn append_entry(&mut self, data: Vec<u8>) -> u64 {
+        self.append_new(LogEntryType::Command, data, None)
+    }
+
+    /// Called by the leader to append a command sent by a client as request `sequence`.
+    /// A retry of an already applied request is skipped when the entry is applied.
+    pub fn append_client_entry(&mut self, data: Vec<u8>, client_id: &str, sequence: u64) -> u64 {
+        let client = ClientTag {
+            client_id: client_id.to_string(),
+            sequence,
+        };
+        self.append_new(LogEntryType::Command, data, Some(client))
+    }
+
+    /// Called by the leader to change the cluster membership. The new configuration
+    /// takes effect as soon as it is appended.
+    pub fn append_configuration(&mut self, membership: &Membership) -> u64 {
+        let data = bincode::serialize(membership).expect("membership serializes");
+        self.append_new(LogEntryType::Configuration, data, None)
+    }
+
+    fn append_new(
+        &mut self,
+        entry_type: LogEntryType,
+        data: Vec<u8>,
+        client: Option<ClientTag>,
+    ) -> u64 {
+        let _span = self.span().entered();
         let index = self.log.last_index() + 1;
 
+        let request_id = self.next_request_id;
+        self.next_request_id += 1;
+        let proposal = info_span!("proposal", request_id, index);
+
         let entry = LogEntry {
             term: self.current_term,
             index,
-            entry_type: LogEntryType::Command,
+            entry_type,
             data,
+            client,
         };
 
+        self.track_configuration(&entry);
         self.log.append(entry);
 
         // Update self match/next index
         self.match_index.insert(self.id.clone(), index);
         self.next_index.insert(self.id.clone(), index + 1);
 
-        println!("[{}] Appended new command at index {}", self.id, index);
+        proposal.in_scope(|| debug!(index, "appended new entry"));
+        self.proposals.insert(index, proposal);
+        self.proposed_at.insert(index, Instant::now());
+        self.report_metrics();
         index
     }
 
-    /// Applies all entries between last_applied..=commit_index to the state machine
+    /// Applies all entries between last_applied..=commit_index to the state machine.
+    /// Entries a durable state machine already holds are skipped. If the state machine
+    /// fails to apply an entry, applying stops before it and the error is returned; the
+    /// node should then be shut down rather than carry on with a diverged replica.
     pub fn apply_committed_entries(
         &mut self,
-        sm: &mut (dyn StateMachine<Command = KvCommand, Response = KvResponse> + Send + Sync),
-    ) {
+        sm: &mut (dyn StateMachine<Command = C, Response = R> + Send + Sync),
+    ) -> Result<(), NexusError> {
+        let _span = self.span().entered();
+        let durable = sm.applied_index().unwrap_or(0);
+
         while self.log.last_applied < self.commit_index {
             let next = self.log.last_applied + 1;
 
             if let Some(entry) = self.log.get(next) {
-                if entry.entry_type == LogEntryType::Command {
-                    if let Ok(cmd) = bincode::deserialize::<KvCommand>(&entry.data) {
-                        sm.apply(cmd);
-                    } else {
-                        eprintln!("[{}] Failed to deserialize entry at {}", self.id, next);
+                let proposal = self.proposals.remove(&next).unwrap_or_else(Span::none);
+                let _proposal = proposal.enter();
+
+                // Sessions advance even for entries already durable, so they match the
+                // state machine after a restart
+                let fresh = entry.client.as_ref().is_none_or(|client| {
+                    record_sequence(&mut self.sessions, &client.client_id, client.sequence)
+                });
+
+                if entry.entry_type == LogEntryType::Command && !fresh {
+                    debug!(index = next, "skipped duplicate client request");
+                } else if entry.entry_type == LogEntryType::Command && next <= durable {
+                    trace!(index = next, "entry already durable in state machine");
+                } else if entry.entry_type == LogEntryType::Command {
+                    match bincode::deserialize::<C>(&entry.data) {
+                        Ok(cmd) => {
+                            if let Err(err) = sm.apply_entry(next, cmd) {
+                                error!(index = next, %err, "failed to apply entry");
+                                self.report_metrics();
+                                return Err(err);
+                            }
+                        }
+                        Err(err) => error!(index = next, %err, "failed to deserialize entry"),
                     }
-                    println!("[{}] Applied log[{}] to state machine", self.id, next);
+                    debug!(index = next, "applied entry to state machine");
                 }
 
                 self.log.last_applied = next;
@@ -152,12 +347,18 @@ impl RaftNode {
                 break;
             }
         }
+
+        self.report_metrics();
+        Ok(())
     }
 
     /// Handles AppendEntries RPC as a follower
     pub fn handle_append_entries(&mut self, req: AppendEntriesRequest) -> AppendEntriesResponse {
+        let _span = self.span().entered();
+
         // 1. Reject if term is older
         if req.term < self.current_term {
+            debug!(leader = %req.leader_id, term = req.term, "rejected AppendEntries from stale term");
             return AppendEntriesResponse {
                 term: self.current_term,
                 success: false,
@@ -175,12 +376,23 @@ impl RaftNode {
         if req.prev_log_index > 0 {
             if let Some(entry) = self.log.get(req.prev_log_index) {
                 if entry.term != req.prev_log_term {
+                    debug!(
+                        prev_log_index = req.prev_log_index,
+                        prev_log_term = req.prev_log_term,
+                        term = entry.term,
+                        "rejected AppendEntries: previous entry term mismatch"
+                    );
                     return AppendEntriesResponse {
                         term: self.current_term,
                         success: false,
                     };
                 }
             } else {
+                debug!(
+                    prev_log_index = req.prev_log_index,
+                    last_index = self.log.last_index(),
+                    "rejected AppendEntries: previous entry missing"
+                );
                 return AppendEntriesResponse {
                     term: self.current_term,
                     success: false,
@@ -192,11 +404,22 @@ impl RaftNode {
         for new_entry in req.entries {
             if let Some(existing) = self.log.get(new_entry.index) {
                 if existing.term != new_entry.term {
-                    // Conflict: truncate and replace
+                    // Conflict: truncate and replace; the next sealed segment supersedes
+                    // the truncated entries on disk
+                    warn!(
+                        index = new_entry.index,
+                        existing_term = existing.term,
+                        new_term = new_entry.term,
+                        "conflicting entry, truncating log"
+                    );
                     self.log.entries.retain(|e| e.index < new_entry.index);
+                    self.sealed_index = self.sealed_index.min(new_entry.index - 1);
+                    self.discard_configs_from(new_entry.index);
+                    self.track_configuration(&new_entry);
                     self.log.append(new_entry);
                 }
             } else {
+                self.track_configuration(&new_entry);
                 self.log.append(new_entry);
             }
         }
@@ -212,8 +435,13 @@ impl RaftNode {
         }
     }
 
-    /// Create a new Raft node
-    pub fn new(id: NodeId, peers: Vec<NodeId>, election_timeout: Duration) -> Self {
+    /// Create a Raft node driving the given state machine
+    pub fn with_state_machine(
+        id: NodeId,
+        peers: Vec<NodeId>,
+        election_timeout: Duration,
+        state_machine: Box<dyn StateMachine<Command = C, Response = R> + Send + Sync>,
+    ) -> Self {
         Self {
             id,
             current_term: 0,
@@ -221,84 +449,655 @@ impl RaftNode {
             role: NodeRole::Follower,
             log: RaftLog::new(),
             peers,
+            learners: Vec::new(),
+            sessions: ClientSessions::new(),
+            configs: BTreeMap::new(),
             commit_index: 0,
             election_timeout,
             last_heartbeat: Instant::now(),
             votes_received: HashSet::new(),
             next_index: HashMap::new(),
             match_index: HashMap::new(),
-            state_machine: Box::new(crate::raft::state_machine::KeyValueStore::default()),
+            priorities: HashMap::new(),
+            transfer_target: None,
+            transfer_started: None,
+            state_machine,
+            metrics: Arc::new(NoopMetrics),
+            next_request_id: 1,
+            proposals: HashMap::new(),
+            proposed_at: BTreeMap::new(),
+            incoming_snapshot: None,
+            snapshot_in_progress: None,
+            compression: CompressionCodec::None,
+            segments: None,
+            sealed_index: 0,
+        }
+    }
+
+    /// Create a Raft node driving the given state machine, taking peers, priorities,
+    /// election timeout and compression from the cluster configuration
+    pub fn with_config(
+        id: NodeId,
+        config: &ClusterConfig,
+        state_machine: Box<dyn StateMachine<Command = C, Response = R> + Send + Sync>,
+    ) -> Self {
+        let peers = config
+            .nodes
+            .iter()
+            .filter(|n| n.node_id != id)
+            .map(|n| n.node_id.clone())
+            .collect();
+        let election_timeout = Duration::from_millis(config.election_timeout_ms);
+
+        let mut node = Self::with_state_machine(id, peers, election_timeout, state_machine);
+        node.compression = config.compression;
+        node.priorities = config
+            .nodes
+            .iter()
+            .map(|n| (n.node_id.clone(), n.priority))
+            .collect();
+        node
+    }
+
+    /// Span carrying this node's id, term and role; entered by every operation
+    pub fn span(&self) -> Span {
+        info_span!(
+            "raft",
+            node_id = %self.id,
+            term = self.current_term,
+            role = ?self.role
+        )
+    }
+
+    /// Seal the log into segment files in `dir`, compressed with `self.compression`
+    pub fn with_log_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.segments = Some(LogSegments::new(dir).with_codec(self.compression));
+        self
+    }
+
+    /// Writes log entries appended since the last call to a new segment file.
+    /// Returns its path, or None if there was nothing new or no log directory.
+    pub fn seal_log(&mut self) -> Result<Option<PathBuf>, NexusError> {
+        let Some(segments) = &self.segments else {
+            return Ok(None);
+        };
+        let unsealed: Vec<LogEntry> = self
+            .log
+            .entries
+            .iter()
+            .filter(|e| e.index > self.sealed_index)
+            .cloned()
+            .collect();
+        if unsealed.is_empty() {
+            return Ok(None);
+        }
+
+        let path = segments.seal(&unsealed)?;
+        self.sealed_index = self.log.last_index();
+        debug!(parent: &self.span(), path = %path.display(), "sealed log segment");
+        Ok(Some(path))
+    }
+
+    /// Loads the sealed log after a restart. Entries covered by the snapshot are skipped.
+    pub fn load_log(&mut self) -> Result<usize, NexusError> {
+        let Some(segments) = &self.segments else {
+            return Ok(0);
+        };
+        let snapshot_index = self.log.snapshot_index;
+        let entries: Vec<LogEntry> = segments
+            .load()?
+            .into_iter()
+            .filter(|e| e.index > snapshot_index)
+            .collect();
+
+        let loaded = entries.len();
+        for entry in &entries {
+            self.track_configuration(entry);
+        }
+        self.log.entries = entries;
+        self.sealed_index = self.log.last_index();
+        Ok(loaded)
+    }
+
+    /// Replace the metrics collector (defaults to a no-op collector)
+    pub fn with_metrics(mut self, metrics: Arc<dyn MetricsCollector>) -> Self {
+        self.metrics = metrics;
+        self
+    }
+
+    /// Reports the current term, role, indexes, queue depth and per-peer lag
+    pub fn report_metrics(&self) {
+        let last_index = self.log.last_index();
+
+        self.metrics
+            .observe_gauge("raft_term", self.current_term as f64);
+        self.metrics
+            .observe_gauge("raft_role", self.role.as_gauge());
+        self.metrics
+            .observe_gauge("raft_commit_index", self.commit_index as f64);
+        self.metrics
+            .observe_gauge("raft_applied_index", self.log.last_applied as f64);
+        self.metrics
+            .observe_gauge("raft_log_last_index", last_index as f64);
+        self.metrics.observe_gauge(
+            "raft_proposal_queue_depth",
+            last_index.saturating_sub(self.commit_index) as f64,
+        );
+
+        if self.role == NodeRole::Leader {
+            for peer in &self.peers {
+                let matched = self.match_index.get(peer).copied().unwrap_or(0);
+                self.metrics.set_gauge(
+                    "raft_replication_lag",
+                    &[("peer", peer)],
+                    last_index.saturating_sub(matched) as f64,
+                );
+            }
+        }
+    }
+
+    /// Metadata for a snapshot of the state at the last applied index
+    fn snapshot_meta(&self) -> SnapshotMeta {
+        SnapshotMeta {
+            last_included_index: self.log.last_applied,
+            last_included_term: self.log.term_at(self.log.last_applied).unwrap_or(0),
+            membership: Some(self.membership_at(self.log.last_applied)),
+            sessions: self.sessions.clone(),
+        }
+    }
+
+    /// Current cluster membership; voters include this node
+    pub fn membership(&self) -> Membership {
+        let mut voters = self.peers.clone();
+        voters.push(self.id.clone());
+        voters.sort();
+
+        Membership {
+            voters,
+            learners: self.learners.clone(),
+        }
+    }
+
+    /// Membership in effect at log `index`: the last configuration entry at or
+    /// before it, or the current membership if there has been no change
+    pub fn membership_at(&self, index: u64) -> Membership {
+        match self.configs.range(..=index).next_back() {
+            Some((_, membership)) => membership.clone(),
+            None => self.membership(),
+        }
+    }
+
+    /// Adopts the membership carried by a Configuration entry as it enters the log
+    fn track_configuration(&mut self, entry: &LogEntry) {
+        if entry.entry_type != LogEntryType::Configuration {
+            return;
+        }
+        match bincode::deserialize::<Membership>(&entry.data) {
+            Ok(membership) => {
+                if self.configs.is_empty() {
+                    // Remember what the membership was before the first change
+                    self.configs
+                        .insert(self.log.snapshot_index, self.membership());
+                }
+                self.set_membership(&membership);
+                self.configs.insert(entry.index, membership);
+            }
+            Err(err) => error!(index = entry.index, %err, "failed to deserialize configuration"),
+        }
+    }
+
+    /// Forgets configurations from truncated entries and falls back to the last one left
+    fn discard_configs_from(&mut self, index: u64) {
+        if self.configs.split_off(&index).is_empty() {
+            return;
+        }
+        if let Some(membership) = self.configs.values().next_back().cloned() {
+            self.set_membership(&membership);
+        }
+    }
+
+    fn set_membership(&mut self, membership: &Membership) {
+        self.peers = membership
+            .voters
+            .iter()
+            .filter(|id| **id != self.id)
+            .cloned()
+            .collect();
+        self.learners = membership.learners.clone();
+    }
+
+    /// Records that `sequence` was applied for `client_id`. Returns false if it
+    /// was already applied, so retried requests can be answered without re-applying.
+    pub fn record_session(&mut self, client_id: &str, sequence: u64) -> bool {
+        record_sequence(&mut self.sessions, client_id, sequence)
+    }
+
+    /// Records metrics for a finished snapshot
+    fn snapshot_finished(&self, meta: &SnapshotMeta, started: Instant, size: u64) {
+        self.metrics.observe_histogram(
+            "raft_snapshot_duration_seconds",
+            &[],
+            started.elapsed().as_secs_f64(),
+        );
+        self.metrics
+            .observe_gauge("raft_snapshot_size_bytes", size as f64);
+        info!(parent: &self.span(), index = meta.last_included_index, size, "snapshot taken");
+    }
+
+    /// Streams a snapshot of the state machine at the last applied index into `storage`,
+    /// blocking until it is written
+    pub fn take_snapshot(&self, storage: &dyn SnapshotStorage) -> Result<SnapshotMeta, NexusError> {
+        let started = Instant::now();
+        let meta = self.snapshot_meta();
+
+        let size = write_snapshot(self.state_machine.freeze()?, storage.create(meta.clone())?)?;
+
+        self.snapshot_finished(&meta, started, size);
+        Ok(meta)
+    }
+
+    /// Starts a snapshot at the last applied index and writes it on a background
+    /// thread, so entries can keep being applied meanwhile. Completion is picked up by
+    /// `poll_snapshot` or `wait_snapshot`.
+    pub fn start_snapshot(
+        &mut self,
+        storage: &dyn SnapshotStorage,
+    ) -> Result<SnapshotMeta, NexusError> {
+        if self.snapshot_in_progress.is_some() {
+            return Err(NexusError::Consensus("snapshot already in progress".into()));
+        }
+
+        let meta = self.snapshot_meta();
+        let frozen = self.state_machine.freeze()?;
+        let sink = storage.create(meta.clone())?;
+
+        self.snapshot_in_progress = Some(PendingSnapshot {
+            meta: meta.clone(),
+            started: Instant::now(),
+            handle: thread::spawn(move || write_snapshot(frozen, sink)),
+        });
+        debug!(parent: &self.span(), index = meta.last_included_index, "snapshot started");
+        Ok(meta)
+    }
+
+    /// Returns the result of the background snapshot if it has finished
+    pub fn poll_snapshot(&mut self) -> Option<Result<SnapshotMeta, NexusError>> {
+        if !self
+            .snapshot_in_progress
+            .as_ref()
+            .is_some_and(|pending| pending.handle.is_finished())
+        {
+            return None;
+        }
+        self.wait_snapshot()
+    }
+
+    /// Blocks until the background snapshot (if any) has finished
+    pub fn wait_snapshot(&mut self) -> Option<Result<SnapshotMeta, NexusError>> {
+        let pending = self.snapshot_in_progress.take()?;
+        let result = pending
+            .handle
+            .join()
+            .unwrap_or_else(|_| Err(NexusError::StateMachine("snapshot thread panicked".into())));
+
+        Some(result.map(|size| {
+            self.snapshot_finished(&pending.meta, pending.started, size);
+            pending.meta
+        }))
+    }
+
+    /// Loads the newest snapshot from `storage` into the state machine and log
+    pub fn restore_snapshot(
+        &mut self,
+        storage: &dyn SnapshotStorage,
+    ) -> Result<Option<SnapshotMeta>, NexusError> {
+        let Some((meta, reader)) = storage.open()? else {
+            return Ok(None);
+        };
+        self.install_snapshot(&meta, reader)?;
+        Ok(Some(meta))
+    }
+
+    /// Replaces the state machine, log prefix, membership and sessions with a snapshot
+    fn install_snapshot(
+        &mut self,
+        meta: &SnapshotMeta,
+        mut reader: SnapshotReader,
+    ) -> Result<(), NexusError> {
+        if self.state_machine.applied_index() >= Some(meta.last_included_index) {
+            // A durable state machine that is already past the snapshot resumes from its
+            // own state; only the log needs to learn where the snapshot ends
+            info!(
+                parent: &self.span(),
+                index = meta.last_included_index,
+                "state machine already covers snapshot"
+            );
+        } else {
+            self.state_machine
+                .restore_at(meta.last_included_index, &mut reader)
+                .map_err(|err| NexusError::StateMachine(err.to_string()))?;
+        }
+        self.log
+            .install_snapshot(meta.last_included_index, meta.last_included_term);
+        self.commit_index = self.commit_index.max(meta.last_included_index);
+
+        // Membership and sessions come from the snapshot, not from ClusterConfig. A
+        // configuration in the log kept after the snapshot is newer and stays in effect.
+        // Without a membership in the snapshot the configured peers stay in place.
+        if let Some(membership) = &meta.membership {
+            let index = meta.last_included_index;
+            let log = &self.log;
+            self.configs
+                .retain(|i, _| *i > index && log.get(*i).is_some());
+            self.configs.insert(index, membership.clone());
+            if let Some(membership) = self.configs.values().next_back().cloned() {
+                self.set_membership(&membership);
+            }
+        }
+        self.sessions = meta.sessions.clone();
+
+        info!(parent: &self.span(), index = meta.last_included_index, "restored snapshot");
+        Ok(())
+    }
+
+    /// Leader: opens the newest snapshot as a stream of InstallSnapshot chunks
+    pub fn snapshot_chunks(
+        &self,
+        storage: &dyn SnapshotStorage,
+        chunk_size: usize,
+    ) -> Result<Option<SnapshotChunks<SnapshotReader>>, NexusError> {
+        Ok(storage.open()?.map(|(meta, reader)| {
+            SnapshotChunks::new(reader, meta, self.current_term, self.id.clone(), chunk_size)
+        }))
+    }
+
+    /// Follower: writes one InstallSnapshot chunk to `storage`; the last chunk
+    /// replaces the state machine and the covered log prefix
+    pub fn handle_install_snapshot(
+        &mut self,
+        req: InstallSnapshotRequest,
+        storage: &dyn SnapshotStorage,
+    ) -> Result<InstallSnapshotResponse, NexusError> {
+        let _span = self.span().entered();
+
+        if req.term < self.current_term {
+            return Ok(InstallSnapshotResponse {
+                term: self.current_term,
+                success: false,
+            });
+        }
+        if req.term > self.current_term || self.role != NodeRole::Follower {
+            self.become_follower(req.term);
+        }
+        self.last_heartbeat = Instant::now();
+
+        let meta = req.meta.clone();
+
+        if req.offset == 0 {
+            // A new transfer replaces any half-received snapshot
+            self.incoming_snapshot = Some(IncomingSnapshot {
+                sink: storage.create(meta.clone())?,
+                meta: meta.clone(),
+                offset: 0,
+            });
         }
+
+        let accepted = matches!(
+            &self.incoming_snapshot,
+            Some(incoming) if incoming.meta == meta && incoming.offset == req.offset
+        );
+        if !accepted {
+            warn!(offset = req.offset, "unexpected snapshot chunk");
+            return Ok(InstallSnapshotResponse {
+                term: self.current_term,
+                success: false,
+            });
+        }
+
+        let incoming = self.incoming_snapshot.as_mut().unwrap();
+        incoming.sink.write_all(&req.data)?;
+        incoming.offset += req.data.len() as u64;
+
+        let mut success = true;
+        if req.done {
+            let incoming = self.incoming_snapshot.take().unwrap();
+            incoming.sink.finish()?;
+
+            // Install exactly what was received, not whatever snapshot is newest locally
+            match storage.open_snapshot(&incoming.meta)? {
+                Some(reader) => self.install_snapshot(&incoming.meta, reader)?,
+                None => {
+                    warn!(
+                        index = incoming.meta.last_included_index,
+                        "received snapshot failed verification"
+                    );
+                    success = false;
+                }
+            }
+        }
+
+        Ok(InstallSnapshotResponse {
+            term: self.current_term,
+            success,
+        })
     }
 
-    /// Called periodically to check if an election should start
+    /// Election priority of the given node (0 if unknown)
+    pub fn priority_of(&self, node_id: &str) -> u32 {
+        self.priorities.get(node_id).copied().unwrap_or(0)
+    }
+
+    /// Election timeout adjusted for priority: every distinct priority level above
+    /// ours adds another base timeout, so preferred nodes campaign first
+    pub fn effective_election_timeout(&self) -> Duration {
+        let own = self.priority_of(&self.id);
+        let higher: HashSet<u32> = self
+            .priorities
+            .values()
+            .copied()
+            .filter(|p| *p > own)
+            .collect();
+
+        self.election_timeout * (higher.len() as u32 + 1)
+    }
+
+    /// Called periodically to check if an election should start. A leader gives up on
+    /// a transfer whose target has not taken over within one election timeout.
     pub fn tick(&mut self) {
-        if self.role != NodeRole::Leader && self.last_heartbeat.elapsed() >= self.election_timeout {
+        if self.role != NodeRole::Leader
+            && self.last_heartbeat.elapsed() >= self.effective_election_timeout()
+        {
             self.start_election();
         }
+
+        let expired = self
+            .transfer_started
+            .is_some_and(|started| started.elapsed() >= self.election_timeout);
+        if self.role == NodeRole::Leader && expired {
+            let target = self.transfer_target.take();
+            self.transfer_started = None;
+            warn!(parent: &self.span(), ?target, "leadership transfer timed out");
+        }
+    }
+
+    /// Leader: if a fully caught-up peer has a higher priority than us, pick it as the
+    /// transfer target and build the TimeoutNow request that makes it campaign
+    pub fn maybe_transfer_leadership(&mut self) -> Option<(NodeId, TimeoutNowRequest)> {
+        if self.role != NodeRole::Leader || self.transfer_target.is_some() {
+            return None;
+        }
+
+        let own = self.priority_of(&self.id);
+        let last_index = self.log.last_index();
+
+        let target = self
+            .peers
+            .iter()
+            .filter(|p| self.priority_of(p) > own)
+            .filter(|p| self.match_index.get(*p).copied().unwrap_or(0) >= last_index)
+            .max_by_key(|p| self.priority_of(p))?
+            .clone();
+
+        self.transfer_target = Some(target.clone());
+        self.transfer_started = Some(Instant::now());
+
+        let request = TimeoutNowRequest {
+            term: self.current_term,
+            leader_id: self.id.clone(),
+        };
+        Some((target, request))
+    }
+
+    /// Leader: appends a command for the state machine; None if not the leader or
+    /// leadership is being transferred, as the target must stay caught up to take over
+    pub fn propose_command(&mut self, command: &C) -> Option<u64> {
+        if self.role != NodeRole::Leader || self.transfer_target.is_some() {
+            return None;
+        }
+
+        let command = bincode::serialize(command).ok()?;
+        Some(self.append_entry(command))
+    }
+
+    /// Handles TimeoutNow from the current leader: campaign right away without waiting
+    /// for the election timeout
+    pub fn handle_timeout_now(&mut self, req: TimeoutNowRequest) {
+        let _span = self.span().entered();
+
+        if req.term < self.current_term {
+            debug!(leader = %req.leader_id, term = req.term, "ignored TimeoutNow from stale term");
+            return;
+        }
+        if req.term > self.current_term {
+            self.become_follower(req.term);
+        }
+        if self.role == NodeRole::Leader {
+            debug!(leader = %req.leader_id, "ignored TimeoutNow, already leader");
+            return;
+        }
+
+        self.start_election();
     }
 
     /// Starts an election
     pub fn start_election(&mut self) {
+        let _span = self.span().entered();
+
         self.role = NodeRole::Candidate;
         self.current_term += 1;
         self.voted_for = Some(self.id.clone());
         self.votes_received.clear();
         self.votes_received.insert(self.id.clone());
         self.last_heartbeat = Instant::now();
+        self.metrics.inc_counter("raft_elections_total");
+        self.report_metrics();
 
         // Normally: send RequestVote RPCs to all peers here
-        println!(
-            "[{}] Starting election for term {}",
-            self.id, self.current_term
-        );
+        info!(term = self.current_term, "starting election");
     }
 
     /// Handles a vote response
     pub fn receive_vote(&mut self, voter_id: NodeId, term: Term, vote_granted: bool) {
+        let _span = self.span().entered();
+
         if term > self.current_term {
             self.become_follower(term);
             return;
         }
 
         if self.role != NodeRole::Candidate || term < self.current_term {
+            debug!(voter = %voter_id, term, "ignored vote from stale term or after election");
             return;
         }
 
         if vote_granted {
             self.votes_received.insert(voter_id);
-            let majority = (self.peers.len() + 1) / 2 + 1;
+            let majority = self.peers.len().div_ceil(2) + 1;
             if self.votes_received.len() >= majority {
                 self.become_leader();
             }
+        } else {
+            debug!(voter = %voter_id, "vote rejected");
         }
     }
 
     /// Transition to follower role
     pub fn become_follower(&mut self, term: Term) {
+        let _span = self.span().entered();
+
         self.role = NodeRole::Follower;
         self.current_term = term;
         self.voted_for = None;
         self.last_heartbeat = Instant::now();
         self.votes_received.clear();
-        println!(
-            "[{}] Became Follower for term {}",
-            self.id, self.current_term
-        );
+        self.transfer_target = None;
+        self.transfer_started = None;
+        self.proposals.clear(); // uncommitted proposals may be overwritten by the new leader
+        self.proposed_at.clear();
+        info!(term, "became follower");
+        self.report_metrics();
     }
 
     /// Transition to leader role
     pub fn become_leader(&mut self) {
+        let _span = self.span().entered();
+
         self.role = NodeRole::Leader;
-        println!("[{}] Became Leader for term {}", self.id, self.current_term);
+        self.transfer_target = None;
+        self.transfer_started = None;
+        info!(term = self.current_term, "became leader");
+        self.report_metrics();
         self.send_heartbeat();
     }
 
     /// Leader sends empty AppendEntries (heartbeat) to all followers
     pub fn send_heartbeat(&mut self) {
+        let _span = self.span().entered();
+
         self.last_heartbeat = Instant::now();
         // Normally: send AppendEntries RPC with no entries
-        println!("[{}] Sending heartbeats", self.id);
+        trace!("sending heartbeats");
+    }
+}
+
+/// Nodes replicating the key-value store
+impl RaftNode {
+    /// Create a new Raft node backed by an in-memory key-value store
+    pub fn new(id: NodeId, peers: Vec<NodeId>, election_timeout: Duration) -> Self {
+        Self::with_state_machine(
+            id,
+            peers,
+            election_timeout,
+            Box::new(crate::raft::state_machine::KeyValueStore::default()),
+        )
+    }
+
+    /// Create a Raft node from the cluster configuration, backed by an in-memory
+    /// key-value store
+    pub fn from_config(id: NodeId, config: &ClusterConfig) -> Self {
+        Self::with_config(
+            id,
+            config,
+            Box::new(crate::raft::state_machine::KeyValueStore::default()),
+        )
+    }
+
+    /// Leader: proposes an `ExpireLeases` entry stamped with the leader's clock. Followers
+    /// expire leases only when they apply this entry, so expiry is identical on every replica.
+    pub fn propose_lease_expiry(&mut self, now_ms: u64) -> Option<u64> {
+        self.propose(KvCommand::ExpireLeases { now_ms }, now_ms)
+    }
+
+    /// Leader: proposes a client command. Lease commands are stamped with the leader's
+    /// clock, replacing whatever time the client sent.
+    pub fn propose(&mut self, mut command: KvCommand, now_ms: u64) -> Option<u64> {
+        match &mut command {
+            KvCommand::LeaseGrant { now_ms: at, .. }
+            | KvCommand::LeaseKeepAlive { now_ms: at, .. }
+            | KvCommand::ExpireLeases { now_ms: at } => *at = now_ms,
+            _ => {}
+        }
+        self.propose_command(&command)
     }
 }
 
@@ -308,6 +1107,9 @@ impl RaftNode {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::raft::snapshot::FileSnapshotStorage;
+    use nexus_common::metrics::InMemoryMetrics;
+    use std::io::Read;
 
     fn test_node(id: &str) -> RaftNode {
         RaftNode::new(
@@ -336,7 +1138,7 @@ mod tests {
         node.receive_vote("node2".into(), node.current_term + 1, false);
 
         assert_eq!(node.role, NodeRole::Follower);
-        assert_eq!(node.current_term, 1); // incremented from start_election()
+        assert_eq!(node.current_term, 2); // incremented from start_election()
                                           // incremented from start_election() -> make it 2 to pass this case, ideally it should fail
                                           //  in order to test it out correctly -> by proper logic
     }
@@ -398,6 +1200,645 @@ mod tests {
         assert_eq!(res.term, 2);
     }
 
+    fn prioritized_node(id: &str) -> RaftNode {
+        let mut node = test_node(id);
+        node.priorities = HashMap::from([
+            ("node1".to_string(), 1),
+            ("node2".to_string(), 3),
+            ("node3".to_string(), 2),
+        ]);
+        node
+    }
+
+    #[test]
+    fn test_from_config_reads_peers_and_priorities() {
+        use nexus_common::types::NodeAddress;
+
+        let address = |id: &str, priority| NodeAddress {
+            host: "127.0.0.1".into(),
+            port: 8080,
+            node_id: id.into(),
+            priority,
+        };
+        let config = ClusterConfig {
+            nodes: vec![address("node1", 1), address("node2", 5)],
+            replication_factor: 2,
+            election_timeout_ms: 150,
+            heartbeat_interval_ms: 50,
+            compression: Default::default(),
+        };
+
+        let node = RaftNode::from_config("node1".into(), &config);
+        assert_eq!(node.peers, vec!["node2".to_string()]);
+        assert_eq!(node.priority_of("node2"), 5);
+        assert_eq!(node.election_timeout, Duration::from_millis(150));
+    }
+
+    #[test]
+    fn test_configured_codec_compresses_log_and_snapshots() {
+        use nexus_common::types::NodeAddress;
+
+        let dir = tempfile::tempdir().unwrap();
+        let config = ClusterConfig {
+            nodes: vec![NodeAddress {
+                host: "127.0.0.1".into(),
+                port: 8080,
+                node_id: "node1".into(),
+                priority: 0,
+            }],
+            replication_factor: 1,
+            election_timeout_ms: 150,
+            heartbeat_interval_ms: 50,
+            compression: CompressionCodec::Zstd,
+        };
+
+        let mut node = RaftNode::from_config("node1".into(), &config).with_log_dir(dir.path());
+        node.become_leader();
+        for _ in 0..200 {
+            node.append_entry(b"set key value".to_vec());
+        }
+        let segment = node.seal_log().unwrap().unwrap();
+        assert!(node.seal_log().unwrap().is_none());
+
+        // The codec byte follows magic and version in the header
+        let bytes = std::fs::read(&segment).unwrap();
+        assert_eq!(bytes[6], CompressionCodec::Zstd.id());
+        assert!(bytes.len() < 200 * 13 / 4);
+
+        let mut restarted = RaftNode::from_config("node1".into(), &config).with_log_dir(dir.path());
+        assert_eq!(restarted.load_log().unwrap(), 200);
+        assert_eq!(restarted.log.last_index(), 200);
+
+        let storage = FileSnapshotStorage::from_config(dir.path().join("snapshots"), &config);
+        assert_eq!(storage.codec, CompressionCodec::Zstd);
+    }
+
+    #[test]
+    fn test_low_priority_waits_longer() {
+        let low = prioritized_node("node1");
+        let high = prioritized_node("node2");
+
+        assert_eq!(
+            high.effective_election_timeout(),
+            Duration::from_millis(150)
+        );
+        assert_eq!(low.effective_election_timeout(), Duration::from_millis(450));
+
+        let mut low = low;
+        low.last_heartbeat = Instant::now() - Duration::from_millis(200);
+        low.tick();
+        assert_eq!(low.role, NodeRole::Follower);
+    }
+
+    #[test]
+    fn test_leader_transfers_to_caught_up_preferred_peer() {
+        let mut node = prioritized_node("node1");
+        node.become_leader();
+        let last = node.append_entry(vec![]);
+
+        // Top priority peer is lagging: the caught-up runner-up is picked instead
+        node.match_index.insert("node2".into(), last - 1);
+        node.match_index.insert("node3".into(), last);
+        let (target, _) = node.maybe_transfer_leadership().unwrap();
+        assert_eq!(target, "node3");
+
+        // Once the top priority peer catches up it is picked
+        let mut node = prioritized_node("node1");
+        node.become_leader();
+        let last = node.append_entry(vec![]);
+        node.match_index.insert("node2".into(), last);
+        node.match_index.insert("node3".into(), last);
+        let (target, req) = node.maybe_transfer_leadership().unwrap();
+        assert_eq!(target, "node2");
+        assert_eq!(req.term, node.current_term);
+        assert_eq!(node.transfer_target, Some("node2".into()));
+
+        // Only one transfer at a time
+        assert!(node.maybe_transfer_leadership().is_none());
+    }
+
+    #[test]
+    fn test_pending_transfer_blocks_proposals_and_times_out() {
+        let mut node = prioritized_node("node1");
+        node.become_leader();
+        let last = node.append_entry(vec![]);
+        node.match_index.insert("node2".into(), last);
+        node.maybe_transfer_leadership().unwrap();
+
+        let command = KvCommand::Set("key".into(), "value".into());
+        assert_eq!(node.propose(command.clone(), 0), None);
+
+        // Still pending within the election timeout
+        node.tick();
+        assert_eq!(node.transfer_target, Some("node2".into()));
+
+        // The target never took over: the leader gives up and accepts writes again
+        node.transfer_started = Some(Instant::now() - node.election_timeout);
+        node.tick();
+        assert_eq!(node.transfer_target, None);
+        assert_eq!(node.role, NodeRole::Leader);
+        assert!(node.propose(command, 0).is_some());
+    }
+
+    #[test]
+    fn test_no_transfer_from_highest_priority_leader() {
+        let mut node = prioritized_node("node2");
+        node.become_leader();
+        node.match_index
+            .insert("node1".into(), node.log.last_index());
+        node.match_index
+            .insert("node3".into(), node.log.last_index());
+
+        assert!(node.maybe_transfer_leadership().is_none());
+    }
+
+    #[test]
+    fn test_timeout_now_starts_election() {
+        let mut node = prioritized_node("node2");
+        node.current_term = 3;
+
+        node.handle_timeout_now(TimeoutNowRequest {
+            term: 3,
+            leader_id: "node1".into(),
+        });
+
+        assert_eq!(node.role, NodeRole::Candidate);
+        assert_eq!(node.current_term, 4);
+    }
+
+    #[test]
+    fn test_timeout_now_adopts_higher_term() {
+        let mut node = prioritized_node("node2");
+        node.current_term = 3;
+        node.voted_for = Some("node3".into());
+
+        node.handle_timeout_now(TimeoutNowRequest {
+            term: 5,
+            leader_id: "node1".into(),
+        });
+
+        // The higher term is adopted first, then the election moves past it
+        assert_eq!(node.role, NodeRole::Candidate);
+        assert_eq!(node.current_term, 6);
+        assert_eq!(node.voted_for, Some("node2".into()));
+    }
+
+    #[test]
+    fn test_reports_metrics() {
+        let metrics = Arc::new(InMemoryMetrics::new());
+        let mut node = test_node("node1").with_metrics(metrics.clone());
+
+        node.start_election();
+        node.receive_vote("node2".into(), node.current_term, true);
+        let index = node.append_entry(vec![]);
+        node.match_index.insert("node2".into(), index - 1);
+        node.report_metrics();
+
+        let snap = metrics.snapshot();
+        assert_eq!(snap.counter("raft_elections_total", &[]), Some(1));
+        assert_eq!(snap.gauge("raft_term", &[]), Some(1.0));
+        assert_eq!(
+            snap.gauge("raft_role", &[]),
+            Some(NodeRole::Leader.as_gauge())
+        );
+        assert_eq!(snap.gauge("raft_proposal_queue_depth", &[]), Some(1.0));
+        assert_eq!(
+            snap.gauge("raft_replication_lag", &[("peer", "node2")]),
+            Some(1.0)
+        );
+        assert_eq!(
+            snap.gauge("raft_replication_lag", &[("peer", "node3")]),
+            Some(1.0)
+        );
+        assert!(snap.histogram("raft_append_latency_seconds", &[]).is_none());
+
+        // Latency is observed once the entry is committed by a quorum
+        node.match_index.insert("node2".into(), index);
+        node.update_commit_index();
+        assert_eq!(node.commit_index, index);
+        let snap = metrics.snapshot();
+        assert_eq!(
+            snap.histogram("raft_append_latency_seconds", &[])
+                .unwrap()
+                .count,
+            1
+        );
+        assert!(node.proposed_at.is_empty());
+    }
+
+    #[test]
+    fn test_take_snapshot_reports_size() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = FileSnapshotStorage::new(dir.path());
+        let metrics = Arc::new(InMemoryMetrics::new());
+        let mut node = test_node("node1").with_metrics(metrics.clone());
+        node.state_machine
+            .apply(KvCommand::Set("key".into(), "value".into()));
+
+        node.take_snapshot(&storage).unwrap();
+
+        let (_, mut reader) = storage.open().unwrap().unwrap();
+        let mut state = Vec::new();
+        reader.read_to_end(&mut state).unwrap();
+
+        let snap = metrics.snapshot();
+        assert_eq!(
+            snap.gauge("raft_snapshot_size_bytes", &[]),
+            Some(state.len() as f64)
+        );
+        assert!(snap
+            .histogram("raft_snapshot_duration_seconds", &[])
+            .is_some());
+    }
+
+    #[test]
+    fn test_background_snapshot_captures_applied_index() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = FileSnapshotStorage::new(dir.path());
+        let mut node = test_node("node1");
+        node.become_leader();
+
+        let set =
+            |k: &str, v: &str| bincode::serialize(&KvCommand::Set(k.into(), v.into())).unwrap();
+        node.commit_index = node.append_entry(set("a", "1"));
+        let mut sm = std::mem::replace(
+            &mut node.state_machine,
+            Box::new(crate::raft::state_machine::KeyValueStore::default()),
+        );
+        node.apply_committed_entries(&mut *sm).unwrap();
+        node.state_machine = sm;
+
+        let meta = node.start_snapshot(&storage).unwrap();
+        assert_eq!(meta.last_included_index, 1);
+        assert!(node.start_snapshot(&storage).is_err());
+
+        // Keep applying while the snapshot is being written
+        node.state_machine
+            .apply(KvCommand::Set("a".into(), "2".into()));
+
+        let finished = node.wait_snapshot().unwrap().unwrap();
+        assert_eq!(finished, meta);
+        assert!(node.poll_snapshot().is_none());
+
+        let mut restored = test_node("node2");
+        restored.restore_snapshot(&storage).unwrap();
+        assert_eq!(restored.state_machine.get("a".into()), Some("1".into()));
+        assert_eq!(restored.log.last_index(), 1);
+    }
+
+    #[test]
+    fn test_leader_proposes_lease_expiry() {
+        let mut follower = test_node("node2");
+        assert_eq!(follower.propose_lease_expiry(1_000), None);
+
+        let mut node = test_node("node1");
+        node.become_leader();
+        let encode = |cmd: KvCommand| bincode::serialize(&cmd).unwrap();
+        node.append_entry(encode(KvCommand::LeaseGrant {
+            id: 1,
+            ttl_ms: 500,
+            now_ms: 0,
+        }));
+        node.append_entry(encode(KvCommand::PutWithLease {
+            key: "k".into(),
+            value: "v".into(),
+            lease: 1,
+        }));
+        node.commit_index = node.propose_lease_expiry(500).unwrap();
+
+        let mut sm = std::mem::replace(
+            &mut node.state_machine,
+            Box::new(crate::raft::state_machine::KeyValueStore::default()),
+        );
+        node.apply_committed_entries(&mut *sm).unwrap();
+        assert_eq!(node.log.last_applied, 3);
+        assert_eq!(sm.get("k".into()), None);
+    }
+
+    #[test]
+    fn test_leader_stamps_lease_commands() {
+        let mut follower = test_node("node2");
+        let grant = KvCommand::LeaseGrant {
+            id: 1,
+            ttl_ms: 500,
+            now_ms: 1_000_000, // A client clock far ahead of the leader's
+        };
+        assert_eq!(follower.propose(grant.clone(), 1_000), None);
+
+        let mut node = test_node("node1");
+        node.become_leader();
+        let index = node.propose(grant, 1_000).unwrap();
+
+        let entry = node.log.get(index).unwrap();
+        assert_eq!(
+            bincode::deserialize::<KvCommand>(&entry.data).unwrap(),
+            KvCommand::LeaseGrant {
+                id: 1,
+                ttl_ms: 500,
+                now_ms: 1_000,
+            }
+        );
+    }
+
+    #[test]
+    fn test_durable_state_machine_resumes_without_replay() {
+        use crate::raft::disk_store::DiskKvStore;
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("kv.redb");
+        let commands = [("a", "1"), ("b", "2"), ("c", "3")];
+        let leader_with_log = || {
+            let mut node = test_node("node1");
+            node.become_leader();
+            for (k, v) in commands {
+                let cmd = KvCommand::Set(k.into(), v.into());
+                node.commit_index = node.append_entry(bincode::serialize(&cmd).unwrap());
+            }
+            node
+        };
+
+        let mut node = leader_with_log();
+        let mut disk = DiskKvStore::open(&path).unwrap();
+        node.apply_committed_entries(&mut disk).unwrap();
+        assert_eq!(disk.applied_index(), Some(3));
+        drop(disk);
+
+        // After a restart the log is replayed, but nothing is applied twice
+        let mut restarted = leader_with_log();
+        let mut disk = DiskKvStore::open(&path).unwrap();
+        restarted.apply_committed_entries(&mut disk).unwrap();
+        assert_eq!(restarted.log.last_applied, 3);
+        assert_eq!(disk.revision(), 3);
+
+        // An older snapshot is not restored over the newer durable state
+        let storage = FileSnapshotStorage::new(dir.path().join("snapshots"));
+        let mut old = test_node("node2");
+        old.log.install_snapshot(2, 1);
+        old.take_snapshot(&storage).unwrap();
+
+        let mut restarted = test_node("node1");
+        restarted.state_machine = Box::new(disk);
+        restarted.restore_snapshot(&storage).unwrap();
+        assert_eq!(restarted.state_machine.get("c".into()), Some("3".into()));
+    }
+
+    #[test]
+    fn test_install_snapshot_in_chunks() {
+        let leader_dir = tempfile::tempdir().unwrap();
+        let leader_storage = FileSnapshotStorage::new(leader_dir.path());
+        let mut leader = test_node("node1");
+        leader.current_term = 2;
+        for i in 0..100 {
+            leader
+                .state_machine
+                .apply(KvCommand::Set(format!("key{}", i), "value".into()));
+        }
+        leader.log.install_snapshot(7, 2);
+        leader.learners.push("node4".into());
+        leader.record_session("client-a", 3);
+        leader.take_snapshot(&leader_storage).unwrap();
+
+        let follower_dir = tempfile::tempdir().unwrap();
+        let follower_storage = FileSnapshotStorage::new(follower_dir.path());
+        let mut follower = RaftNode::new("node2".into(), vec![], Duration::from_millis(150));
+
+        let chunks = leader
+            .snapshot_chunks(&leader_storage, 64)
+            .unwrap()
+            .unwrap();
+        for chunk in chunks {
+            let res = follower
+                .handle_install_snapshot(chunk.unwrap(), &follower_storage)
+                .unwrap();
+            assert!(res.success);
+        }
+
+        assert!(follower.incoming_snapshot.is_none());
+        assert_eq!(follower.current_term, 2);
+        assert_eq!(follower.log.last_index(), 7);
+        assert_eq!(
+            follower.peers,
+            vec!["node1".to_string(), "node3".to_string()]
+        );
+        assert_eq!(follower.learners, vec!["node4".to_string()]);
+        assert_eq!(follower.sessions.get("client-a"), Some(&3));
+        assert_eq!(follower.commit_index, 7);
+        assert_eq!(
+            follower.state_machine.get("key42".into()),
+            Some("value".into())
+        );
+    }
+
+    #[test]
+    fn test_install_snapshot_ignores_newer_local_snapshot() {
+        let leader_dir = tempfile::tempdir().unwrap();
+        let leader_storage = FileSnapshotStorage::new(leader_dir.path());
+        let mut leader = test_node("node1");
+        leader
+            .state_machine
+            .apply(KvCommand::Set("from".into(), "leader".into()));
+        leader.log.install_snapshot(7, 1);
+        leader.take_snapshot(&leader_storage).unwrap();
+
+        // The follower already has a newer snapshot of a different state on disk
+        let follower_dir = tempfile::tempdir().unwrap();
+        let follower_storage = FileSnapshotStorage::new(follower_dir.path());
+        let mut follower = test_node("node2");
+        follower
+            .state_machine
+            .apply(KvCommand::Set("from".into(), "local".into()));
+        follower.log.install_snapshot(20, 1);
+        follower.take_snapshot(&follower_storage).unwrap();
+
+        let mut follower = test_node("node2");
+        for chunk in leader
+            .snapshot_chunks(&leader_storage, 16)
+            .unwrap()
+            .unwrap()
+        {
+            let res = follower
+                .handle_install_snapshot(chunk.unwrap(), &follower_storage)
+                .unwrap();
+            assert!(res.success);
+        }
+
+        assert_eq!(
+            follower.state_machine.get("from".into()),
+            Some("leader".into())
+        );
+        assert_eq!(follower.commit_index, 7);
+    }
+
+    #[test]
+    fn test_restart_from_snapshot_restores_membership() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = FileSnapshotStorage::new(dir.path());
+        let node = test_node("node1");
+        node.take_snapshot(&storage).unwrap();
+
+        // Restarted without any peers configured
+        let mut restarted = RaftNode::new("node1".into(), vec![], Duration::from_millis(150));
+        let meta = restarted.restore_snapshot(&storage).unwrap().unwrap();
+
+        assert_eq!(
+            meta.membership.unwrap().voters,
+            vec!["node1", "node2", "node3"]
+        );
+        assert_eq!(
+            restarted.peers,
+            vec!["node2".to_string(), "node3".to_string()]
+        );
+    }
+
+    #[test]
+    fn test_snapshot_without_membership_keeps_configured_peers() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = FileSnapshotStorage::new(dir.path());
+        let mut sink = storage.create(SnapshotMeta::at(4, 1)).unwrap();
+        let state = test_node("node1").state_machine.freeze().unwrap();
+        state.write_to(&mut sink).unwrap();
+        sink.finish().unwrap();
+
+        let mut node = test_node("node1");
+        node.restore_snapshot(&storage).unwrap().unwrap();
+
+        assert_eq!(node.peers, vec!["node2".to_string(), "node3".to_string()]);
+        assert_eq!(node.log.snapshot_index, 4);
+    }
+
+    #[test]
+    fn test_record_session_rejects_duplicates() {
+        let mut node = test_node("node1");
+
+        assert!(node.record_session("client-a", 1));
+        assert!(node.record_session("client-a", 2));
+        assert!(!node.record_session("client-a", 2));
+        assert!(node.record_session("client-b", 1));
+    }
+
+    #[test]
+    fn test_retried_client_request_is_applied_once() {
+        let mut node = test_node("node1");
+        node.become_leader();
+
+        let first = bincode::serialize(&KvCommand::Set("key".into(), "1".into())).unwrap();
+        let retry = bincode::serialize(&KvCommand::Set("key".into(), "2".into())).unwrap();
+        node.append_client_entry(first, "client-a", 1);
+        node.commit_index = node.append_client_entry(retry, "client-a", 1);
+
+        let mut sm = std::mem::replace(
+            &mut node.state_machine,
+            Box::new(crate::raft::state_machine::KeyValueStore::default()),
+        );
+        node.apply_committed_entries(&mut *sm).unwrap();
+        node.state_machine = sm;
+
+        assert_eq!(node.state_machine.get("key".into()), Some("1".into()));
+        assert_eq!(node.snapshot_meta().sessions.get("client-a"), Some(&1));
+    }
+
+    #[test]
+    fn test_snapshot_membership_is_taken_at_snapshot_index() {
+        let mut node = test_node("node1");
+        node.become_leader();
+        node.commit_index = node.append_entry(vec![]);
+        node.log.last_applied = node.commit_index;
+        let before = node.membership();
+
+        let grown = Membership {
+            voters: vec!["node1".into(), "node2".into(), "node3".into()],
+            learners: vec!["node4".into()],
+        };
+        let index = node.append_configuration(&grown);
+        assert_eq!(node.learners, vec!["node4".to_string()]);
+
+        // The change is not applied yet, so a snapshot still carries the old membership
+        assert_eq!(node.snapshot_meta().membership, Some(before));
+
+        node.log.last_applied = index;
+        assert_eq!(node.snapshot_meta().membership, Some(grown));
+    }
+
+    #[test]
+    fn test_truncated_configuration_is_rolled_back() {
+        let mut follower = test_node("node2");
+        let grown = Membership {
+            voters: vec!["node1".into(), "node2".into(), "node3".into()],
+            learners: vec!["node4".into()],
+        };
+        let entry = |term, entry_type, data| LogEntry {
+            term,
+            index: 1,
+            entry_type,
+            data,
+            client: None,
+        };
+        let request = |term, entries| AppendEntriesRequest {
+            term,
+            leader_id: "node1".into(),
+            prev_log_index: 0,
+            prev_log_term: 0,
+            entries,
+            leader_commit: 0,
+        };
+
+        let config = bincode::serialize(&grown).unwrap();
+        follower.handle_append_entries(request(
+            1,
+            vec![entry(1, LogEntryType::Configuration, config)],
+        ));
+        assert_eq!(follower.learners, vec!["node4".to_string()]);
+
+        // A new leader overwrites the uncommitted change
+        follower.handle_append_entries(request(2, vec![entry(2, LogEntryType::Noop, vec![])]));
+        assert!(follower.learners.is_empty());
+        assert_eq!(follower.membership_at(1).learners, Vec::<NodeId>::new());
+    }
+
+    #[test]
+    fn test_install_snapshot_rejects_out_of_order_chunk() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = FileSnapshotStorage::new(dir.path());
+        let mut node = test_node("node2");
+
+        let res = node
+            .handle_install_snapshot(
+                InstallSnapshotRequest {
+                    term: 1,
+                    leader_id: "node1".into(),
+                    meta: SnapshotMeta::at(3, 1),
+                    offset: 10,
+                    data: vec![1, 2, 3],
+                    done: false,
+                },
+                &storage,
+            )
+            .unwrap();
+
+        assert!(!res.success);
+        assert!(storage.open().unwrap().is_none());
+    }
+
+    #[test]
+    fn test_proposal_span_lives_until_applied() {
+        let mut node = test_node("node1");
+        node.become_leader();
+
+        let command = KvCommand::Set("key".into(), "value".into());
+        let index = node.append_entry(bincode::serialize(&command).unwrap());
+        assert!(node.proposals.contains_key(&index));
+        assert_eq!(node.next_request_id, 2);
+
+        node.commit_index = index;
+        let mut sm = std::mem::replace(
+            &mut node.state_machine,
+            Box::new(crate::raft::state_machine::KeyValueStore::default()),
+        );
+        node.apply_committed_entries(&mut *sm).unwrap();
+
+        assert!(node.proposals.is_empty());
+    }
+
     #[test]
     fn test_append_and_apply_committed_entry() {
         let mut node = test_node("node1");
@@ -414,7 +1855,7 @@ mod tests {
         );
 
         // Apply committed entries
-        node.apply_committed_entries(&mut *sm);
+        node.apply_committed_entries(&mut *sm).unwrap();
 
         // Put the state machine back
         node.state_machine = sm;
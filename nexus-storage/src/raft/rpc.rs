@@ -1,4 +1,5 @@
 use super::log::LogEntry;
+use super::snapshot::SnapshotMeta;
 use nexus_common::types::{NodeId, Term};
 use serde::{Deserialize, Serialize};
 
@@ -36,6 +37,32 @@ pub struct RequestVoteResponse {
     pub vote_granted: bool,
 }
 
+/// TimeoutNow RPC: Leader → Peer, asks the peer to start an election immediately.
+/// Used to hand leadership over to a preferred (higher priority) node.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TimeoutNowRequest {
+    pub term: Term,        // Leader's term
+    pub leader_id: NodeId, // Leader handing over leadership
+}
+
+/// InstallSnapshot RPC: Leader → Peer, one chunk of a snapshot stream
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct InstallSnapshotRequest {
+    pub term: Term,         // Leader's term
+    pub leader_id: NodeId,  // Leader's ID
+    pub meta: SnapshotMeta, // Log position, membership and sessions the snapshot covers
+    pub offset: u64,        // Byte offset of this chunk in the snapshot stream
+    pub data: Vec<u8>,      // Chunk contents
+    pub done: bool,         // True if this is the last chunk
+}
+
+/// Response to InstallSnapshot
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct InstallSnapshotResponse {
+    pub term: Term,    // Current term (may be newer)
+    pub success: bool, // False if the chunk was rejected (stale term or unexpected offset)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -0,0 +1,241 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+
+use crate::raft::codec::{self, Compressor};
+use crate::raft::log::LogEntry;
+use nexus_common::error::NexusError;
+use nexus_common::types::CompressionCodec;
+
+/// Identifies log segment files ("NeXus LoG")
+const MAGIC: &[u8; 4] = b"NXLG";
+/// Current on-disk format version
+const FORMAT_VERSION: u16 = 1;
+/// magic (4) + version (2) + codec (1) + crc32 (4) + payload length (8)
+const HEADER_LEN: usize = 19;
+
+/// Sealed log segments in a directory. The segment holding entries `first..=last` is
+/// named `{first:020}-{last:020}.seg`. Sealing a segment removes any segment starting at
+/// or after its first index, so entries truncated from the log never come back on load.
+pub struct LogSegments {
+    pub dir: PathBuf,
+    pub codec: CompressionCodec, // Compression for newly sealed segments
+}
+
+impl LogSegments {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self {
+            dir: dir.into(),
+            codec: CompressionCodec::None,
+        }
+    }
+
+    /// Compress new segments with `codec` (usually `ClusterConfig::compression`).
+    /// Existing segments keep the codec recorded in their header.
+    pub fn with_codec(mut self, codec: CompressionCodec) -> Self {
+        self.codec = codec;
+        self
+    }
+
+    /// Segment files with their first index, in index order
+    pub fn list(&self) -> Result<Vec<(u64, PathBuf)>, NexusError> {
+        if !self.dir.exists() {
+            return Ok(Vec::new());
+        }
+
+        let mut segments: Vec<(u64, PathBuf)> = fs::read_dir(&self.dir)?
+            .filter_map(|entry| entry.ok().map(|e| e.path()))
+            .filter(|path| path.extension().is_some_and(|ext| ext == "seg"))
+            .filter_map(|path| {
+                let name = path.file_stem()?.to_str()?;
+                let first = name.split('-').next()?.parse().ok()?;
+                Some((first, path))
+            })
+            .collect();
+        segments.sort();
+        Ok(segments)
+    }
+
+    /// Writes `entries` (consecutive, non-empty) as a new segment
+    pub fn seal(&self, entries: &[LogEntry]) -> Result<PathBuf, NexusError> {
+        let (first, last) = match entries {
+            [first, .., last] => (first.index, last.index),
+            [only] => (only.index, only.index),
+            [] => return Err(NexusError::Consensus("cannot seal an empty segment".into())),
+        };
+        fs::create_dir_all(&self.dir)?;
+
+        let path = self.dir.join(format!("{:020}-{:020}.seg", first, last));
+        write_segment(&path, entries, self.codec)?;
+        for (start, stale) in self.list()? {
+            if start >= first && stale != path {
+                fs::remove_file(stale)?;
+            }
+        }
+        Ok(path)
+    }
+
+    /// Reads every segment back into one run of entries
+    pub fn load(&self) -> Result<Vec<LogEntry>, NexusError> {
+        let mut entries: Vec<LogEntry> = Vec::new();
+        for (first, path) in self.list()? {
+            entries.retain(|e| e.index < first);
+            entries.extend(read_segment(&path)?);
+        }
+        Ok(entries)
+    }
+}
+
+/// Writes a run of log entries to a segment file, compressed with `codec`.
+/// The file is written to a temp path, fsynced and renamed into place.
+pub fn write_segment(
+    path: &Path,
+    entries: &[LogEntry],
+    codec: CompressionCodec,
+) -> Result<(), NexusError> {
+    let mut compressor = Compressor::new(codec, Vec::new())?;
+    bincode::serialize_into(&mut compressor, entries)?;
+    let payload = compressor.finish()?;
+
+    let mut header = Vec::with_capacity(HEADER_LEN);
+    header.extend_from_slice(MAGIC);
+    header.extend_from_slice(&FORMAT_VERSION.to_le_bytes());
+    header.push(codec.id());
+    header.extend_from_slice(&crc32fast::hash(&payload).to_le_bytes());
+    header.extend_from_slice(&(payload.len() as u64).to_le_bytes());
+
+    let temp_path = path.with_extension("tmp");
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(&temp_path)?;
+    file.write_all(&header)?;
+    file.write_all(&payload)?;
+    file.sync_all()?;
+    drop(file);
+
+    fs::rename(&temp_path, path)?;
+    if let Some(dir) = path.parent() {
+        #[cfg(unix)]
+        File::open(dir)?.sync_all()?;
+        #[cfg(not(unix))]
+        let _ = dir;
+    }
+    Ok(())
+}
+
+/// Reads a segment file written by `write_segment`, whatever codec it used
+pub fn read_segment(path: &Path) -> Result<Vec<LogEntry>, NexusError> {
+    let corrupt =
+        |reason: &str| NexusError::Corruption(format!("segment {}: {}", path.display(), reason));
+
+    let mut bytes = Vec::new();
+    File::open(path)?.read_to_end(&mut bytes)?;
+
+    if bytes.len() < HEADER_LEN || &bytes[0..4] != MAGIC {
+        return Err(corrupt("bad header"));
+    }
+
+    let version = u16::from_le_bytes([bytes[4], bytes[5]]);
+    if version != FORMAT_VERSION {
+        return Err(corrupt(&format!("unsupported format version {}", version)));
+    }
+
+    let codec = CompressionCodec::from_id(bytes[6])
+        .ok_or_else(|| corrupt(&format!("unknown codec {}", bytes[6])))?;
+    let crc = u32::from_le_bytes(bytes[7..11].try_into().unwrap());
+    let len = u64::from_le_bytes(bytes[11..19].try_into().unwrap());
+
+    let payload = bytes.split_off(HEADER_LEN);
+    if payload.len() as u64 != len {
+        return Err(corrupt("truncated payload"));
+    }
+    if crc32fast::hash(&payload) != crc {
+        return Err(corrupt("checksum mismatch"));
+    }
+
+    let reader = codec::decompressor(codec, std::io::Cursor::new(payload))?;
+    Ok(bincode::deserialize_from(reader)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::raft::log::LogEntryType;
+
+    fn entries() -> Vec<LogEntry> {
+        (1..=100)
+            .map(|index| LogEntry {
+                term: 1,
+                index,
+                entry_type: LogEntryType::Command,
+                data: b"set key value".to_vec(),
+                client: None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_segment_round_trip_with_each_codec() {
+        let dir = tempfile::tempdir().unwrap();
+
+        for codec in [
+            CompressionCodec::None,
+            CompressionCodec::Lz4,
+            CompressionCodec::Zstd,
+        ] {
+            let path = dir.path().join(format!("{:?}.seg", codec));
+            write_segment(&path, &entries(), codec).unwrap();
+
+            let read = read_segment(&path).unwrap();
+            assert_eq!(read.len(), 100);
+            assert_eq!(read[99].index, 100);
+            assert_eq!(read[0].data, b"set key value");
+        }
+
+        let plain = fs::metadata(dir.path().join("None.seg")).unwrap().len();
+        let zstd = fs::metadata(dir.path().join("Zstd.seg")).unwrap().len();
+        assert!(zstd < plain / 4);
+    }
+
+    #[test]
+    fn test_sealed_segments_reload_without_truncated_entries() {
+        let dir = tempfile::tempdir().unwrap();
+        let segments = LogSegments::new(dir.path()).with_codec(CompressionCodec::Lz4);
+
+        let all = entries();
+        segments.seal(&all[0..10]).unwrap();
+        segments.seal(&all[10..20]).unwrap();
+
+        // Entries from index 6 on were replaced by a new leader
+        let mut replaced = all[5..8].to_vec();
+        for entry in &mut replaced {
+            entry.term = 2;
+        }
+        segments.seal(&replaced).unwrap();
+
+        let loaded = segments.load().unwrap();
+        let terms: Vec<(u64, u64)> = loaded.iter().map(|e| (e.index, e.term)).collect();
+        let expected: Vec<(u64, u64)> = (1..=8).map(|i| (i, if i > 5 { 2 } else { 1 })).collect();
+        assert_eq!(terms, expected);
+        assert_eq!(segments.list().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_corrupt_segment_is_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("00001.seg");
+        write_segment(&path, &entries(), CompressionCodec::Lz4).unwrap();
+
+        let mut bytes = fs::read(&path).unwrap();
+        let last = bytes.len() - 1;
+        bytes[last] ^= 0xff;
+        fs::write(&path, bytes).unwrap();
+
+        assert!(matches!(
+            read_segment(&path),
+            Err(NexusError::Corruption(_))
+        ));
+    }
+}
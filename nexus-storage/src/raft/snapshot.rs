@@ -1,80 +1,757 @@
-use std::fs::{self, File};
-use std::io::{Read, Write};
-use std::path::PathBuf;
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
 
-use crate::raft::state_machine::KvCommand;
+use crate::raft::codec::{self, Compressor};
+use crate::raft::rpc::InstallSnapshotRequest;
 use nexus_common::error::NexusError;
+use nexus_common::types::{ClusterConfig, CompressionCodec, NodeId, Term};
 use serde::{Deserialize, Serialize};
+use tracing::warn;
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
-pub struct RaftSnapshot {
+/// Identifies snapshot files ("NeXus SNapshot")
+const MAGIC: &[u8; 4] = b"NXSN";
+/// On-disk format version; files with any other version are rejected
+const FORMAT_VERSION: u16 = 1;
+/// magic (4) + version (2) + codec (1) + crc32 (4) + data length (8) + metadata length (4)
+const HEADER_LEN: u64 = 23;
+/// Offset of the crc32 field, patched once all data has been streamed
+const CRC_OFFSET: u64 = 7;
+/// Buffer size used when streaming snapshot files
+const IO_BUFFER: usize = 64 * 1024;
+/// Number of snapshots kept on disk unless configured otherwise
+pub const DEFAULT_RETAIN: usize = 3;
+/// Distinguishes temp files of sinks created by this process
+static NEXT_SINK: AtomicU64 = AtomicU64::new(0);
+
+/// Client id -> sequence number of the last request applied for that client
+pub type ClientSessions = BTreeMap<String, u64>;
+
+/// Cluster membership in effect at a log position
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+pub struct Membership {
+    pub voters: Vec<NodeId>, // Nodes that vote and count towards quorum (incl. leader)
+    pub learners: Vec<NodeId>, // Nodes that replicate the log without voting
+}
+
+/// Describes which prefix of the log a snapshot replaces, plus the cluster state
+/// that the compacted entries established
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct SnapshotMeta {
     pub last_included_index: u64,
     pub last_included_term: u64,
-    pub state: Vec<u8>, // Serialized state machine data
+    pub membership: Option<Membership>, // `None`: keep the membership from `ClusterConfig`
+    pub sessions: ClientSessions,
+}
+
+impl SnapshotMeta {
+    /// Metadata for a log position with no membership or session state
+    pub fn at(last_included_index: u64, last_included_term: u64) -> Self {
+        Self {
+            last_included_index,
+            last_included_term,
+            membership: None,
+            sessions: ClientSessions::new(),
+        }
+    }
+}
+
+/// Stream of snapshot state data, as returned by `SnapshotStorage::open`
+pub type SnapshotReader = Box<dyn Read + Send>;
+
+/// Destination a snapshot is streamed into. Data only becomes visible to readers
+/// once `finish` succeeds; dropping the sink abandons the snapshot.
+pub trait SnapshotSink: Write + Send {
+    fn finish(self: Box<Self>) -> Result<(), NexusError>;
 }
 
 /// Defines the behavior for any snapshot storage backend.
-pub trait SnapshotStorage {
-    fn save(&self, snapshot: &RaftSnapshot) -> Result<(), NexusError>;
-    fn load(&self) -> Result<Option<RaftSnapshot>, NexusError>;
+pub trait SnapshotStorage: Send + Sync {
+    /// Starts a new snapshot whose state will be streamed into the returned sink
+    fn create(&self, meta: SnapshotMeta) -> Result<Box<dyn SnapshotSink>, NexusError>;
+
+    /// Opens the newest valid snapshot for streaming reads
+    fn open(&self) -> Result<Option<(SnapshotMeta, SnapshotReader)>, NexusError>;
+
+    /// Opens the snapshot described by `meta`, if it is stored and valid
+    fn open_snapshot(&self, meta: &SnapshotMeta) -> Result<Option<SnapshotReader>, NexusError>;
 }
 
-/// Saves snapshots as a binary file.
+/// Saves snapshots as checksummed binary files in a directory.
+///
+/// Each snapshot is streamed to a temporary file of its own, fsynced and renamed into
+/// place, so a crash mid-write never damages an existing snapshot. The newest `retain` snapshots are
+/// kept; `open` falls back to an older one if the newest fails verification.
 pub struct FileSnapshotStorage {
-    pub path: PathBuf,
+    pub dir: PathBuf,
+    pub retain: usize,           // Number of snapshots kept on disk (at least 1)
+    pub codec: CompressionCodec, // Compression for newly written snapshots
 }
 
 impl FileSnapshotStorage {
-    pub fn new(path: impl Into<PathBuf>) -> Self {
-        Self { path: path.into() }
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self {
+            dir: dir.into(),
+            retain: DEFAULT_RETAIN,
+            codec: CompressionCodec::None,
+        }
+    }
+
+    /// Storage compressing new snapshots with the cluster's configured codec
+    pub fn from_config(dir: impl Into<PathBuf>, config: &ClusterConfig) -> Self {
+        Self::new(dir).with_codec(config.compression)
+    }
+
+    /// Compress new snapshots with `codec` (usually `ClusterConfig::compression`).
+    /// Existing files keep the codec recorded in their header.
+    pub fn with_codec(mut self, codec: CompressionCodec) -> Self {
+        self.codec = codec;
+        self
+    }
+
+    /// Keep the newest `retain` snapshots instead of `DEFAULT_RETAIN`
+    pub fn with_retention(mut self, retain: usize) -> Self {
+        self.retain = retain.max(1);
+        self
+    }
+
+    /// File name for a snapshot; zero padding makes names sort by index, then term
+    fn file_name(meta: &SnapshotMeta) -> String {
+        format!(
+            "snapshot-{:020}-{:020}.snap",
+            meta.last_included_index, meta.last_included_term
+        )
+    }
+
+    /// Lists complete snapshot files, newest first
+    pub fn list(&self) -> Result<Vec<PathBuf>, NexusError> {
+        if !self.dir.exists() {
+            return Ok(Vec::new());
+        }
+
+        let mut files: Vec<PathBuf> = fs::read_dir(&self.dir)?
+            .filter_map(|entry| entry.ok().map(|e| e.path()))
+            .filter(|path| {
+                path.file_name()
+                    .and_then(|n| n.to_str())
+                    .is_some_and(|n| n.starts_with("snapshot-") && n.ends_with(".snap"))
+            })
+            .collect();
+        files.sort();
+        files.reverse();
+        Ok(files)
+    }
+
+    /// Temp file name for a sink: `{name}.{pid}-{n}.tmp`, unique among live sinks
+    fn temp_name(name: &str) -> String {
+        let n = NEXT_SINK.fetch_add(1, Ordering::Relaxed);
+        format!("{}.{}-{}.tmp", name, std::process::id(), n)
+    }
+
+    /// Removes snapshots beyond the retention limit. Temp files are left to their sinks.
+    fn prune(&self) -> Result<(), NexusError> {
+        for old in self.list()?.into_iter().skip(self.retain) {
+            fs::remove_file(old)?;
+        }
+        Ok(())
+    }
+
+    /// Removes temp files left behind by earlier processes that crashed mid-write.
+    /// Temp files of this process belong to sinks that may still be writing.
+    fn remove_stale_temp_files(&self) -> Result<(), NexusError> {
+        let own = format!(".{}-", std::process::id());
+        for entry in fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+            let stale = path
+                .file_name()
+                .and_then(|n| n.to_str())
+                .is_some_and(|n| n.ends_with(".tmp") && !n.contains(&own));
+            if stale {
+                fs::remove_file(path)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Verifies a snapshot file in one streaming pass and returns its metadata, codec,
+    /// the offset where state data starts and the stored data length
+    fn verify(path: &Path) -> Result<StoredSnapshot, NexusError> {
+        let corrupt = |reason: &str| {
+            NexusError::Corruption(format!("snapshot {}: {}", path.display(), reason))
+        };
+
+        let mut reader = BufReader::with_capacity(IO_BUFFER, File::open(path)?);
+        let mut prefix = [0u8; 6];
+        reader
+            .read_exact(&mut prefix)
+            .map_err(|_| corrupt("bad header"))?;
+
+        if &prefix[0..4] != MAGIC {
+            return Err(corrupt("bad header"));
+        }
+
+        let version = u16::from_le_bytes([prefix[4], prefix[5]]);
+        if version != FORMAT_VERSION {
+            return Err(corrupt(&format!("unsupported format version {}", version)));
+        }
+
+        let mut id = [0u8; 1];
+        reader
+            .read_exact(&mut id)
+            .map_err(|_| corrupt("bad header"))?;
+        let codec = CompressionCodec::from_id(id[0])
+            .ok_or_else(|| corrupt(&format!("unknown codec {}", id[0])))?;
+
+        let mut header = [0u8; 16];
+        reader
+            .read_exact(&mut header)
+            .map_err(|_| corrupt("bad header"))?;
+        let crc = u32::from_le_bytes(header[0..4].try_into().unwrap());
+        let data_len = u64::from_le_bytes(header[4..12].try_into().unwrap());
+        let meta_len = u32::from_le_bytes(header[12..16].try_into().unwrap());
+
+        let mut hasher = crc32fast::Hasher::new();
+        let mut meta = vec![0u8; meta_len as usize];
+        reader
+            .read_exact(&mut meta)
+            .map_err(|_| corrupt("truncated metadata"))?;
+        hasher.update(&meta);
+
+        let mut remaining = data_len;
+        let mut buf = vec![0u8; IO_BUFFER];
+        while remaining > 0 {
+            let want = remaining.min(buf.len() as u64) as usize;
+            let read = reader.read(&mut buf[..want])?;
+            if read == 0 {
+                return Err(corrupt("truncated data"));
+            }
+            hasher.update(&buf[..read]);
+            remaining -= read as u64;
+        }
+
+        if reader.read(&mut [0u8; 1])? != 0 {
+            return Err(corrupt("trailing bytes"));
+        }
+        if hasher.finalize() != crc {
+            return Err(corrupt("checksum mismatch"));
+        }
+
+        let meta = bincode::deserialize(&meta)?;
+
+        Ok(StoredSnapshot {
+            meta,
+            codec,
+            data_offset: HEADER_LEN + meta_len as u64,
+            data_len,
+        })
+    }
+
+    /// Streams the (decompressed) state data of a verified snapshot file
+    fn data_reader(path: &Path, stored: &StoredSnapshot) -> Result<SnapshotReader, NexusError> {
+        let mut file = File::open(path)?;
+        file.seek(SeekFrom::Start(stored.data_offset))?;
+        let reader = BufReader::with_capacity(IO_BUFFER, file.take(stored.data_len));
+        Ok(codec::decompressor(stored.codec, reader)?)
+    }
+}
+
+/// Layout of a verified snapshot file
+struct StoredSnapshot {
+    meta: SnapshotMeta,
+    codec: CompressionCodec,
+    data_offset: u64, // Where (possibly compressed) state data starts
+    data_len: u64,    // Stored length of the state data
+}
+
+/// Flushes directory metadata so a completed rename survives a crash
+fn sync_dir(dir: &Path) -> Result<(), NexusError> {
+    #[cfg(unix)]
+    File::open(dir)?.sync_all()?;
+    #[cfg(not(unix))]
+    let _ = dir;
+    Ok(())
+}
+
+/// Checksums and counts the bytes that actually reach the file
+struct ChecksumWriter {
+    inner: BufWriter<File>,
+    hasher: crc32fast::Hasher,
+    written: u64,
+}
+
+impl Write for ChecksumWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.hasher.update(&buf[..written]);
+        self.written += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Streams snapshot data through the codec into a temp file, which is removed if the
+/// sink is dropped before `finish`
+struct FileSnapshotSink {
+    writer: Option<Compressor<ChecksumWriter>>, // Taken by `finish`
+    temp_path: PathBuf,
+    final_path: PathBuf,
+    dir: PathBuf,
+    retain: usize,
+}
+
+impl Write for FileSnapshotSink {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.writer.as_mut().map_or(Ok(0), |w| w.write(buf))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.as_mut().map_or(Ok(()), |w| w.flush())
+    }
+}
+
+impl Drop for FileSnapshotSink {
+    fn drop(&mut self) {
+        // Gone already once `finish` renamed it into place
+        let _ = fs::remove_file(&self.temp_path);
+    }
+}
+
+impl SnapshotSink for FileSnapshotSink {
+    fn finish(mut self: Box<Self>) -> Result<(), NexusError> {
+        let writer = self.writer.take().expect("sink is finished only once");
+        let checksum = writer.finish()?;
+        let mut file = checksum
+            .inner
+            .into_inner()
+            .map_err(|err| NexusError::Io(err.into_error()))?;
+
+        // Patch the checksum and length now that all data has been written
+        file.seek(SeekFrom::Start(CRC_OFFSET))?;
+        file.write_all(&checksum.hasher.finalize().to_le_bytes())?;
+        file.write_all(&checksum.written.to_le_bytes())?;
+        file.sync_all()?;
+        drop(file);
+
+        fs::rename(&self.temp_path, &self.final_path)?;
+        sync_dir(&self.dir)?;
+
+        FileSnapshotStorage::new(self.dir.clone())
+            .with_retention(self.retain)
+            .prune()
     }
 }
 
 impl SnapshotStorage for FileSnapshotStorage {
-    fn save(&self, snapshot: &RaftSnapshot) -> Result<(), NexusError> {
-        let encoded = bincode::serialize(snapshot)?;
-        let mut file = File::create(&self.path)?;
-        file.write_all(&encoded)?;
-        Ok(())
+    fn create(&self, meta: SnapshotMeta) -> Result<Box<dyn SnapshotSink>, NexusError> {
+        fs::create_dir_all(&self.dir)?;
+
+        let name = Self::file_name(&meta);
+        let final_path = self.dir.join(&name);
+        let temp_path = self.dir.join(Self::temp_name(&name));
+
+        let file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&temp_path)?;
+        let mut writer = BufWriter::with_capacity(IO_BUFFER, file);
+
+        // crc32 and data length are placeholders until `finish`
+        let meta = bincode::serialize(&meta)?;
+        writer.write_all(MAGIC)?;
+        writer.write_all(&FORMAT_VERSION.to_le_bytes())?;
+        writer.write_all(&[self.codec.id()])?;
+        writer.write_all(&[0u8; 12])?;
+        writer.write_all(&(meta.len() as u32).to_le_bytes())?;
+        writer.write_all(&meta)?;
+
+        let mut hasher = crc32fast::Hasher::new();
+        hasher.update(&meta);
+        let checksum = ChecksumWriter {
+            inner: writer,
+            hasher,
+            written: 0,
+        };
+
+        Ok(Box::new(FileSnapshotSink {
+            writer: Some(Compressor::new(self.codec, checksum)?),
+            temp_path,
+            final_path,
+            dir: self.dir.clone(),
+            retain: self.retain,
+        }))
+    }
+
+    fn open(&self) -> Result<Option<(SnapshotMeta, SnapshotReader)>, NexusError> {
+        let files = self.list()?;
+        if files.is_empty() {
+            return Ok(None);
+        }
+        self.remove_stale_temp_files()?;
+
+        for path in &files {
+            match Self::verify(path) {
+                Ok(stored) => {
+                    let reader = Self::data_reader(path, &stored)?;
+                    return Ok(Some((stored.meta, reader)));
+                }
+                Err(err) => warn!(path = %path.display(), %err, "skipping unreadable snapshot"),
+            }
+        }
+
+        Err(NexusError::Corruption(format!(
+            "none of the {} snapshots in {} could be read",
+            files.len(),
+            self.dir.display()
+        )))
     }
 
-    fn load(&self) -> Result<Option<RaftSnapshot>, NexusError> {
-        if !self.path.exists() {
+    fn open_snapshot(&self, meta: &SnapshotMeta) -> Result<Option<SnapshotReader>, NexusError> {
+        let path = self.dir.join(Self::file_name(meta));
+        if !path.exists() {
             return Ok(None);
         }
 
-        let mut file = File::open(&self.path)?;
-        let mut bytes = Vec::new();
-        file.read_to_end(&mut bytes)?;
+        match Self::verify(&path) {
+            Ok(stored) if stored.meta == *meta => Ok(Some(Self::data_reader(&path, &stored)?)),
+            Ok(_) => Ok(None),
+            Err(err) => {
+                warn!(path = %path.display(), %err, "received snapshot is unreadable");
+                Ok(None)
+            }
+        }
+    }
+}
+
+/// Splits a snapshot stream into InstallSnapshot requests of at most `chunk_size`
+/// bytes, reading only one chunk at a time
+pub struct SnapshotChunks<R: Read> {
+    reader: R,
+    meta: SnapshotMeta,
+    term: Term,
+    leader_id: NodeId,
+    chunk_size: usize,
+    offset: u64,
+    done: bool,
+}
+
+impl<R: Read> SnapshotChunks<R> {
+    pub fn new(
+        reader: R,
+        meta: SnapshotMeta,
+        term: Term,
+        leader_id: NodeId,
+        chunk_size: usize,
+    ) -> Self {
+        Self {
+            reader,
+            meta,
+            term,
+            leader_id,
+            chunk_size: chunk_size.max(1),
+            offset: 0,
+            done: false,
+        }
+    }
+}
+
+impl<R: Read> Iterator for SnapshotChunks<R> {
+    type Item = Result<InstallSnapshotRequest, NexusError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+
+        let mut data = Vec::with_capacity(self.chunk_size);
+        if let Err(err) = (&mut self.reader)
+            .take(self.chunk_size as u64)
+            .read_to_end(&mut data)
+        {
+            self.done = true;
+            return Some(Err(err.into()));
+        }
+
+        // A short read means the stream is exhausted
+        let done = data.len() < self.chunk_size;
+        let request = InstallSnapshotRequest {
+            term: self.term,
+            leader_id: self.leader_id.clone(),
+            meta: self.meta.clone(),
+            offset: self.offset,
+            data,
+            done,
+        };
 
-        let snapshot = bincode::deserialize(&bytes)?;
-        Ok(Some(snapshot))
+        self.offset += request.data.len() as u64;
+        self.done = done;
+        Some(Ok(request))
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::raft::state_machine::KvCommand;
+
+    fn meta(index: u64) -> SnapshotMeta {
+        SnapshotMeta::at(index, 3)
+    }
+
+    fn save(store: &FileSnapshotStorage, index: u64, state: &[u8]) {
+        let mut sink = store.create(meta(index)).unwrap();
+        for chunk in state.chunks(7) {
+            sink.write_all(chunk).unwrap();
+        }
+        sink.finish().unwrap();
+    }
+
+    fn load(store: &FileSnapshotStorage) -> (SnapshotMeta, Vec<u8>) {
+        let (meta, mut reader) = store.open().unwrap().unwrap();
+        let mut state = Vec::new();
+        reader.read_to_end(&mut state).unwrap();
+        (meta, state)
+    }
 
     #[test]
     fn test_snapshot_save_and_load() {
-        let temp_path = PathBuf::from("test_snapshot.bin");
-        let store = FileSnapshotStorage::new(&temp_path);
+        let dir = tempfile::tempdir().unwrap();
+        let store = FileSnapshotStorage::new(dir.path());
 
-        let snap = RaftSnapshot {
-            last_included_index: 42,
-            last_included_term: 3,
-            state: bincode::serialize(&KvCommand::Set("x".into(), "y".into())).unwrap(),
-        };
+        let state = bincode::serialize(&KvCommand::Set("x".into(), "y".into())).unwrap();
+        save(&store, 42, &state);
+
+        let (loaded_meta, loaded_state) = load(&store);
+        assert_eq!(loaded_meta, meta(42));
+        assert_eq!(loaded_state, state);
+    }
+
+    #[test]
+    fn test_membership_and_sessions_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = FileSnapshotStorage::new(dir.path());
+
+        let mut full = meta(12);
+        full.membership = Some(Membership {
+            voters: vec!["node1".into(), "node2".into(), "node3".into()],
+            learners: vec!["node4".into()],
+        });
+        full.sessions.insert("client-a".into(), 17);
+
+        let mut sink = store.create(full.clone()).unwrap();
+        sink.write_all(b"state").unwrap();
+        sink.finish().unwrap();
+
+        assert_eq!(load(&store), (full, b"state".to_vec()));
+    }
+
+    #[test]
+    fn test_compressed_snapshots() {
+        let state: Vec<u8> = b"key=value;".iter().copied().cycle().take(50_000).collect();
 
-        store.save(&snap).expect("Failed to save snapshot");
-        let loaded = store.load().expect("Failed to load snapshot").unwrap();
+        for codec in [CompressionCodec::Lz4, CompressionCodec::Zstd] {
+            let dir = tempfile::tempdir().unwrap();
+            let store = FileSnapshotStorage::new(dir.path()).with_codec(codec);
+            save(&store, 7, &state);
 
-        assert_eq!(snap.last_included_index, loaded.last_included_index);
-        assert_eq!(snap.last_included_term, loaded.last_included_term);
-        assert_eq!(snap.state, loaded.state);
+            let size = fs::metadata(&store.list().unwrap()[0]).unwrap().len();
+            assert!(size < state.len() as u64 / 10, "{:?}", codec);
+            assert_eq!(load(&store), (meta(7), state.clone()));
+        }
+    }
+
+    #[test]
+    fn test_reads_snapshots_written_with_other_codecs() {
+        let dir = tempfile::tempdir().unwrap();
+        let zstd = FileSnapshotStorage::new(dir.path()).with_codec(CompressionCodec::Zstd);
+        save(&zstd, 5, b"zstd state");
+
+        // Newest snapshot is plain, the fallback is zstd: both must stay readable
+        let plain = FileSnapshotStorage::new(dir.path());
+        save(&plain, 10, b"plain state");
+        assert_eq!(load(&plain), (meta(10), b"plain state".to_vec()));
+
+        fs::remove_file(&plain.list().unwrap()[0]).unwrap();
+        assert_eq!(load(&plain), (meta(5), b"zstd state".to_vec()));
+    }
+
+    #[test]
+    fn test_rejects_unknown_format_version() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = FileSnapshotStorage::new(dir.path());
+        let mut sink = store.create(meta(4)).unwrap();
+        sink.write_all(b"state").unwrap();
+        sink.finish().unwrap();
+
+        let path = &store.list().unwrap()[0];
+        let mut file = fs::read(path).unwrap();
+        file[4..6].copy_from_slice(&2u16.to_le_bytes());
+        fs::write(path, file).unwrap();
+
+        let err = store
+            .open()
+            .err()
+            .expect("unknown version must be rejected");
+        assert!(matches!(err, NexusError::Corruption(_)), "{err:?}");
+    }
+
+    #[test]
+    fn test_load_from_empty_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = FileSnapshotStorage::new(dir.path().join("missing"));
+
+        assert!(store.open().unwrap().is_none());
+    }
+
+    #[test]
+    fn test_abandoned_sink_is_not_visible() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = FileSnapshotStorage::new(dir.path());
+        save(&store, 5, b"old state");
+
+        let mut sink = store.create(meta(10)).unwrap();
+        sink.write_all(b"half written").unwrap();
+        drop(sink);
+
+        assert_eq!(load(&store).0, meta(5));
+    }
+
+    #[test]
+    fn test_keeps_only_newest_snapshots() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = FileSnapshotStorage::new(dir.path()).with_retention(2);
+
+        for index in [5, 10, 15] {
+            save(&store, index, b"state");
+        }
+
+        let files = store.list().unwrap();
+        assert_eq!(files.len(), 2);
+        assert_eq!(load(&store).0, meta(15));
+    }
+
+    #[test]
+    fn test_falls_back_when_newest_is_corrupt() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = FileSnapshotStorage::new(dir.path());
+        save(&store, 5, b"older state");
+        save(&store, 10, b"newer state");
+
+        // Flip a byte in the newest snapshot's data
+        let newest = store.list().unwrap()[0].clone();
+        let mut bytes = fs::read(&newest).unwrap();
+        let last = bytes.len() - 1;
+        bytes[last] ^= 0xff;
+        fs::write(&newest, bytes).unwrap();
+
+        assert_eq!(load(&store), (meta(5), b"older state".to_vec()));
+    }
+
+    #[test]
+    fn test_all_corrupt_is_an_error() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = FileSnapshotStorage::new(dir.path());
+        save(&store, 5, b"state");
+
+        let path = store.list().unwrap()[0].clone();
+        fs::write(&path, b"garbage").unwrap();
+
+        assert!(matches!(store.open(), Err(NexusError::Corruption(_))));
+    }
+
+    #[test]
+    fn test_leftover_temp_file_is_ignored() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = FileSnapshotStorage::new(dir.path());
+        save(&store, 5, b"state");
+
+        // A crash mid-write leaves only a partial temp file behind
+        let leftover = dir.path().join("snapshot-x.snap.0-0.tmp");
+        fs::write(&leftover, b"partial").unwrap();
+
+        assert_eq!(load(&store).0, meta(5));
+        assert!(!leftover.exists());
+    }
+
+    #[test]
+    fn test_concurrent_sinks_keep_their_temp_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = FileSnapshotStorage::new(dir.path());
+
+        // Two sinks for the same snapshot, one finishing while the other still writes
+        let mut slow = store.create(meta(10)).unwrap();
+        slow.write_all(b"slow ").unwrap();
+        let mut fast = store.create(meta(10)).unwrap();
+        fast.write_all(b"fast").unwrap();
+        fast.finish().unwrap();
+        save(&store, 11, b"newer");
+
+        slow.write_all(b"state").unwrap();
+        slow.finish().unwrap();
+        let newest = store.list().unwrap()[1].clone();
+        assert_eq!(FileSnapshotStorage::verify(&newest).unwrap().meta, meta(10));
+
+        // Abandoned sinks clean up after themselves
+        drop(store.create(meta(12)).unwrap());
+        let temp_files = fs::read_dir(dir.path())
+            .unwrap()
+            .filter(|e| e.as_ref().unwrap().path().extension().unwrap() == "tmp")
+            .count();
+        assert_eq!(temp_files, 0);
+    }
+
+    #[test]
+    fn test_open_specific_snapshot() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = FileSnapshotStorage::new(dir.path());
+        save(&store, 5, b"older state");
+        save(&store, 10, b"newer state");
+
+        let mut state = Vec::new();
+        let mut reader = store.open_snapshot(&meta(5)).unwrap().unwrap();
+        reader.read_to_end(&mut state).unwrap();
+        assert_eq!(state, b"older state");
+        assert!(store.open_snapshot(&meta(7)).unwrap().is_none());
+
+        // A file whose metadata differs from what was asked for is not returned
+        let mut other = meta(10);
+        other.sessions.insert("client-a".into(), 1);
+        assert!(store.open_snapshot(&other).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_snapshot_chunks() {
+        let state: Vec<u8> = (0..25).collect();
+        let chunks: Vec<InstallSnapshotRequest> =
+            SnapshotChunks::new(&state[..], meta(9), 4, "leader".into(), 10)
+                .collect::<Result<_, _>>()
+                .unwrap();
+        let data: Vec<u8> = chunks.iter().flat_map(|c| c.data.clone()).collect();
+
+        assert_eq!(chunks.len(), 3);
+        assert_eq!(
+            chunks.iter().map(|c| c.offset).collect::<Vec<_>>(),
+            vec![0, 10, 20]
+        );
+        assert!(chunks[2].done && !chunks[1].done);
+        assert_eq!(chunks[0].meta.last_included_index, 9);
+        assert_eq!(data, state);
+    }
+
+    #[test]
+    fn test_exact_multiple_ends_with_empty_chunk() {
+        let state = [1u8; 20];
+        let chunks: Vec<InstallSnapshotRequest> =
+            SnapshotChunks::new(&state[..], meta(9), 4, "leader".into(), 10)
+                .collect::<Result<_, _>>()
+                .unwrap();
 
-        // cleanup
-        let _ = fs::remove_file(temp_path);
+        assert_eq!(chunks.len(), 3);
+        assert!(chunks[2].data.is_empty() && chunks[2].done);
     }
 }
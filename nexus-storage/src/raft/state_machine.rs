@@ -1,6 +1,11 @@
+use crate::raft::kv_backend::{self, Bounds, KvBackend};
+use crate::raft::watch::{Delivery, WatchEvent, WatchFilter, WatchResponse, WatchStream, Watcher};
+use nexus_common::error::NexusError;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::BTreeSet;
+use std::convert::Infallible;
 use std::error::Error;
+use std::io::{Read, Write};
 
 /// Trait for any Raft-compatible state machine.
 /// This allows pluggable logic for different types of services (e.g., key-value store, DB, etc.)
@@ -13,23 +18,262 @@ pub trait StateMachine: Send + Sync {
     /// Applies a command and returns a response
     fn apply(&mut self, command: Self::Command) -> Self::Response;
 
-    /// Produces a binary snapshot of the current state
-    fn snapshot(&self) -> Vec<u8>;
+    /// Applies the command stored at log `index`. Durable state machines record the
+    /// index atomically with the command's effects. An error means the command could
+    /// not be applied at all, and the replica must stop applying entries.
+    fn apply_entry(
+        &mut self,
+        index: u64,
+        command: Self::Command,
+    ) -> Result<Self::Response, NexusError> {
+        let _ = index;
+        Ok(self.apply(command))
+    }
+
+    /// Last log index whose effects survive a restart; `None` for in-memory machines,
+    /// which must be rebuilt from a snapshot and the log
+    fn applied_index(&self) -> Option<u64> {
+        None
+    }
+
+    /// Captures a point-in-time copy of the state. This must be cheap, as it runs on
+    /// the apply path; serializing the copy can then happen on another thread.
+    fn freeze(&self) -> Result<Box<dyn FrozenState>, NexusError>;
+
+    /// Streams a binary snapshot of the current state into `out`
+    fn snapshot(&self, out: &mut dyn Write) -> Result<(), Box<dyn Error>> {
+        self.freeze()?.write_to(out)
+    }
+
+    /// Restores state from a binary snapshot stream
+    fn restore(&mut self, input: &mut dyn Read) -> Result<(), Box<dyn Error>>;
+
+    /// Restores from a snapshot that covers the log up to `index`
+    fn restore_at(&mut self, index: u64, input: &mut dyn Read) -> Result<(), Box<dyn Error>> {
+        let _ = index;
+        self.restore(input)
+    }
+}
 
-    /// Restores state from a binary snapshot
-    fn restore(&mut self, snapshot: Vec<u8>) -> Result<(), Box<dyn Error>>;
+/// Immutable view of a state machine taken by `StateMachine::freeze`
+pub trait FrozenState: Send {
+    /// Serializes the captured state in the format `StateMachine::restore` reads
+    fn write_to(&self, out: &mut dyn Write) -> Result<(), Box<dyn Error>>;
 }
 
 //
 // Example Implementation: In-Memory Key-Value Store
 //
 
-/// Commands that the key-value store can handle
+/// Commands that the key-value store can handle.
+/// Scans return keys in ascending order; a `limit` of 0 means no limit.
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 pub enum KvCommand {
     Set(String, String),
     Get(String),
     Delete(String),
+    /// Keys in `[start, end)`; pass the returned `next` cursor as `start` for the next page
+    Range {
+        start: String,
+        end: String,
+        limit: usize,
+    },
+    /// Keys beginning with `prefix`, from the `cursor` key onwards if given
+    Prefix {
+        prefix: String,
+        cursor: Option<String>,
+        limit: usize,
+    },
+    DeleteRange {
+        start: String,
+        end: String,
+    },
+    DeletePrefix(String),
+    /// Returns the value together with its revision metadata
+    GetEntry(String),
+    /// Sets the key only if its current state matches `expected`
+    CompareAndSwap {
+        key: String,
+        expected: Expected,
+        value: String,
+    },
+    /// Sets the key only if it does not exist yet
+    SetIfAbsent(String, String),
+    /// Runs `success` if every condition holds, `failure` otherwise, atomically as one
+    /// revision. A branch may write each key at most once (`DuplicateKey` otherwise).
+    Txn {
+        compare: Vec<Condition>,
+        success: Vec<Op>,
+        failure: Vec<Op>,
+    },
+    /// Creates a lease expiring `ttl_ms` after `now_ms`.
+    /// All lease times are stamped by the leader when proposing (`RaftNode::propose`),
+    /// never read from a local clock.
+    LeaseGrant {
+        id: u64,
+        ttl_ms: u64,
+        now_ms: u64,
+    },
+    /// Pushes a live lease's expiry to `now_ms + ttl`
+    LeaseKeepAlive {
+        id: u64,
+        now_ms: u64,
+    },
+    /// Removes a lease and deletes every key attached to it
+    LeaseRevoke(u64),
+    /// Sets a key and attaches it to a lease; a plain `Set` detaches it again. The lease
+    /// must be live as of the latest time stamped on a lease command.
+    PutWithLease {
+        key: String,
+        value: String,
+        lease: u64,
+    },
+    /// Revokes every lease that has expired at `now_ms`; proposed periodically by the leader
+    ExpireLeases {
+        now_ms: u64,
+    },
+    /// Returns the key's entry as of `revision`
+    GetAt(String, u64),
+    /// Like `Range`, but reads the keys as of `revision`
+    RangeAt {
+        start: String,
+        end: String,
+        limit: usize,
+        revision: u64,
+    },
+    /// Drops history older than the revision; reads before it fail with `Compacted`
+    Compact(u64),
+}
+
+/// A lease and the keys attached to it
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct Lease {
+    pub id: u64,
+    pub ttl_ms: u64,
+    pub expires_at_ms: u64,
+    pub keys: im::OrdSet<String>,
+}
+
+/// A comparison evaluated against a key's current state inside a `Txn`.
+/// Missing keys have version and revisions 0 and fail every value comparison.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct Condition {
+    pub key: String,
+    pub op: CompareOp,
+    pub target: CompareTarget,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub enum CompareOp {
+    Equal,
+    NotEqual,
+    Greater,
+    Less,
+}
+
+/// What a `Condition` compares the key against
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub enum CompareTarget {
+    Value(String),
+    Version(u64),
+    CreateRevision(u64),
+    ModRevision(u64),
+    Exists(bool),
+}
+
+/// An operation executed inside a `Txn`
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub enum Op {
+    Put(String, String),
+    Get(String),
+    Delete(String),
+    /// Put attached to a lease; the whole `Txn` fails with `LeaseNotFound` if it is missing
+    PutWithLease {
+        key: String,
+        value: String,
+        lease: u64,
+    },
+}
+
+impl Op {
+    /// Key the op writes to, if it is a write
+    fn written_key(&self) -> Option<&str> {
+        match self {
+            Op::Put(key, _) | Op::Delete(key) | Op::PutWithLease { key, .. } => Some(key),
+            Op::Get(_) => None,
+        }
+    }
+}
+
+/// First key written more than once within one branch of a `Txn`, if any
+pub(crate) fn duplicate_write(ops: &[Op]) -> Option<&str> {
+    let mut written = std::collections::HashSet::new();
+    ops.iter()
+        .filter_map(Op::written_key)
+        .find(|key| !written.insert(*key))
+}
+
+/// Result of one `Op`, in the same position as the op
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub enum OpResult {
+    Put { mod_revision: u64 },
+    Get(Option<KvEntry>),
+    Delete { deleted: bool },
+}
+
+/// Precondition for a compare-and-swap
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub enum Expected {
+    Value(String), // Current value must equal this
+    Version(u64),  // Current per-key version must equal this (0 = key absent)
+}
+
+impl Condition {
+    /// Evaluates the condition against the key's current entry
+    pub fn holds(&self, entry: Option<&KvEntry>) -> bool {
+        use std::cmp::Ordering;
+
+        let ordering = match &self.target {
+            CompareTarget::Value(value) => match entry {
+                Some(e) => e.value.as_str().cmp(value),
+                None => return false,
+            },
+            CompareTarget::Version(v) => entry.map(|e| e.version).unwrap_or(0).cmp(v),
+            CompareTarget::CreateRevision(r) => {
+                entry.map(|e| e.create_revision).unwrap_or(0).cmp(r)
+            }
+            CompareTarget::ModRevision(r) => entry.map(|e| e.mod_revision).unwrap_or(0).cmp(r),
+            CompareTarget::Exists(exists) => entry.is_some().cmp(exists),
+        };
+
+        match self.op {
+            CompareOp::Equal => ordering == Ordering::Equal,
+            CompareOp::NotEqual => ordering != Ordering::Equal,
+            CompareOp::Greater => ordering == Ordering::Greater,
+            CompareOp::Less => ordering == Ordering::Less,
+        }
+    }
+}
+
+impl Expected {
+    /// Whether the key's current entry satisfies the precondition
+    pub fn matches(&self, entry: Option<&KvEntry>) -> bool {
+        match self {
+            Expected::Value(value) => entry.is_some_and(|e| e.value == *value),
+            Expected::Version(version) => entry.map(|e| e.version).unwrap_or(0) == *version,
+        }
+    }
+}
+
+/// A stored value with its revision metadata.
+/// Revisions come from a store-wide counter bumped by every write command.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct KvEntry {
+    pub value: String,
+    pub create_revision: u64, // Revision that created the key
+    pub mod_revision: u64,    // Revision of the last write to the key
+    pub version: u64,         // Number of writes since the key was created
+    pub lease: Option<u64>,   // Lease the key is attached to, if any
 }
 
 /// Response type returned by the state machine
@@ -37,12 +281,316 @@ pub enum KvCommand {
 pub enum KvResponse {
     Value(Option<String>),
     Ack,
+    /// One page of a scan; `next` is the first key of the following page, if any
+    Entries {
+        entries: Vec<(String, String)>,
+        next: Option<String>,
+    },
+    /// Number of keys removed by a range or prefix delete
+    Deleted(u64),
+    Entry(Option<KvEntry>),
+    /// A write succeeded; carries the key's new mod revision
+    Written {
+        mod_revision: u64,
+    },
+    /// A conditional write's precondition did not hold; nothing was changed
+    ConditionFailed {
+        current: Option<KvEntry>,
+    },
+    /// Outcome of a `Txn`: which branch ran and the result of each of its ops
+    Txn {
+        succeeded: bool,
+        results: Vec<OpResult>,
+    },
+    /// A lease was granted or kept alive
+    Lease {
+        id: u64,
+        expires_at_ms: u64,
+    },
+    /// The lease does not exist or has already expired
+    LeaseNotFound(u64),
+    /// A lease with this id already exists
+    LeaseExists(u64),
+    /// Leases revoked by `ExpireLeases`
+    Expired(Vec<u64>),
+    /// A `Txn` branch writes this key more than once; nothing was changed
+    DuplicateKey(String),
+    /// The requested revision has been compacted; carries the compaction revision
+    Compacted(u64),
+    /// The requested revision has not been written yet; carries the current revision
+    FutureRevision(u64),
+}
+
+/// One state of a key in its history; `entry` is `None` where the key was deleted
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct KeyVersion {
+    pub revision: u64,
+    pub entry: Option<KvEntry>,
 }
 
-/// The in-memory key-value store with Raft StateMachine trait
+/// The in-memory key-value store with Raft StateMachine trait.
+/// Backed by an ordered persistent map: keys can be scanned in order, and snapshots
+/// share structure with the live state. Every write is also kept in a per-key history
+/// until it is compacted, so the store can be read as of an earlier revision.
 #[derive(Debug, Default)]
 pub struct KeyValueStore {
-    data: HashMap<String, String>,
+    data: im::OrdMap<String, KvEntry>,
+    revision: u64, // Revision of the last write command
+    leases: im::OrdMap<u64, Lease>,
+    history: im::OrdMap<String, Vec<KeyVersion>>, // Oldest version first
+    compacted: u64,                               // Reads before this revision are rejected
+    lease_clock_ms: u64, // Latest leader time carried by an applied lease command
+    watchers: Vec<Watcher>,
+    touched: BTreeSet<String>, // Keys written since watchers were last notified
+    notified: u64,             // Revision watchers were last notified at
+}
+
+impl KeyValueStore {
+    /// Revision of the last applied write
+    pub fn revision(&self) -> u64 {
+        self.revision
+    }
+
+    /// Revision the history was last compacted at
+    pub fn compacted_revision(&self) -> u64 {
+        self.compacted
+    }
+
+    /// Retained versions of a key, oldest first
+    pub fn history(&self, key: &str) -> impl Iterator<Item = &KeyVersion> {
+        self.history.get(key).into_iter().flatten()
+    }
+
+    /// Watches a key or prefix. Every revision from `start_revision` onwards (0 = the
+    /// next write) that changes a watched key is delivered as one `WatchResponse`, in
+    /// revision order. A watcher whose `capacity` buffered messages are not drained falls
+    /// behind and is caught up from the history later. Watches are local to this replica
+    /// and are not part of snapshots.
+    pub fn watch(
+        &mut self,
+        filter: WatchFilter,
+        start_revision: u64,
+        capacity: usize,
+    ) -> Result<WatchStream, NexusError> {
+        let start = if start_revision == 0 {
+            self.revision + 1
+        } else {
+            start_revision
+        };
+        if start <= self.compacted {
+            return Err(NexusError::Compacted(self.compacted));
+        }
+
+        let (mut watcher, stream) = Watcher::new(filter, start, capacity);
+        if self.catch_up(&mut watcher, None) {
+            self.watchers.push(watcher);
+        }
+        Ok(stream)
+    }
+
+    /// Delivers new revisions to every watcher. Watchers that are up to date only need
+    /// the keys touched since the last call; the rest are caught up from the history.
+    fn notify_watchers(&mut self, full: bool) {
+        let touched = std::mem::take(&mut self.touched);
+        let mut watchers = std::mem::take(&mut self.watchers);
+        watchers.retain_mut(|watcher| {
+            let synced = !full && watcher.next_revision > self.notified;
+            self.catch_up(watcher, synced.then_some(&touched))
+        });
+        self.watchers = watchers;
+        self.notified = self.revision;
+    }
+
+    /// Sends the watcher everything from its next revision, looking only at `touched`
+    /// keys if given. Returns false once the watch is over.
+    fn catch_up(&self, watcher: &mut Watcher, touched: Option<&BTreeSet<String>>) -> bool {
+        if watcher.next_revision <= self.compacted {
+            let err = NexusError::Compacted(self.compacted);
+            return matches!(watcher.send(Err(err)), Delivery::Full);
+        }
+
+        let keys: Vec<&String> = match (touched, &watcher.filter) {
+            (Some(touched), filter) => touched.iter().filter(|k| filter.matches(k)).collect(),
+            (None, WatchFilter::Key(key)) => vec![key],
+            (None, WatchFilter::Prefix(prefix)) => self
+                .history
+                .range(prefix.clone()..)
+                .map(|(k, _)| k)
+                .take_while(|k| k.starts_with(prefix.as_str()))
+                .collect(),
+        };
+
+        let mut changes: Vec<(&String, &KeyVersion)> = keys
+            .into_iter()
+            .filter_map(|key| Some((key, self.history.get(key)?)))
+            .flat_map(|(key, versions)| versions.iter().map(move |v| (key, v)))
+            .filter(|(_, v)| v.revision >= watcher.next_revision)
+            .collect();
+        changes.sort_by(|(ka, a), (kb, b)| (a.revision, ka).cmp(&(b.revision, kb)));
+
+        for batch in changes.chunk_by(|(_, a), (_, b)| a.revision == b.revision) {
+            let revision = batch[0].1.revision;
+            let events = batch
+                .iter()
+                .map(|(key, v)| match &v.entry {
+                    Some(entry) => WatchEvent::Put {
+                        key: key.to_string(),
+                        entry: entry.clone(),
+                    },
+                    None => WatchEvent::Delete {
+                        key: key.to_string(),
+                    },
+                })
+                .collect();
+
+            match watcher.send(Ok(WatchResponse { revision, events })) {
+                Delivery::Sent => watcher.next_revision = revision + 1,
+                Delivery::Full => return true,
+                Delivery::Closed => return false,
+            }
+        }
+
+        watcher.next_revision = watcher.next_revision.max(self.revision + 1);
+        true
+    }
+
+    /// Returns a lease by id
+    pub fn lease(&self, id: u64) -> Option<&Lease> {
+        self.leases.get(&id)
+    }
+}
+
+/// Point-in-time copy of a `KeyValueStore`. The current data is rebuilt from the
+/// history on restore, so only the history is written.
+struct FrozenKv {
+    revision: u64,
+    compacted: u64,
+    lease_clock_ms: u64,
+    leases: im::OrdMap<u64, Lease>,
+    history: im::OrdMap<String, Vec<KeyVersion>>,
+}
+
+impl FrozenState for FrozenKv {
+    fn write_to(&self, out: &mut dyn Write) -> Result<(), Box<dyn Error>> {
+        bincode::serialize_into(
+            out,
+            &(
+                self.revision,
+                self.compacted,
+                self.lease_clock_ms,
+                &self.leases,
+                &self.history,
+            ),
+        )?;
+        Ok(())
+    }
+}
+
+impl KvBackend for KeyValueStore {
+    type Error = Infallible;
+
+    fn revision(&self) -> u64 {
+        self.revision
+    }
+
+    fn set_revision(&mut self, revision: u64) {
+        self.revision = revision;
+    }
+
+    fn compacted(&self) -> u64 {
+        self.compacted
+    }
+
+    fn set_compacted(&mut self, revision: u64) {
+        self.compacted = revision;
+    }
+
+    fn lease_clock_ms(&self) -> u64 {
+        self.lease_clock_ms
+    }
+
+    fn set_lease_clock_ms(&mut self, now_ms: u64) {
+        self.lease_clock_ms = now_ms;
+    }
+
+    fn entry(&self, key: &str) -> Result<Option<KvEntry>, Infallible> {
+        Ok(self.data.get(key).cloned())
+    }
+
+    fn set_entry(&mut self, key: &str, entry: Option<KvEntry>) -> Result<(), Infallible> {
+        match entry {
+            Some(entry) => self.data.insert(key.to_string(), entry),
+            None => self.data.remove(key),
+        };
+        Ok(())
+    }
+
+    fn visit_entries(
+        &self,
+        bounds: Bounds,
+        visit: &mut dyn FnMut(&str, &KvEntry) -> bool,
+    ) -> Result<(), Infallible> {
+        for (key, entry) in self.data.range::<_, str>(bounds) {
+            if !visit(key, entry) {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    fn push_version(&mut self, key: &str, version: KeyVersion) -> Result<(), Infallible> {
+        let versions = self.history.entry(key.to_string()).or_default();
+        if versions
+            .last()
+            .is_some_and(|v| v.revision == version.revision)
+        {
+            versions.pop();
+        }
+        versions.push(version);
+        self.touched.insert(key.to_string());
+        Ok(())
+    }
+
+    fn visit_history(
+        &self,
+        bounds: Bounds,
+        visit: &mut dyn FnMut(&str, &[KeyVersion]) -> bool,
+    ) -> Result<(), Infallible> {
+        for (key, versions) in self.history.range::<_, str>(bounds) {
+            if !visit(key, versions) {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    fn drop_versions_before(&mut self, key: &str, revision: u64) -> Result<(), Infallible> {
+        if let Some(versions) = self.history.get_mut(key) {
+            versions.retain(|v| v.revision >= revision);
+            if versions.is_empty() {
+                self.history.remove(key);
+            }
+        }
+        Ok(())
+    }
+
+    fn lease(&self, id: u64) -> Result<Option<Lease>, Infallible> {
+        Ok(self.leases.get(&id).cloned())
+    }
+
+    fn save_lease(&mut self, lease: Lease) -> Result<(), Infallible> {
+        self.leases.insert(lease.id, lease);
+        Ok(())
+    }
+
+    fn remove_lease(&mut self, id: u64) -> Result<(), Infallible> {
+        self.leases.remove(&id);
+        Ok(())
+    }
+
+    fn leases(&self) -> Result<Vec<Lease>, Infallible> {
+        Ok(self.leases.values().cloned().collect())
+    }
 }
 
 impl StateMachine for KeyValueStore {
@@ -50,30 +598,43 @@ impl StateMachine for KeyValueStore {
     type Response = KvResponse;
 
     fn apply(&mut self, command: Self::Command) -> Self::Response {
-        match command {
-            KvCommand::Set(k, v) => {
-                self.data.insert(k, v);
-                KvResponse::Ack
-            }
-            KvCommand::Get(k) => KvResponse::Value(self.data.get(&k).cloned()),
-            KvCommand::Delete(k) => {
-                self.data.remove(&k);
-                KvResponse::Ack
-            }
-        }
+        let Ok(response) = kv_backend::execute(self, command);
+        self.notify_watchers(false);
+        response
     }
 
-    fn snapshot(&self) -> Vec<u8> {
-        bincode::serialize(&self.data).unwrap()
+    fn freeze(&self) -> Result<Box<dyn FrozenState>, NexusError> {
+        Ok(Box::new(FrozenKv {
+            revision: self.revision,
+            compacted: self.compacted,
+            lease_clock_ms: self.lease_clock_ms,
+            leases: self.leases.clone(),
+            history: self.history.clone(), // O(1): shares structure with `history`
+        }))
     }
 
-    fn restore(&mut self, snapshot: Vec<u8>) -> Result<(), Box<dyn Error>> {
-        self.data = bincode::deserialize(&snapshot)?;
+    fn restore(&mut self, input: &mut dyn Read) -> Result<(), Box<dyn Error>> {
+        (
+            self.revision,
+            self.compacted,
+            self.lease_clock_ms,
+            self.leases,
+            self.history,
+        ) = bincode::deserialize_from(input)?;
+        self.data = self
+            .history
+            .iter()
+            .filter_map(|(k, versions)| {
+                let entry = versions.last()?.entry.clone()?;
+                Some((k.clone(), entry))
+            })
+            .collect();
+        self.notify_watchers(true);
         Ok(())
     }
 
     fn get(&self, key: String) -> Option<String> {
-        self.data.get(&key).cloned()
+        self.data.get(&key).map(|e| e.value.clone())
     }
 }
 
@@ -90,7 +651,7 @@ mod tests {
 
         // Set a value
         let resp = kv.apply(KvCommand::Set("foo".into(), "bar".into()));
-        assert_eq!(resp, KvResponse::Ack);
+        assert_eq!(resp, KvResponse::Written { mod_revision: 1 });
 
         // Get it
         let resp = kv.apply(KvCommand::Get("foo".into()));
@@ -105,17 +666,705 @@ mod tests {
         assert_eq!(resp, KvResponse::Value(None));
     }
 
+    fn kv_with(keys: &[&str]) -> KeyValueStore {
+        let mut kv = KeyValueStore::default();
+        for key in keys {
+            kv.apply(KvCommand::Set(key.to_string(), key.to_uppercase()));
+        }
+        kv
+    }
+
+    fn keys(resp: &KvResponse) -> Vec<&str> {
+        match resp {
+            KvResponse::Entries { entries, .. } => {
+                entries.iter().map(|(k, _)| k.as_str()).collect()
+            }
+            other => panic!("unexpected response {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_range_scan_with_pagination() {
+        let mut kv = kv_with(&["a", "b", "c", "d", "e"]);
+
+        let page = kv.apply(KvCommand::Range {
+            start: "b".into(),
+            end: "e".into(),
+            limit: 2,
+        });
+        assert_eq!(keys(&page), vec!["b", "c"]);
+        let KvResponse::Entries { next, .. } = page else {
+            unreachable!()
+        };
+        assert_eq!(next, Some("d".into()));
+
+        let page = kv.apply(KvCommand::Range {
+            start: next.unwrap(),
+            end: "e".into(),
+            limit: 2,
+        });
+        assert_eq!(keys(&page), vec!["d"]);
+        assert!(matches!(page, KvResponse::Entries { next: None, .. }));
+
+        let empty = kv.apply(KvCommand::Range {
+            start: "e".into(),
+            end: "a".into(),
+            limit: 0,
+        });
+        assert!(keys(&empty).is_empty());
+    }
+
+    #[test]
+    fn test_prefix_scan_with_cursor() {
+        let mut kv = kv_with(&["stream/1", "stream/2", "stream/3", "streamx", "other"]);
+
+        let page = kv.apply(KvCommand::Prefix {
+            prefix: "stream/".into(),
+            cursor: None,
+            limit: 2,
+        });
+        assert_eq!(keys(&page), vec!["stream/1", "stream/2"]);
+        assert!(matches!(&page, KvResponse::Entries { next: Some(n), .. } if n == "stream/3"));
+
+        let page = kv.apply(KvCommand::Prefix {
+            prefix: "stream/".into(),
+            cursor: Some("stream/3".into()),
+            limit: 0,
+        });
+        assert_eq!(keys(&page), vec!["stream/3"]);
+        assert!(matches!(page, KvResponse::Entries { next: None, .. }));
+    }
+
+    #[test]
+    fn test_delete_prefix_and_range() {
+        let mut kv = kv_with(&["a/1", "a/2", "b/1", "c/1", "c/2"]);
+
+        assert_eq!(
+            kv.apply(KvCommand::DeletePrefix("a/".into())),
+            KvResponse::Deleted(2)
+        );
+        assert_eq!(
+            kv.apply(KvCommand::DeleteRange {
+                start: "b".into(),
+                end: "c/2".into(),
+            }),
+            KvResponse::Deleted(2)
+        );
+
+        assert_eq!(kv.get("c/2".into()), Some("C/2".into()));
+        assert_eq!(kv.get("a/1".into()), None);
+        assert_eq!(kv.get("b/1".into()), None);
+    }
+
+    #[test]
+    fn test_revisions_and_versions() {
+        let mut kv = KeyValueStore::default();
+        kv.apply(KvCommand::Set("a".into(), "1".into()));
+        kv.apply(KvCommand::Set("b".into(), "1".into()));
+        kv.apply(KvCommand::Set("a".into(), "2".into()));
+
+        let KvResponse::Entry(Some(entry)) = kv.apply(KvCommand::GetEntry("a".into())) else {
+            panic!("missing entry");
+        };
+        assert_eq!(entry.value, "2");
+        assert_eq!(entry.create_revision, 1);
+        assert_eq!(entry.mod_revision, 3);
+        assert_eq!(entry.version, 2);
+
+        // Deleting a missing key is not a write
+        kv.apply(KvCommand::Delete("missing".into()));
+        assert_eq!(kv.revision(), 3);
+        kv.apply(KvCommand::Delete("a".into()));
+        assert_eq!(kv.revision(), 4);
+    }
+
+    #[test]
+    fn test_compare_and_swap() {
+        let mut kv = kv_with(&["lock"]);
+
+        let resp = kv.apply(KvCommand::CompareAndSwap {
+            key: "lock".into(),
+            expected: Expected::Value("wrong".into()),
+            value: "mine".into(),
+        });
+        let KvResponse::ConditionFailed {
+            current: Some(current),
+        } = resp
+        else {
+            panic!("expected failure, got {:?}", resp);
+        };
+        assert_eq!(current.value, "LOCK");
+        assert_eq!(kv.get("lock".into()), Some("LOCK".into()));
+
+        let resp = kv.apply(KvCommand::CompareAndSwap {
+            key: "lock".into(),
+            expected: Expected::Version(current.version),
+            value: "mine".into(),
+        });
+        assert_eq!(resp, KvResponse::Written { mod_revision: 2 });
+        assert_eq!(kv.get("lock".into()), Some("mine".into()));
+
+        // Version 0 means the key must not exist
+        let resp = kv.apply(KvCommand::CompareAndSwap {
+            key: "new".into(),
+            expected: Expected::Version(0),
+            value: "created".into(),
+        });
+        assert_eq!(resp, KvResponse::Written { mod_revision: 3 });
+    }
+
+    #[test]
+    fn test_set_if_absent() {
+        let mut kv = KeyValueStore::default();
+
+        assert_eq!(
+            kv.apply(KvCommand::SetIfAbsent("k".into(), "first".into())),
+            KvResponse::Written { mod_revision: 1 }
+        );
+        assert!(matches!(
+            kv.apply(KvCommand::SetIfAbsent("k".into(), "second".into())),
+            KvResponse::ConditionFailed { current: Some(e) } if e.value == "first"
+        ));
+    }
+
+    fn condition(key: &str, op: CompareOp, target: CompareTarget) -> Condition {
+        Condition {
+            key: key.into(),
+            op,
+            target,
+        }
+    }
+
+    #[test]
+    fn test_txn_success_branch_is_one_revision() {
+        let mut kv = kv_with(&["from"]);
+
+        let resp = kv.apply(KvCommand::Txn {
+            compare: vec![
+                condition(
+                    "from",
+                    CompareOp::Equal,
+                    CompareTarget::Value("FROM".into()),
+                ),
+                condition("to", CompareOp::Equal, CompareTarget::Exists(false)),
+            ],
+            success: vec![
+                Op::Delete("from".into()),
+                Op::Put("to".into(), "moved".into()),
+                Op::Get("to".into()),
+            ],
+            failure: vec![],
+        });
+
+        let KvResponse::Txn { succeeded, results } = resp else {
+            panic!("unexpected response");
+        };
+        assert!(succeeded);
+        assert_eq!(results[0], OpResult::Delete { deleted: true });
+        assert_eq!(results[1], OpResult::Put { mod_revision: 2 });
+        assert!(matches!(&results[2], OpResult::Get(Some(e)) if e.value == "moved"));
+        assert_eq!(kv.revision(), 2);
+        assert_eq!(kv.get("from".into()), None);
+    }
+
+    #[test]
+    fn test_txn_rejects_duplicate_key_writes() {
+        let mut kv = kv_with(&["a"]);
+
+        let resp = kv.apply(KvCommand::Txn {
+            compare: vec![],
+            success: vec![
+                Op::Put("a".into(), "1".into()),
+                Op::Get("a".into()),
+                Op::Put("a".into(), "2".into()),
+            ],
+            failure: vec![],
+        });
+        assert_eq!(resp, KvResponse::DuplicateKey("a".into()));
+
+        // The failure branch is checked too, even when it would not run
+        let resp = kv.apply(KvCommand::Txn {
+            compare: vec![],
+            success: vec![Op::Put("b".into(), "1".into())],
+            failure: vec![Op::Put("b".into(), "1".into()), Op::Delete("b".into())],
+        });
+        assert_eq!(resp, KvResponse::DuplicateKey("b".into()));
+
+        let entry = kv.apply(KvCommand::GetEntry("a".into()));
+        assert!(matches!(entry, KvResponse::Entry(Some(e)) if e.version == 1));
+        assert_eq!(kv.revision(), 1);
+    }
+
+    #[test]
+    fn test_txn_failure_branch() {
+        let mut kv = kv_with(&["a", "b"]);
+
+        let resp = kv.apply(KvCommand::Txn {
+            compare: vec![
+                condition("a", CompareOp::Equal, CompareTarget::Version(1)),
+                condition("b", CompareOp::Greater, CompareTarget::ModRevision(5)),
+            ],
+            success: vec![Op::Put("a".into(), "never".into())],
+            failure: vec![Op::Get("b".into())],
+        });
+
+        let KvResponse::Txn { succeeded, results } = resp else {
+            panic!("unexpected response");
+        };
+        assert!(!succeeded);
+        assert!(matches!(&results[0], OpResult::Get(Some(e)) if e.mod_revision == 2));
+        assert_eq!(kv.get("a".into()), Some("A".into()));
+        assert_eq!(kv.revision(), 2); // read-only branch does not bump the revision
+    }
+
+    #[test]
+    fn test_txn_comparisons_on_missing_key() {
+        assert!(condition("x", CompareOp::Equal, CompareTarget::CreateRevision(0)).holds(None));
+        assert!(condition("x", CompareOp::Less, CompareTarget::Version(1)).holds(None));
+        assert!(condition("x", CompareOp::NotEqual, CompareTarget::Exists(true)).holds(None));
+        assert!(!condition("x", CompareOp::NotEqual, CompareTarget::Value("y".into())).holds(None));
+    }
+
+    #[test]
+    fn test_lease_expiry_deletes_attached_keys() {
+        let mut kv = KeyValueStore::default();
+        assert_eq!(
+            kv.apply(KvCommand::LeaseGrant {
+                id: 7,
+                ttl_ms: 1000,
+                now_ms: 5000,
+            }),
+            KvResponse::Lease {
+                id: 7,
+                expires_at_ms: 6000
+            }
+        );
+        kv.apply(KvCommand::PutWithLease {
+            key: "svc/a".into(),
+            value: "10.0.0.1".into(),
+            lease: 7,
+        });
+        kv.apply(KvCommand::Set("static".into(), "x".into()));
+
+        // Not expired yet
+        assert_eq!(
+            kv.apply(KvCommand::ExpireLeases { now_ms: 5999 }),
+            KvResponse::Expired(vec![])
+        );
+        assert_eq!(
+            kv.apply(KvCommand::ExpireLeases { now_ms: 6000 }),
+            KvResponse::Expired(vec![7])
+        );
+        assert_eq!(kv.get("svc/a".into()), None);
+        assert_eq!(kv.get("static".into()), Some("x".into()));
+        assert!(kv.lease(7).is_none());
+    }
+
+    #[test]
+    fn test_lease_keepalive_and_revoke() {
+        let mut kv = KeyValueStore::default();
+        kv.apply(KvCommand::LeaseGrant {
+            id: 1,
+            ttl_ms: 100,
+            now_ms: 0,
+        });
+        kv.apply(KvCommand::PutWithLease {
+            key: "lock".into(),
+            value: "me".into(),
+            lease: 1,
+        });
+
+        assert_eq!(
+            kv.apply(KvCommand::LeaseKeepAlive { id: 1, now_ms: 90 }),
+            KvResponse::Lease {
+                id: 1,
+                expires_at_ms: 190
+            }
+        );
+        assert_eq!(
+            kv.apply(KvCommand::ExpireLeases { now_ms: 150 }),
+            KvResponse::Expired(vec![])
+        );
+
+        assert_eq!(kv.apply(KvCommand::LeaseRevoke(1)), KvResponse::Deleted(1));
+        assert_eq!(kv.get("lock".into()), None);
+        assert_eq!(
+            kv.apply(KvCommand::LeaseKeepAlive { id: 1, now_ms: 160 }),
+            KvResponse::LeaseNotFound(1)
+        );
+    }
+
+    #[test]
+    fn test_plain_set_detaches_key_from_lease() {
+        let mut kv = KeyValueStore::default();
+        kv.apply(KvCommand::LeaseGrant {
+            id: 1,
+            ttl_ms: 100,
+            now_ms: 0,
+        });
+        kv.apply(KvCommand::PutWithLease {
+            key: "k".into(),
+            value: "v1".into(),
+            lease: 1,
+        });
+        kv.apply(KvCommand::Set("k".into(), "v2".into()));
+
+        assert!(kv.lease(1).unwrap().keys.is_empty());
+        kv.apply(KvCommand::ExpireLeases { now_ms: 100 });
+        assert_eq!(kv.get("k".into()), Some("v2".into()));
+    }
+
+    #[test]
+    fn test_put_with_unknown_lease() {
+        let mut kv = KeyValueStore::default();
+
+        assert_eq!(
+            kv.apply(KvCommand::PutWithLease {
+                key: "k".into(),
+                value: "v".into(),
+                lease: 9,
+            }),
+            KvResponse::LeaseNotFound(9)
+        );
+        assert_eq!(kv.get("k".into()), None);
+    }
+
+    #[test]
+    fn test_put_with_expired_lease() {
+        let mut kv = KeyValueStore::default();
+        kv.apply(KvCommand::LeaseGrant {
+            id: 1,
+            ttl_ms: 100,
+            now_ms: 0,
+        });
+        // A later lease command moves the clock past lease 1's expiry before any
+        // ExpireLeases has revoked it
+        kv.apply(KvCommand::LeaseGrant {
+            id: 2,
+            ttl_ms: 100,
+            now_ms: 150,
+        });
+        assert!(kv.lease(1).is_some());
+
+        assert_eq!(
+            kv.apply(KvCommand::PutWithLease {
+                key: "k".into(),
+                value: "v".into(),
+                lease: 1,
+            }),
+            KvResponse::LeaseNotFound(1)
+        );
+        let resp = kv.apply(KvCommand::Txn {
+            compare: vec![],
+            success: vec![Op::PutWithLease {
+                key: "k".into(),
+                value: "v".into(),
+                lease: 1,
+            }],
+            failure: vec![],
+        });
+        assert_eq!(resp, KvResponse::LeaseNotFound(1));
+        assert_eq!(kv.get("k".into()), None);
+    }
+
+    #[test]
+    fn test_frozen_state_ignores_later_writes() {
+        let mut kv = KeyValueStore::default();
+        kv.apply(KvCommand::Set("alpha".into(), "beta".into()));
+
+        let frozen = kv.freeze().unwrap();
+        kv.apply(KvCommand::Set("alpha".into(), "changed".into()));
+        kv.apply(KvCommand::Set("gamma".into(), "delta".into()));
+
+        let mut snap = Vec::new();
+        frozen.write_to(&mut snap).unwrap();
+
+        let mut restored = KeyValueStore::default();
+        restored.restore(&mut &snap[..]).unwrap();
+        assert_eq!(restored.get("alpha".into()), Some("beta".into()));
+        assert_eq!(restored.get("gamma".into()), None);
+    }
+
     #[test]
     fn test_snapshot_restore() {
         let mut kv = KeyValueStore::default();
         kv.apply(KvCommand::Set("alpha".into(), "beta".into()));
 
-        let snap = kv.snapshot();
+        let mut snap = Vec::new();
+        kv.snapshot(&mut snap).unwrap();
 
         let mut restored = KeyValueStore::default();
-        restored.restore(snap).unwrap();
+        restored.restore(&mut &snap[..]).unwrap();
 
         let resp = restored.apply(KvCommand::Get("alpha".into()));
         assert_eq!(resp, KvResponse::Value(Some("beta".into())));
+        assert_eq!(restored.revision(), 1);
+    }
+
+    fn value_at(kv: &mut KeyValueStore, key: &str, revision: u64) -> Option<String> {
+        match kv.apply(KvCommand::GetAt(key.into(), revision)) {
+            KvResponse::Entry(entry) => entry.map(|e| e.value),
+            other => panic!("unexpected response {other:?}"),
+        }
+    }
+
+    #[test]
+    fn test_get_and_range_at_revision() {
+        let mut kv = KeyValueStore::default();
+        kv.apply(KvCommand::Set("a".into(), "1".into())); // rev 1
+        kv.apply(KvCommand::Set("b".into(), "1".into())); // rev 2
+        kv.apply(KvCommand::Set("a".into(), "2".into())); // rev 3
+        kv.apply(KvCommand::Delete("b".into())); // rev 4
+
+        assert_eq!(value_at(&mut kv, "a", 1), Some("1".into()));
+        assert_eq!(value_at(&mut kv, "a", 2), Some("1".into()));
+        assert_eq!(value_at(&mut kv, "a", 3), Some("2".into()));
+        assert_eq!(value_at(&mut kv, "b", 3), Some("1".into()));
+        assert_eq!(value_at(&mut kv, "b", 4), None);
+
+        let resp = kv.apply(KvCommand::RangeAt {
+            start: "a".into(),
+            end: "z".into(),
+            limit: 0,
+            revision: 2,
+        });
+        assert_eq!(
+            resp,
+            KvResponse::Entries {
+                entries: vec![("a".into(), "1".into()), ("b".into(), "1".into())],
+                next: None,
+            }
+        );
+        assert_eq!(
+            kv.apply(KvCommand::GetAt("a".into(), 5)),
+            KvResponse::FutureRevision(4)
+        );
+    }
+
+    #[test]
+    fn test_txn_writes_one_history_version_per_revision() {
+        let mut kv = KeyValueStore::default();
+        kv.apply(KvCommand::Txn {
+            compare: vec![],
+            success: vec![
+                Op::Put("a".into(), "1".into()),
+                Op::Put("b".into(), "2".into()),
+            ],
+            failure: vec![],
+        });
+
+        for key in ["a", "b"] {
+            let revisions: Vec<u64> = kv.history(key).map(|v| v.revision).collect();
+            assert_eq!(revisions, vec![1]);
+        }
+        assert_eq!(value_at(&mut kv, "b", 1), Some("2".into()));
+    }
+
+    #[test]
+    fn test_compaction_drops_older_history() {
+        let mut kv = KeyValueStore::default();
+        kv.apply(KvCommand::Set("a".into(), "1".into())); // rev 1
+        kv.apply(KvCommand::Set("a".into(), "2".into())); // rev 2
+        kv.apply(KvCommand::Set("gone".into(), "x".into())); // rev 3
+        kv.apply(KvCommand::Delete("gone".into())); // rev 4
+        kv.apply(KvCommand::Set("a".into(), "3".into())); // rev 5
+
+        assert_eq!(kv.apply(KvCommand::Compact(4)), KvResponse::Ack);
+        assert_eq!(kv.compacted_revision(), 4);
+
+        assert_eq!(
+            kv.apply(KvCommand::GetAt("a".into(), 3)),
+            KvResponse::Compacted(4)
+        );
+        assert_eq!(value_at(&mut kv, "a", 4), Some("2".into()));
+        assert_eq!(value_at(&mut kv, "a", 5), Some("3".into()));
+        assert_eq!(kv.history("a").count(), 2);
+        assert_eq!(kv.history("gone").count(), 0);
+
+        assert_eq!(
+            kv.apply(KvCommand::Compact(9)),
+            KvResponse::FutureRevision(5)
+        );
+    }
+
+    #[test]
+    fn test_snapshot_keeps_history() {
+        let mut kv = KeyValueStore::default();
+        kv.apply(KvCommand::Set("a".into(), "1".into()));
+        kv.apply(KvCommand::Set("a".into(), "2".into()));
+        kv.apply(KvCommand::Set("b".into(), "1".into()));
+        kv.apply(KvCommand::Delete("b".into()));
+        kv.apply(KvCommand::Compact(1));
+
+        let mut snap = Vec::new();
+        kv.snapshot(&mut snap).unwrap();
+        let mut restored = KeyValueStore::default();
+        restored.restore(&mut &snap[..]).unwrap();
+
+        assert_eq!(restored.compacted_revision(), 1);
+        assert_eq!(value_at(&mut restored, "a", 1), Some("1".into()));
+        assert_eq!(value_at(&mut restored, "b", 3), Some("1".into()));
+        assert_eq!(restored.get("a".into()), Some("2".into()));
+        assert_eq!(restored.get("b".into()), None);
+    }
+
+    fn drain(stream: &WatchStream) -> Vec<(u64, Vec<WatchEvent>)> {
+        stream
+            .try_iter()
+            .map(|resp| {
+                let resp = resp.unwrap();
+                (resp.revision, resp.events)
+            })
+            .collect()
+    }
+
+    fn put_event(kv: &KeyValueStore, key: &str, revision: u64) -> WatchEvent {
+        let entry = kv
+            .history(key)
+            .find(|v| v.revision == revision)
+            .and_then(|v| v.entry.clone())
+            .unwrap();
+        WatchEvent::Put {
+            key: key.into(),
+            entry,
+        }
+    }
+
+    #[test]
+    fn test_watch_prefix_streams_changes_in_revision_order() {
+        let mut kv = KeyValueStore::default();
+        let stream = kv.watch(WatchFilter::Prefix("svc/".into()), 0, 16).unwrap();
+
+        kv.apply(KvCommand::Set("svc/a".into(), "1".into())); // rev 1
+        kv.apply(KvCommand::Set("other".into(), "x".into())); // rev 2
+        kv.apply(KvCommand::Txn {
+            compare: vec![],
+            success: vec![
+                Op::Put("svc/b".into(), "2".into()),
+                Op::Delete("svc/a".into()),
+            ],
+            failure: vec![],
+        }); // rev 3
+
+        assert_eq!(
+            drain(&stream),
+            vec![
+                (1, vec![put_event(&kv, "svc/a", 1)]),
+                (
+                    3,
+                    vec![
+                        WatchEvent::Delete {
+                            key: "svc/a".into()
+                        },
+                        put_event(&kv, "svc/b", 3),
+                    ]
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_watch_resumes_from_revision() {
+        let mut kv = KeyValueStore::default();
+        kv.apply(KvCommand::Set("k".into(), "1".into())); // rev 1
+        kv.apply(KvCommand::Set("k".into(), "2".into())); // rev 2
+        kv.apply(KvCommand::Delete("k".into())); // rev 3
+
+        let stream = kv.watch(WatchFilter::Key("k".into()), 2, 16).unwrap();
+        kv.apply(KvCommand::Set("k".into(), "4".into())); // rev 4
+
+        assert_eq!(
+            drain(&stream),
+            vec![
+                (2, vec![put_event(&kv, "k", 2)]),
+                (3, vec![WatchEvent::Delete { key: "k".into() }]),
+                (4, vec![put_event(&kv, "k", 4)]),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_slow_watcher_catches_up_from_history() {
+        let mut kv = KeyValueStore::default();
+        let stream = kv.watch(WatchFilter::Prefix("".into()), 0, 1).unwrap();
+
+        for i in 1..=3 {
+            kv.apply(KvCommand::Set("k".into(), i.to_string()));
+        }
+        assert_eq!(drain(&stream).len(), 1); // Only one message fits
+
+        kv.apply(KvCommand::Get("k".into())); // Any apply retries delivery
+        assert_eq!(drain(&stream)[0].0, 2);
+        kv.apply(KvCommand::Get("k".into()));
+        assert_eq!(drain(&stream)[0].0, 3);
+        kv.apply(KvCommand::Get("k".into()));
+        assert!(drain(&stream).is_empty());
+    }
+
+    #[test]
+    fn test_watch_behind_compaction_fails() {
+        let mut kv = KeyValueStore::default();
+        kv.apply(KvCommand::Set("k".into(), "1".into()));
+        kv.apply(KvCommand::Set("k".into(), "2".into()));
+        kv.apply(KvCommand::Compact(2));
+
+        let err = kv.watch(WatchFilter::Key("k".into()), 2, 16).unwrap_err();
+        assert!(matches!(err, NexusError::Compacted(2)));
+
+        // A watcher that falls behind a later compaction gets the same error
+        let stream = kv.watch(WatchFilter::Key("k".into()), 3, 1).unwrap();
+        kv.apply(KvCommand::Set("k".into(), "3".into())); // rev 3, delivered
+        kv.apply(KvCommand::Set("k".into(), "4".into())); // rev 4, buffer full
+        kv.apply(KvCommand::Compact(4));
+
+        assert_eq!(stream.recv().unwrap().unwrap().revision, 3);
+        kv.apply(KvCommand::Get("k".into()));
+        assert!(matches!(
+            stream.recv().unwrap(),
+            Err(NexusError::Compacted(4))
+        ));
+        assert!(kv.watchers.is_empty());
+    }
+
+    #[test]
+    fn test_dropped_watch_is_removed() {
+        let mut kv = KeyValueStore::default();
+        let stream = kv.watch(WatchFilter::Key("k".into()), 0, 16).unwrap();
+        drop(stream);
+
+        kv.apply(KvCommand::Set("k".into(), "1".into()));
+        assert!(kv.watchers.is_empty());
+    }
+
+    #[test]
+    fn test_txn_put_with_lease() {
+        let mut kv = KeyValueStore::default();
+        let put = |lease| Op::PutWithLease {
+            key: "k".into(),
+            value: "v".into(),
+            lease,
+        };
+
+        let resp = kv.apply(KvCommand::Txn {
+            compare: vec![],
+            success: vec![put(1)],
+            failure: vec![],
+        });
+        assert_eq!(resp, KvResponse::LeaseNotFound(1));
+        assert_eq!(kv.revision(), 0);
+
+        kv.apply(KvCommand::LeaseGrant {
+            id: 1,
+            ttl_ms: 100,
+            now_ms: 0,
+        });
+        kv.apply(KvCommand::Txn {
+            compare: vec![],
+            success: vec![put(1)],
+            failure: vec![],
+        });
+        assert_eq!(kv.lease(1).unwrap().keys.len(), 1);
+        kv.apply(KvCommand::LeaseRevoke(1));
+        assert_eq!(kv.get("k".into()), None);
     }
 }
@@ -0,0 +1,86 @@
+use crate::raft::state_machine::KvEntry;
+use nexus_common::error::NexusError;
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+
+/// Keys followed by a watch
+#[derive(Debug, Clone, PartialEq)]
+pub enum WatchFilter {
+    Key(String),
+    Prefix(String),
+}
+
+impl WatchFilter {
+    pub fn matches(&self, key: &str) -> bool {
+        match self {
+            WatchFilter::Key(k) => k == key,
+            WatchFilter::Prefix(prefix) => key.starts_with(prefix.as_str()),
+        }
+    }
+}
+
+/// A change to a watched key
+#[derive(Debug, Clone, PartialEq)]
+pub enum WatchEvent {
+    Put { key: String, entry: KvEntry },
+    Delete { key: String },
+}
+
+/// The watched changes made by one revision, in key order
+#[derive(Debug, Clone, PartialEq)]
+pub struct WatchResponse {
+    pub revision: u64,
+    pub events: Vec<WatchEvent>,
+}
+
+/// Receiving end of a watch. Ends with `NexusError::Compacted` if the watcher fell
+/// behind compaction; dropping it cancels the watch.
+pub type WatchStream = Receiver<Result<WatchResponse, NexusError>>;
+
+/// Outcome of handing a message to a watcher
+pub(crate) enum Delivery {
+    Sent,
+    Full,   // The client is not keeping up; retry later
+    Closed, // The client dropped its stream
+}
+
+/// Sending end of a watch, held by the store
+#[derive(Debug)]
+pub(crate) struct Watcher {
+    pub filter: WatchFilter,
+    pub next_revision: u64, // First revision not yet delivered
+    tx: SyncSender<Result<WatchResponse, NexusError>>,
+}
+
+impl Watcher {
+    /// Creates a watcher that buffers at most `capacity` undelivered messages
+    pub fn new(filter: WatchFilter, next_revision: u64, capacity: usize) -> (Self, WatchStream) {
+        let (tx, rx) = mpsc::sync_channel(capacity.max(1));
+        let watcher = Watcher {
+            filter,
+            next_revision,
+            tx,
+        };
+        (watcher, rx)
+    }
+
+    pub fn send(&self, message: Result<WatchResponse, NexusError>) -> Delivery {
+        match self.tx.try_send(message) {
+            Ok(()) => Delivery::Sent,
+            Err(TrySendError::Full(_)) => Delivery::Full,
+            Err(TrySendError::Disconnected(_)) => Delivery::Closed,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_filter_matches() {
+        assert!(WatchFilter::Key("a".into()).matches("a"));
+        assert!(!WatchFilter::Key("a".into()).matches("ab"));
+        assert!(WatchFilter::Prefix("a/".into()).matches("a/b"));
+        assert!(!WatchFilter::Prefix("a/".into()).matches("b/a"));
+    }
+}
@@ -0,0 +1,132 @@
+use super::{acquire, release, unexpected, KvClient, Session};
+use crate::raft::state_machine::{KvCommand, KvEntry, KvResponse};
+use crate::raft::watch::{WatchFilter, WatchStream};
+use nexus_common::error::NexusError;
+
+/// Single-leader election on one key
+#[derive(Debug, Clone)]
+pub struct Election {
+    key: String,
+}
+
+/// The current leader as read from the store
+#[derive(Debug, Clone, PartialEq)]
+pub struct Leader {
+    pub value: String,
+    pub revision: u64, // Revision leadership was won at; grows with every new leader
+    pub lease: Option<u64>,
+}
+
+/// Held by the winner of a campaign
+#[derive(Debug, Clone, PartialEq)]
+pub struct Leadership {
+    pub key: String,
+    pub revision: u64, // Usable as a fencing token, like `LockGuard::token`
+}
+
+impl From<KvEntry> for Leader {
+    fn from(entry: KvEntry) -> Self {
+        Leader {
+            value: entry.value,
+            revision: entry.create_revision,
+            lease: entry.lease,
+        }
+    }
+}
+
+impl Election {
+    pub fn new(key: impl Into<String>) -> Self {
+        Election { key: key.into() }
+    }
+
+    /// Becomes leader with `value` (e.g. the candidate's address) if there is none.
+    /// Returns `None` if someone else leads; `observe` to learn when to try again.
+    pub fn campaign(
+        &self,
+        client: &mut dyn KvClient,
+        session: &Session,
+        value: impl Into<String>,
+    ) -> Result<Option<Leadership>, NexusError> {
+        let won = acquire(client, session, &self.key, value.into())?;
+        Ok(won.ok().map(|revision| Leadership {
+            key: self.key.clone(),
+            revision,
+        }))
+    }
+
+    /// The current leader, if any
+    pub fn leader(&self, client: &mut dyn KvClient) -> Result<Option<Leader>, NexusError> {
+        match client.execute(KvCommand::GetEntry(self.key.clone()))? {
+            KvResponse::Entry(entry) => Ok(entry.map(Leader::from)),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    /// The current leader plus every later change of leadership. The watch is opened
+    /// before the read, so no change is missed; events at or before the returned
+    /// leader's revision may repeat what the read already saw.
+    pub fn observe(
+        &self,
+        client: &mut dyn KvClient,
+    ) -> Result<(Option<Leader>, WatchStream), NexusError> {
+        let changes = client.watch(WatchFilter::Key(self.key.clone()), 0)?;
+        let leader = self.leader(client)?;
+        Ok((leader, changes))
+    }
+
+    /// Steps down; false if leadership had already been lost to lease expiry
+    pub fn resign(client: &mut dyn KvClient, leadership: Leadership) -> Result<bool, NexusError> {
+        release(client, &leadership.key, leadership.revision)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::raft::state_machine::KeyValueStore;
+    use crate::raft::watch::WatchEvent;
+
+    #[test]
+    fn test_campaign_observe_resign() {
+        let mut kv = KeyValueStore::default();
+        let a = Session::grant(&mut kv, 1, 1000, 0).unwrap();
+        let b = Session::grant(&mut kv, 2, 1000, 0).unwrap();
+        let election = Election::new("election/orders");
+
+        let leadership = election.campaign(&mut kv, &a, "node-a").unwrap().unwrap();
+        assert_eq!(election.campaign(&mut kv, &b, "node-b").unwrap(), None);
+
+        let (leader, changes) = election.observe(&mut kv).unwrap();
+        let leader = leader.unwrap();
+        assert_eq!(leader.value, "node-a");
+        assert_eq!(leader.revision, leadership.revision);
+        assert_eq!(leader.lease, Some(1));
+
+        assert!(Election::resign(&mut kv, leadership).unwrap());
+        let next = election.campaign(&mut kv, &b, "node-b").unwrap().unwrap();
+
+        let events: Vec<WatchEvent> = changes
+            .try_iter()
+            .flat_map(|resp| resp.unwrap().events)
+            .collect();
+        assert_eq!(events.len(), 2);
+        assert!(matches!(&events[1], WatchEvent::Put { entry, .. } if entry.value == "node-b"));
+        assert_eq!(
+            election.leader(&mut kv).unwrap().unwrap().revision,
+            next.revision
+        );
+    }
+
+    #[test]
+    fn test_leadership_ends_with_session() {
+        let mut kv = KeyValueStore::default();
+        let a = Session::grant(&mut kv, 1, 1000, 0).unwrap();
+        let election = Election::new("election/orders");
+
+        let leadership = election.campaign(&mut kv, &a, "node-a").unwrap().unwrap();
+        a.close(&mut kv).unwrap();
+
+        assert_eq!(election.leader(&mut kv).unwrap(), None);
+        assert!(!Election::resign(&mut kv, leadership).unwrap());
+    }
+}
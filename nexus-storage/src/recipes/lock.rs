@@ -0,0 +1,97 @@
+use super::{acquire, release, KvClient, Session};
+use crate::raft::watch::{WatchFilter, WatchStream};
+use nexus_common::error::NexusError;
+
+/// A mutex on one key. The holder's key is attached to its session's lease, so the lock
+/// is freed if the holder stops keeping the session alive.
+#[derive(Debug, Clone)]
+pub struct Lock {
+    key: String,
+}
+
+/// Proof of holding a lock
+#[derive(Debug, Clone, PartialEq)]
+pub struct LockGuard {
+    pub key: String,
+    /// Revision the lock was taken at. Tokens grow with every new holder, so a resource
+    /// guarded by the lock can reject writes carrying an older token than it has seen.
+    pub token: u64,
+}
+
+impl Lock {
+    pub fn new(key: impl Into<String>) -> Self {
+        Lock { key: key.into() }
+    }
+
+    /// Takes the lock if it is free; `None` if another session holds it
+    pub fn try_lock(
+        &self,
+        client: &mut dyn KvClient,
+        session: &Session,
+    ) -> Result<Option<LockGuard>, NexusError> {
+        let acquired = acquire(client, session, &self.key, session.lease.to_string())?;
+        Ok(acquired.ok().map(|token| LockGuard {
+            key: self.key.clone(),
+            token,
+        }))
+    }
+
+    /// Changes to the lock key from the next write on. To wait for the lock, open this
+    /// first, then retry `try_lock` after each `Delete` event.
+    pub fn watch(&self, client: &mut dyn KvClient) -> Result<WatchStream, NexusError> {
+        client.watch(WatchFilter::Key(self.key.clone()), 0)
+    }
+
+    /// Releases the lock; false if it had already been lost to lease expiry
+    pub fn unlock(client: &mut dyn KvClient, guard: LockGuard) -> Result<bool, NexusError> {
+        release(client, &guard.key, guard.token)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::raft::state_machine::{KeyValueStore, KvCommand, StateMachine};
+    use crate::raft::watch::WatchEvent;
+
+    #[test]
+    fn test_lock_is_exclusive_and_tokens_increase() {
+        let mut kv = KeyValueStore::default();
+        let a = Session::grant(&mut kv, 1, 1000, 0).unwrap();
+        let b = Session::grant(&mut kv, 2, 1000, 0).unwrap();
+        let lock = Lock::new("locks/db");
+
+        let first = lock.try_lock(&mut kv, &a).unwrap().unwrap();
+        assert_eq!(lock.try_lock(&mut kv, &b).unwrap(), None);
+
+        let released = lock.watch(&mut kv).unwrap();
+        assert!(Lock::unlock(&mut kv, first.clone()).unwrap());
+        let events = released.try_recv().unwrap().unwrap().events;
+        assert_eq!(
+            events,
+            vec![WatchEvent::Delete {
+                key: "locks/db".into()
+            }]
+        );
+
+        let second = lock.try_lock(&mut kv, &b).unwrap().unwrap();
+        assert!(second.token > first.token);
+    }
+
+    #[test]
+    fn test_expired_holder_loses_lock() {
+        let mut kv = KeyValueStore::default();
+        let a = Session::grant(&mut kv, 1, 100, 0).unwrap();
+        let b = Session::grant(&mut kv, 2, 1000, 0).unwrap();
+        let lock = Lock::new("locks/db");
+
+        let stale = lock.try_lock(&mut kv, &a).unwrap().unwrap();
+        kv.apply(KvCommand::ExpireLeases { now_ms: 100 });
+
+        let fresh = lock.try_lock(&mut kv, &b).unwrap().unwrap();
+        assert!(fresh.token > stale.token);
+        // The old holder's unlock must not release the new holder's lock
+        assert!(!Lock::unlock(&mut kv, stale).unwrap());
+        assert_eq!(lock.try_lock(&mut kv, &a).unwrap(), None);
+    }
+}
@@ -0,0 +1,156 @@
+// Coordination recipes built on the replicated key-value store: sessions backed by
+// leases, locks with fencing tokens and leader election
+mod election;
+mod lock;
+
+pub use election::{Election, Leader, Leadership};
+pub use lock::{Lock, LockGuard};
+
+use crate::raft::state_machine::{
+    CompareOp, CompareTarget, Condition, KeyValueStore, KvCommand, KvEntry, KvResponse, Op,
+    OpResult, StateMachine,
+};
+use crate::raft::watch::{WatchFilter, WatchStream};
+use nexus_common::error::NexusError;
+
+/// Messages buffered per watch opened by `KeyValueStore`'s client impl
+const WATCH_CAPACITY: usize = 64;
+
+/// Runs commands against the replicated key-value store. Implementations propose the
+/// command through Raft and return its response once it has been applied.
+pub trait KvClient {
+    fn execute(&mut self, command: KvCommand) -> Result<KvResponse, NexusError>;
+
+    /// Watches keys from `start_revision` (0 = the next write)
+    fn watch(
+        &mut self,
+        filter: WatchFilter,
+        start_revision: u64,
+    ) -> Result<WatchStream, NexusError>;
+}
+
+/// Applies commands straight to a local store (single node, tests)
+impl KvClient for KeyValueStore {
+    fn execute(&mut self, command: KvCommand) -> Result<KvResponse, NexusError> {
+        Ok(self.apply(command))
+    }
+
+    fn watch(
+        &mut self,
+        filter: WatchFilter,
+        start_revision: u64,
+    ) -> Result<WatchStream, NexusError> {
+        KeyValueStore::watch(self, filter, start_revision, WATCH_CAPACITY)
+    }
+}
+
+/// A lease held by one client. Keys written by the recipes are attached to it, so they
+/// disappear when the client stops keeping the session alive.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Session {
+    pub lease: u64,
+    pub expires_at_ms: u64,
+}
+
+impl Session {
+    /// Grants the session's lease. `now_ms` is only used by local clients; a Raft
+    /// leader replaces it with its own clock, as for `KvCommand::LeaseGrant`.
+    pub fn grant(
+        client: &mut dyn KvClient,
+        lease: u64,
+        ttl_ms: u64,
+        now_ms: u64,
+    ) -> Result<Self, NexusError> {
+        match client.execute(KvCommand::LeaseGrant {
+            id: lease,
+            ttl_ms,
+            now_ms,
+        })? {
+            KvResponse::Lease { id, expires_at_ms } => Ok(Session {
+                lease: id,
+                expires_at_ms,
+            }),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    /// Extends the lease; fails once it has expired, after which every lock and
+    /// leadership taken with this session is lost
+    pub fn keep_alive(&mut self, client: &mut dyn KvClient, now_ms: u64) -> Result<(), NexusError> {
+        match client.execute(KvCommand::LeaseKeepAlive {
+            id: self.lease,
+            now_ms,
+        })? {
+            KvResponse::Lease { expires_at_ms, .. } => {
+                self.expires_at_ms = expires_at_ms;
+                Ok(())
+            }
+            KvResponse::LeaseNotFound(id) => {
+                Err(NexusError::StateMachine(format!("lease {id} has expired")))
+            }
+            other => Err(unexpected(other)),
+        }
+    }
+
+    /// Revokes the lease, releasing everything held through it
+    pub fn close(self, client: &mut dyn KvClient) -> Result<(), NexusError> {
+        match client.execute(KvCommand::LeaseRevoke(self.lease))? {
+            KvResponse::Deleted(_) | KvResponse::LeaseNotFound(_) => Ok(()),
+            other => Err(unexpected(other)),
+        }
+    }
+}
+
+/// Creates `key` under the session's lease if it does not exist. Returns the revision
+/// it was created at, or the entry of whoever holds it.
+fn acquire(
+    client: &mut dyn KvClient,
+    session: &Session,
+    key: &str,
+    value: String,
+) -> Result<Result<u64, Option<KvEntry>>, NexusError> {
+    let resp = client.execute(KvCommand::Txn {
+        compare: vec![Condition {
+            key: key.to_string(),
+            op: CompareOp::Equal,
+            target: CompareTarget::Exists(false),
+        }],
+        success: vec![Op::PutWithLease {
+            key: key.to_string(),
+            value,
+            lease: session.lease,
+        }],
+        failure: vec![Op::Get(key.to_string())],
+    })?;
+
+    match resp {
+        KvResponse::Txn { succeeded, results } => match (succeeded, results.as_slice()) {
+            (true, [OpResult::Put { mod_revision }]) => Ok(Ok(*mod_revision)),
+            (false, [OpResult::Get(holder)]) => Ok(Err(holder.clone())),
+            _ => Err(unexpected(KvResponse::Txn { succeeded, results })),
+        },
+        other => Err(unexpected(other)),
+    }
+}
+
+/// Deletes `key` if it is still the one created at `revision`; false if it was lost
+fn release(client: &mut dyn KvClient, key: &str, revision: u64) -> Result<bool, NexusError> {
+    let resp = client.execute(KvCommand::Txn {
+        compare: vec![Condition {
+            key: key.to_string(),
+            op: CompareOp::Equal,
+            target: CompareTarget::CreateRevision(revision),
+        }],
+        success: vec![Op::Delete(key.to_string())],
+        failure: vec![],
+    })?;
+
+    match resp {
+        KvResponse::Txn { succeeded, .. } => Ok(succeeded),
+        other => Err(unexpected(other)),
+    }
+}
+
+fn unexpected(resp: KvResponse) -> NexusError {
+    NexusError::StateMachine(format!("unexpected response: {resp:?}"))
+}